@@ -0,0 +1,123 @@
+var totpForm = document.getElementById('totp-form');
+var codeField = document.getElementById('code');
+var confirmButton = document.getElementById('confirm-button');
+var disableButton = document.getElementById('disable-button');
+var disableCodeField = document.getElementById('disable-code');
+var disabledWrapper = document.getElementById('disabled-wrapper');
+var enabledWrapper = document.getElementById('enabled-wrapper');
+var enrollButton = document.getElementById('enroll-button');
+var enrollWrapper = document.getElementById('enroll-wrapper');
+var provisioningUri = document.getElementById('provisioning-uri');
+var recoveryCodes = document.getElementById('recovery-codes');
+var recoveryWrapper = document.getElementById('recovery-wrapper');
+var secret = document.getElementById('secret');
+
+var totpUrl = '/api/users/' + totpForm.dataset.userId + '/totp';
+
+function handleErrorResponse(response) {
+
+    response.json()
+        .then(e => showMessage(e.message, 'error'));
+}
+
+function handleEnrollResponse(response) {
+
+    if (!response.ok) {
+        handleErrorResponse(response);
+        return;
+    }
+
+    response.json().then(t => {
+        provisioningUri.href = t.provisioningUri;
+        provisioningUri.innerText = t.provisioningUri;
+        secret.innerText = t.secret;
+        disabledWrapper.hidden = true;
+        enrollWrapper.hidden = false;
+        codeField.focus();
+    });
+}
+
+function enroll() {
+
+    let init = {
+        method: 'PUT',
+        credentials: 'same-origin',
+    };
+
+    fetch(totpUrl, init)
+        .then(handleEnrollResponse);
+}
+
+function handleConfirmResponse(response) {
+
+    confirmButton.disabled = false;
+    confirmButton.classList.remove('is-loading');
+
+    if (!response.ok) {
+        handleErrorResponse(response);
+        return;
+    }
+
+    response.json().then(c => {
+        recoveryCodes.innerText = c.recoveryCodes.join('\n');
+        enrollWrapper.hidden = true;
+        recoveryWrapper.hidden = false;
+    });
+}
+
+function confirmEnrollment() {
+
+    confirmButton.disabled = true;
+    confirmButton.classList.add('is-loading');
+
+    let init = {
+        headers: {
+            'Content-Type': 'application/json'
+        },
+        credentials: 'same-origin',
+        method: 'POST',
+        body: JSON.stringify({
+            code: codeField.value
+        })
+    };
+
+    fetch(totpUrl + '/confirmation', init)
+        .then(handleConfirmResponse);
+}
+
+function handleDisableResponse(response) {
+
+    if (response.ok) {
+        showMessage('Two-factor authentication was disabled', 'success');
+        disableCodeField.value = '';
+        enabledWrapper.hidden = true;
+        disabledWrapper.hidden = false;
+    } else {
+        handleErrorResponse(response);
+    }
+}
+
+function disable() {
+
+    if (!confirm('Are you sure you want to disable two-factor authentication?')) {
+        return;
+    }
+
+    let init = {
+        headers: {
+            'Content-Type': 'application/json'
+        },
+        credentials: 'same-origin',
+        method: 'DELETE',
+        body: JSON.stringify({
+            code: disableCodeField.value
+        })
+    };
+
+    fetch(totpUrl, init)
+        .then(handleDisableResponse);
+}
+
+confirmButton.onclick = confirmEnrollment;
+disableButton.onclick = disable;
+enrollButton.onclick = enroll;
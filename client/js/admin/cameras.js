@@ -17,15 +17,23 @@ class CamEntry extends HTMLElement {
             addressWrapper: 'cam-address-wrapper',
             cancelButton: 'cancel-button',
             deleteButton: 'delete-button',
+            diagnostics: 'cam-diagnostics',
+            diagnosticsWrapper: 'cam-diagnostics-wrapper',
             dropdownIndicator: 'dropdown-indicator',
             enabledSwitch: 'cam-enabled',
             enabledSwitchLabel: 'cam-enabled-label',
             formWrapper: 'form-wrapper',
             header: 'header',
+            healthTag: 'cam-health-tag',
             nameLabel: 'cam-name-label',
             nameField: 'cam-name-field',
             orientationSelect: 'cam-orientation-select',
+            pairingCodeField: 'cam-pairing-code-field',
+            pairedTag: 'cam-paired-tag',
+            proxyTag: 'cam-proxy-tag',
+            resetButton: 'reset-button',
             saveButton: 'save-button',
+            thumbnail: 'cam-thumbnail',
         };
         Object.keys(elements).forEach(propertyName => {
             this[propertyName] = this.body.getElementById(elements[propertyName]);
@@ -37,7 +45,10 @@ class CamEntry extends HTMLElement {
         this.deleteButton.onclick = e => this.onDeleteButtonClicked(e);
         this.enabledSwitch.onclick = e => this.onEnabledSwitchClicked(e);
         this.header.onclick = e => this.onHeaderClicked(e);
+        this.resetButton.onclick = e => this.onResetButtonClicked(e);
         this.saveButton.onclick = e => this.onSaveButtonClicked(e);
+        this.thumbnail.onerror = _ => this.thumbnail.hidden = true;
+        this.thumbnail.onload = _ => this.thumbnail.hidden = false;
     }
 
     connectedCallback() {
@@ -55,10 +66,15 @@ class CamEntry extends HTMLElement {
         return [
             'cam-address',
             'cam-enabled',
+            'cam-health',
             'cam-id',
+            'cam-last-seen',
+            'cam-latency',
             'cam-local',
             'cam-name',
             'cam-orientation',
+            'cam-paired',
+            'cam-proxy-pending',
         ];
     }
 
@@ -70,11 +86,17 @@ class CamEntry extends HTMLElement {
             case 'cam-enabled':
                 this.enabledSwitch.checked = (newValue == 'true');
                 break;
+            case 'cam-health':
+            case 'cam-last-seen':
+            case 'cam-latency':
+                this.showHealth();
+                break;
             case 'cam-id':
                 this.header.hidden = false;
                 if (!this.isLocal) {
                     this.showDeleteButton();
                 }
+                this.thumbnail.src = '/api/cameras/' + newValue + '/snapshot.jpg';
                 let switchId = newValue + '-enabled';
                 this.enabledSwitch.setAttribute('id', switchId);
                 this.enabledSwitchLabel.setAttribute('for', switchId);
@@ -93,6 +115,12 @@ class CamEntry extends HTMLElement {
             case 'cam-orientation':
                 this.orientationSelect.value = newValue;
                 break;
+            case 'cam-paired':
+                this.pairedTag.hidden = (newValue != 'false');
+                break;
+            case 'cam-proxy-pending':
+                this.proxyTag.hidden = (newValue != 'true');
+                break;
         }
     }
 
@@ -105,6 +133,13 @@ class CamEntry extends HTMLElement {
         this.setAttribute('cam-id', camera.id);
         this.setAttribute('cam-name', camera.name);
         this.setAttribute('cam-orientation', camera.orientation);
+        this.setAttribute('cam-paired', camera.paired);
+        this.setAttribute('cam-proxy-pending', camera.proxyPending);
+        if (camera.health) {
+            this.setAttribute('cam-health', camera.health.status);
+            this.setAttribute('cam-latency', camera.health.latencyMs || '');
+            this.setAttribute('cam-last-seen', camera.health.lastSeen || '');
+        }
     }
 
     //#region Form Display
@@ -113,6 +148,10 @@ class CamEntry extends HTMLElement {
         this.dropdownIndicator.classList.remove('fa-chevron-down');
         this.dropdownIndicator.classList.add('fa-chevron-up');
         this.formWrapper.hidden = false;
+
+        if (this.hasAttribute('cam-id')) {
+            this.loadDiagnostics();
+        }
     }
 
     hideForm() {
@@ -121,6 +160,27 @@ class CamEntry extends HTMLElement {
         this.formWrapper.hidden = true;
     }
 
+    showHealth() {
+
+        let status = this.getAttribute('cam-health');
+        let latency = this.getAttribute('cam-latency');
+        let lastSeen = this.getAttribute('cam-last-seen');
+
+        this.healthTag.hidden = !status;
+        this.healthTag.classList.toggle('is-success', status == 'online');
+        this.healthTag.classList.toggle('is-danger', status == 'offline');
+        if (status == 'online') {
+            this.healthTag.innerText = latency ? 'Online (' + latency + ' ms)' : 'Online';
+        } else {
+            this.healthTag.innerText = 'Offline';
+        }
+
+        // Times are stored in UTC without an offset
+        this.healthTag.title = lastSeen
+            ? 'Last seen ' + new Date(lastSeen + 'Z').toLocaleString()
+            : 'Never seen';
+    }
+
     showDeleteButton() {
         this.deleteButton.classList.add('button')
         this.deleteButton.hidden = false;
@@ -180,6 +240,7 @@ class CamEntry extends HTMLElement {
 
         if (response.ok) {
             jsonPromise.then(c => {
+                this.pairingCodeField.value = '';
                 this.reload(c);
                 if (showSuccessMessage) {
                     showMessage('Camera changes were saved successfully', 'success');
@@ -191,6 +252,68 @@ class CamEntry extends HTMLElement {
         }
     }
 
+    loadDiagnostics() {
+
+        let url = '/api/cameras/' + this.getAttribute('cam-id') + '/diagnostics';
+        let init = {
+            credentials: 'same-origin',
+        };
+
+        fetch(url, init)
+            .then(r => this.handleDiagnosticsResponse(r));
+    }
+
+    handleDiagnosticsResponse(response) {
+
+        this.diagnosticsWrapper.hidden = false;
+
+        if (!response.ok) {
+            this.diagnostics.innerText = 'Diagnostics are unavailable for this camera';
+            return;
+        }
+
+        response.json().then(d => {
+            let lines = [
+                'Status: ' + d.status,
+                'Restarts: ' + d.restartCount,
+                'Last started: ' + (d.startedAt || 'never'),
+                'Last exited: ' + (d.lastExitAt || 'never'),
+            ];
+            if (d.lastExitStatus) {
+                lines.push('Last exit status: ' + d.lastExitStatus);
+            }
+            if (d.stderr.length > 0) {
+                lines.push('', ...d.stderr);
+            }
+            this.diagnostics.innerText = lines.join('\n');
+            this.resetButton.hidden = (d.status != 'failed');
+        });
+    }
+
+    resetCamera() {
+
+        let url = '/api/cameras/' + this.getAttribute('cam-id') + '/reset';
+        let init = {
+            method: 'POST',
+            credentials: 'same-origin',
+        };
+
+        fetch(url, init)
+            .then(r => this.handleResetResponse(r));
+    }
+
+    handleResetResponse(response) {
+
+        if (response.ok) {
+            showMessage('Camera successfully reset', 'success');
+            this.loadDiagnostics();
+
+        } else {
+            response.json()
+                .then(e => showMessage(e.message, 'error'));
+        }
+    }
+
     deleteCamera() {
 
         let url = '/api/cameras/' + this.getAttribute('cam-id');
@@ -231,6 +354,7 @@ class CamEntry extends HTMLElement {
             this.enabledSwitch.checked = (this.getAttribute('cam-enabled') == 'true');
             this.nameField.value = this.getAttribute('cam-name');
             this.orientationSelect.value = this.getAttribute('cam-orientation');
+            this.pairingCodeField.value = '';
         }
     }
 
@@ -241,6 +365,11 @@ class CamEntry extends HTMLElement {
         }
     }
 
+    onResetButtonClicked(_) {
+
+        this.resetCamera();
+    }
+
     onEnabledSwitchClicked(_) {
 
         let camera = {
@@ -268,6 +397,9 @@ class CamEntry extends HTMLElement {
 
         if (!this.isLocal) {
             camera.address = this.addressField.value;
+            if (this.pairingCodeField.value || !this.hasAttribute('cam-id')) {
+                camera.pairingCode = this.pairingCodeField.value;
+            }
         }
 
         this.uploadCamera(camera);
@@ -292,3 +424,78 @@ function addCamera() {
 }
 
 addCameraButton.onclick = addCamera;
+
+
+var discoveredWrapper = document.getElementById('discovered-wrapper');
+var discoveredList = document.getElementById('discovered-list');
+
+function adoptCamera(camera, button) {
+
+    let pairingCode = prompt('Enter the pairing code logged by ' + camera.name);
+    if (!pairingCode) {
+        return;
+    }
+
+    let init = {
+        method: 'PUT',
+        headers: {
+            'Content-Type': 'application/json'
+        },
+        credentials: 'same-origin',
+        body: JSON.stringify({ pairingCode: pairingCode }),
+    };
+
+    button.disabled = true;
+    button.classList.add('is-loading');
+    fetch('/api/cameras/discovered/' + encodeURIComponent(camera.name), init)
+        .then(response => {
+            button.disabled = false;
+            button.classList.remove('is-loading');
+
+            if (response.ok) {
+                response.json().then(c => {
+                    let newCamEntry = document.createElement('cam-entry');
+                    cameraList.appendChild(newCamEntry);
+                    newCamEntry.reload(c);
+                    button.closest('tr').remove();
+                    discoveredWrapper.hidden = (discoveredList.children.length == 0);
+                    showMessage('Camera "' + c.name + '" was added successfully', 'success');
+                });
+            } else {
+                response.json()
+                    .then(e => showMessage(e.message, 'error'));
+            }
+        });
+}
+
+function showDiscoveredCameras(cameras) {
+
+    discoveredList.innerHTML = '';
+    discoveredWrapper.hidden = (cameras.length == 0);
+
+    cameras.forEach(camera => {
+        let row = discoveredList.insertRow();
+        row.insertCell().innerText = camera.name;
+        row.insertCell().innerText = camera.address;
+        row.insertCell().innerText = camera.properties.resolution || '';
+        row.insertCell().innerText = (camera.properties.paired == 'true') ? 'Yes' : 'No';
+
+        let button = document.createElement('button');
+        button.classList.add('button', 'is-primary', 'is-small');
+        button.innerText = 'Add';
+        button.onclick = _ => adoptCamera(camera, button);
+        row.insertCell().appendChild(button);
+    });
+}
+
+function loadDiscoveredCameras() {
+
+    fetch('/api/cameras/discovered', { credentials: 'same-origin' })
+        .then(response => {
+            if (response.ok) {
+                response.json().then(showDiscoveredCameras);
+            }
+        });
+}
+
+loadDiscoveredCameras();
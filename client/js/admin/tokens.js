@@ -0,0 +1,79 @@
+var createButton = document.getElementById('create-button');
+var nameField = document.getElementById('name-field');
+var scopeField = document.getElementById('scope-field');
+var expiresField = document.getElementById('expires-field');
+var secretWrapper = document.getElementById('secret-wrapper');
+var secret = document.getElementById('secret');
+
+function handleJsonResponse(response) {
+
+    if (response.ok) {
+        return response.json();
+    }
+
+    return response.json()
+        .then(e => Promise.reject(new Error(e.message)));
+}
+
+function createToken() {
+
+    let token = {
+        name: nameField.value,
+        scope: scopeField.value,
+    };
+
+    // Expiry is chosen as a local date, but is stored in UTC without an offset
+    if (expiresField.value) {
+        let expires = new Date(expiresField.value + 'T00:00:00');
+        token.expiresAt = expires.toISOString().replace('Z', '');
+    }
+
+    let init = {
+        method: 'PUT',
+        headers: {
+            'Content-Type': 'application/json'
+        },
+        credentials: 'same-origin',
+        body: JSON.stringify(token),
+    };
+
+    createButton.disabled = true;
+    fetch('/api/tokens', init)
+        .then(handleJsonResponse)
+        .then(t => {
+            secret.innerText = t.secret;
+            secretWrapper.hidden = false;
+            nameField.value = '';
+            expiresField.value = '';
+            showMessage('Token "' + t.name + '" was created successfully', 'success');
+        })
+        .catch(e => showMessage(e.message, 'error'))
+        .finally(() => createButton.disabled = false);
+}
+
+function revokeToken(button) {
+
+    if (!confirm('Are you sure you want to revoke this token?')) {
+        return;
+    }
+
+    let init = {
+        method: 'DELETE',
+        credentials: 'same-origin',
+    };
+
+    fetch('/api/tokens/' + button.dataset.tokenId, init)
+        .then(response => {
+            if (response.ok) {
+                button.closest('tr').remove();
+                showMessage('Token successfully revoked', 'success');
+            } else {
+                response.json()
+                    .then(e => showMessage(e.message, 'error'));
+            }
+        });
+}
+
+createButton.onclick = createToken;
+document.querySelectorAll('.revoke-button')
+    .forEach(b => b.onclick = _ => revokeToken(b));
@@ -13,6 +13,8 @@ class UserEntry extends HTMLElement {
 
         // Bind template elemets to properties of this object
         let elements = {
+            camerasField: 'cameras-field',
+            camerasWrapper: 'cameras-wrapper',
             cancelButton: 'cancel-button',
             deleteButton: 'delete-button',
             dropdownIndicator: 'dropdown-indicator',
@@ -20,7 +22,12 @@ class UserEntry extends HTMLElement {
             header: 'header',
             passwordConfirmField: 'password-cfrm-field',
             passwordField: 'password-field',
+            roleField: 'role-field',
             saveButton: 'save-button',
+            totpResetButton: 'totp-reset-button',
+            totpResetPasswordField: 'totp-reset-password',
+            totpStatus: 'totp-status',
+            totpWrapper: 'totp-wrapper',
             usernameLabel: 'username-label',
             usernameField: 'username-field'
         };
@@ -33,7 +40,12 @@ class UserEntry extends HTMLElement {
         this.cancelButton.onclick = e => this.onCancelButtonClicked(e);
         this.deleteButton.onclick = e => this.onDeleteButtonClicked(e);
         this.header.onclick = e => this.onHeaderClicked(e);
+        this.roleField.onchange = _ => this.showCamerasField();
         this.saveButton.onclick = e => this.onSaveButtonClicked(e);
+        this.totpResetButton.onclick = e => this.onTotpResetButtonClicked(e);
+
+        this.roleField.value = 'viewer';
+        this.showCamerasField();
     }
 
     connectedCallback() {
@@ -50,6 +62,7 @@ class UserEntry extends HTMLElement {
     static get observedAttributes() {
         return [
             'username',
+            'role',
             'user-id',
         ];
     }
@@ -60,9 +73,15 @@ class UserEntry extends HTMLElement {
                 this.usernameLabel.innerText = newValue;
                 this.usernameField.value = newValue;
                 break;
+            case 'role':
+                this.roleField.value = newValue;
+                this.showCamerasField();
+                break;
             case 'user-id':
                 this.header.hidden = false;
                 this.showDeleteButton();
+                this.loadCameras();
+                this.loadTotpStatus();
                 break;
         }
     }
@@ -71,6 +90,7 @@ class UserEntry extends HTMLElement {
 
     reload(user) {
         this.setAttribute('username', user.username);
+        this.setAttribute('role', user.role);
         this.setAttribute('user-id', user.id);
         this.passwordField.value = '';
         this.passwordConfirmField.value = '';
@@ -100,6 +120,11 @@ class UserEntry extends HTMLElement {
         this.deleteButton.hidden = true;
     }
 
+    showCamerasField() {
+        // Only viewers are restricted to specific cameras
+        this.camerasWrapper.hidden = this.roleField.value != 'viewer';
+    }
+
     //#endregion
 
     //#region API Interaction
@@ -136,6 +161,39 @@ class UserEntry extends HTMLElement {
             .then(r => this.handleUploadResponse(r));
     }
 
+    loadCameras() {
+
+        let url = '/api/users/' + this.getAttribute('user-id') + '/cameras';
+        let init = {
+            credentials: 'same-origin',
+        };
+
+        fetch(url, init)
+            .then(r => r.json())
+            .then(ids => {
+                Array.from(this.camerasField.options)
+                    .forEach(o => o.selected = ids.includes(parseInt(o.value)));
+            });
+    }
+
+    uploadCameras() {
+
+        let ids = Array.from(this.camerasField.selectedOptions)
+            .map(o => parseInt(o.value));
+
+        let url = '/api/users/' + this.getAttribute('user-id') + '/cameras';
+        let init = {
+            method: 'PUT',
+            headers: {
+                'Content-Type': 'application/json'
+            },
+            credentials: 'same-origin',
+            body: JSON.stringify(ids),
+        };
+
+        return fetch(url, init);
+    }
+
     handleUploadResponse(response) {
 
         this.activeSubmission = false;
@@ -146,10 +204,12 @@ class UserEntry extends HTMLElement {
         let jsonPromise = response.json();
 
         if (response.ok) {
-            jsonPromise.then(u => {
-                this.reload(u);
-                showMessage('User changes were saved successfully', 'success');
-            });
+            jsonPromise
+                .then(u => {
+                    this.reload(u);
+                    return this.uploadCameras();
+                })
+                .then(_ => showMessage('User changes were saved successfully', 'success'));
         } else {
             jsonPromise.then(e => showMessage(e.message, 'error'));
         }
@@ -174,7 +234,53 @@ class UserEntry extends HTMLElement {
             this.parentElement.removeChild(this);
 
         } else {
-            repsonse.json()
+            response.json()
+                .then(e => showMessage(e.message, 'error'));
+        }
+    }
+
+    loadTotpStatus() {
+
+        let url = '/api/users/' + this.getAttribute('user-id') + '/totp';
+        let init = {
+            credentials: 'same-origin',
+        };
+
+        fetch(url, init)
+            .then(r => r.json())
+            .then(s => {
+                // Only users who have enabled two-factor authentication need a reset
+                this.totpWrapper.hidden = !s.enabled;
+            });
+    }
+
+    resetTotp() {
+
+        let url = '/api/users/' + this.getAttribute('user-id') + '/totp';
+        let init = {
+            headers: {
+                'Content-Type': 'application/json'
+            },
+            credentials: 'same-origin',
+            method: 'DELETE',
+            body: JSON.stringify({
+                password: this.totpResetPasswordField.value
+            })
+        };
+
+        fetch(url, init)
+            .then(r => this.handleTotpResetResponse(r));
+    }
+
+    handleTotpResetResponse(response) {
+
+        if (response.ok) {
+            showMessage('Two-factor authentication successfully reset', 'success');
+            this.totpResetPasswordField.value = '';
+            this.totpWrapper.hidden = true;
+
+        } else {
+            response.json()
                 .then(e => showMessage(e.message, 'error'));
         }
     }
@@ -194,6 +300,9 @@ class UserEntry extends HTMLElement {
             this.passwordConfirmField.value = '';
             this.passwordField.value = '';
             this.usernameField.value = this.getAttribute('username');
+            this.roleField.value = this.getAttribute('role');
+            this.showCamerasField();
+            this.loadCameras();
         }
     }
 
@@ -204,6 +313,13 @@ class UserEntry extends HTMLElement {
         }
     }
 
+    onTotpResetButtonClicked(_) {
+
+        if (confirm('Are you sure you want to reset two-factor authentication for this user?')) {
+            this.resetTotp();
+        }
+    }
+
     onHeaderClicked(_) {
 
         if (this.formWrapper.hidden) {
@@ -217,6 +333,7 @@ class UserEntry extends HTMLElement {
 
         let user = {
             username: this.usernameField.value,
+            role: this.roleField.value,
         };
 
         if (this.passwordField.value) {
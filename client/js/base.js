@@ -90,3 +90,21 @@ function showMessage(message, level) {
 
     messageArea.appendChild(messageBanner);
 }
+
+
+var logoutLink = document.getElementById('logout-link');
+
+function logout() {
+
+    let init = {
+        method: 'DELETE',
+        credentials: 'same-origin',
+    };
+
+    fetch('/api/sessions/current', init)
+        .then(_ => window.location = '/login');
+}
+
+if (logoutLink != null) {
+    logoutLink.onclick = logout;
+}
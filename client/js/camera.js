@@ -1,18 +1,187 @@
 var stream = document.getElementById('stream');
+var timeline = document.getElementById('timeline');
+var timelineCursor = document.getElementById('timeline-cursor');
+var timelineRange = document.getElementById('timeline-range');
+var timelineStart = document.getElementById('timeline-start');
+var timelineEnd = document.getElementById('timeline-end');
+var liveButton = document.getElementById('live-button');
+var playbackPosition = document.getElementById('playback-position');
+
+var cameraId = stream.dataset.cameraId;
+var hls = null;
+
+// Time range currently shown by the timeline
+var rangeStart = null;
+var rangeEnd = null;
+
+// Recorded ranges shown by the timeline, oldest first
+var recordings = [];
+
+// Wall-clock time at which the current playback started (null while live)
+var playbackStart = null;
+
+
+//#region Playback
+
+function playSource(url) {
 
-window.onload = function() {
-    
     if (Hls.isSupported()) {
-        let hls = new Hls();
-        hls.loadSource(stream.dataset.streamUrl);
+        if (hls != null) {
+            hls.destroy();
+        }
+        hls = new Hls();
+        hls.loadSource(url);
         hls.attachMedia(stream);
         hls.on(Hls.Events.MANIFEST_PARSED, function() { stream.play(); });
 
     } else if (stream.canPlayType('application/vnd.apple.mpegurl')) {
-        stream.src = stream.dataset.streamUrl;
+        stream.src = url;
         stream.addEventListener('loadmetadata', function() { stream.play(); });
 
     } else {
         showMessage('Browser does not support HLS streaming', 'warning');
     }
 }
+
+function playLive() {
+
+    playbackStart = null;
+    liveButton.disabled = true;
+    playbackPosition.innerText = '';
+    timelineCursor.hidden = true;
+    playSource(stream.dataset.streamUrl);
+}
+
+function playRecording(start) {
+
+    // Play until the end of the recording containing the chosen time
+    let recording = recordings.find(r => r.start <= start && start < r.end);
+    if (recording == null) {
+        return;
+    }
+
+    playbackStart = start;
+    liveButton.disabled = false;
+
+    let url = '/cameras/' + cameraId + '/playback.m3u8'
+        + '?start=' + encodeURIComponent(start.toISOString())
+        + '&end=' + encodeURIComponent(recording.end.toISOString());
+    playSource(url);
+}
+
+function onTimeUpdate() {
+
+    if (playbackStart == null) {
+        return;
+    }
+
+    let position = new Date(playbackStart.getTime() + stream.currentTime * 1000);
+    playbackPosition.innerText = position.toLocaleString();
+    moveCursor(position);
+}
+
+//#endregion
+
+
+//#region Timeline
+
+function parseTime(time) {
+
+    // Times are reported in UTC without an offset
+    return new Date(time + 'Z');
+}
+
+function timelineOffset(time) {
+
+    let fraction = (time - rangeStart) / (rangeEnd - rangeStart);
+    return Math.min(Math.max(fraction, 0), 1) * 100 + '%';
+}
+
+function moveCursor(time) {
+
+    timelineCursor.hidden = time < rangeStart || time > rangeEnd;
+    timelineCursor.style.left = timelineOffset(time);
+}
+
+function addTimelineElement(className, start, end) {
+
+    let element = document.createElement('div');
+    element.className = className;
+    element.style.left = timelineOffset(start);
+    if (end != null) {
+        element.style.right = 'calc(100% - ' + timelineOffset(end) + ')';
+    }
+    timeline.appendChild(element);
+}
+
+function loadTimeline() {
+
+    rangeEnd = new Date();
+    rangeStart = new Date(rangeEnd.getTime() - timelineRange.value * 60 * 60 * 1000);
+    timelineStart.innerText = rangeStart.toLocaleString();
+    timelineEnd.innerText = rangeEnd.toLocaleString();
+
+    let init = {
+        credentials: 'same-origin',
+    };
+
+    Promise.all([
+        fetch('/api/cameras/' + cameraId + '/recordings', init).then(handleJsonResponse),
+        fetch('/api/cameras/' + cameraId + '/events', init).then(handleJsonResponse),
+    ])
+        .then(results => renderTimeline(results[0], results[1]))
+        .catch(e => showMessage(e.message, 'error'));
+}
+
+function handleJsonResponse(response) {
+
+    if (response.ok) {
+        return response.json();
+    }
+
+    return response.json()
+        .then(e => Promise.reject(new Error(e.message)));
+}
+
+function renderTimeline(recordingList, eventList) {
+
+    timeline.querySelectorAll('.timeline-range, .timeline-event')
+        .forEach(e => e.remove());
+
+    recordings = recordingList
+        .map(r => ({
+            start: parseTime(r.startedAt),
+            end: r.endedAt == null ? new Date() : parseTime(r.endedAt),
+        }))
+        .filter(r => r.end > rangeStart && r.start < rangeEnd)
+        .sort((a, b) => a.start - b.start);
+    recordings.forEach(r => addTimelineElement('timeline-range', r.start, r.end));
+
+    eventList
+        .map(e => parseTime(e.startedAt))
+        .filter(t => t >= rangeStart && t <= rangeEnd)
+        .forEach(t => addTimelineElement('timeline-event', t, null));
+}
+
+function onTimelineClicked(e) {
+
+    let bounds = timeline.getBoundingClientRect();
+    let fraction = (e.clientX - bounds.left) / bounds.width;
+    let time = new Date(rangeStart.getTime() + fraction * (rangeEnd - rangeStart));
+
+    playRecording(time);
+}
+
+//#endregion
+
+
+window.onload = function() {
+
+    liveButton.onclick = _ => playLive();
+    timeline.onclick = e => onTimelineClicked(e);
+    timelineRange.onchange = _ => loadTimeline();
+    stream.addEventListener('timeupdate', onTimeUpdate);
+
+    playLive();
+    loadTimeline();
+}
@@ -2,6 +2,8 @@
 var submitButton = document.getElementById('submit');
 var usernameField = document.getElementById('username');
 var passwordField = document.getElementById('password');
+var codeField = document.getElementById('code');
+var codeWrapper = document.getElementById('code-wrapper');
 
 var activeSubmission = false;
 
@@ -25,8 +27,17 @@ function handleSubmitResponse(response) {
         window.location = dest;
     } else {
 
-        // TODO: extract message from response
-        showMessage('Failed to login', 'error');
+        response.json()
+            .then(e => {
+                // Password was correct, but the user has two-factor authentication enabled
+                if (e.codeRequired) {
+                    codeWrapper.hidden = false;
+                    codeField.focus();
+                } else {
+                    showMessage(e.message, 'error');
+                }
+            })
+            .catch(_ => showMessage('Failed to login', 'error'));
     }
 }
 
@@ -50,7 +61,8 @@ function submit() {
         method: 'PUT',
         body: JSON.stringify({
             username: usernameField.value,
-            password: passwordField.value
+            password: passwordField.value,
+            code: codeField.value || null
         })
     };
 
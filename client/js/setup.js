@@ -0,0 +1,59 @@
+var setupForm = document.getElementById('setup-form');
+var submitButton = document.getElementById('submit');
+var passwordField = document.getElementById('password');
+var passwordConfirmField = document.getElementById('password-cfrm');
+
+var activeSubmission = false;
+
+function handleSubmitResponse(response) {
+
+    activeSubmission = false;
+    submitButton.disabled = false;
+    submitButton.classList.remove('is-loading');
+
+    if (response.ok) {
+        window.location = window.location.origin + '/';
+    } else {
+        response.json()
+            .then(e => showMessage(e.message, 'error'));
+    }
+}
+
+function submit() {
+
+    // Only allow one in-flight submission at a time
+    if (activeSubmission) {
+        return;
+    }
+
+    if (!passwordField.value) {
+        showMessage('Password must not be empty', 'error');
+        return;
+    }
+
+    if (passwordField.value != passwordConfirmField.value) {
+        showMessage('Passwords do not match', 'error');
+        return;
+    }
+
+    activeSubmission = true;
+    submitButton.disabled = true;
+    submitButton.classList.add('is-loading');
+
+    let url = '/api/users/' + setupForm.dataset.userId;
+    let init = {
+        headers: {
+            'Content-Type': 'application/json'
+        },
+        credentials: 'same-origin',
+        method: 'PATCH',
+        body: JSON.stringify({
+            password: passwordField.value
+        })
+    };
+
+    fetch(url, init)
+        .then(handleSubmitResponse);
+}
+
+submitButton.onclick = submit;
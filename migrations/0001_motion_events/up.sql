@@ -0,0 +1,25 @@
+CREATE TABLE motion_events (
+
+    id
+        INTEGER
+        PRIMARY KEY ASC
+        NOT NULL,
+
+    started_at
+        TIMESTAMP
+        NOT NULL,
+
+    ended_at
+        TIMESTAMP,
+
+    peak_score
+        REAL
+        NOT NULL,
+
+    camera_id
+        INTEGER
+
+);
+
+CREATE INDEX idx_motion_event_started_at
+ON motion_events (started_at);
@@ -0,0 +1,23 @@
+CREATE TABLE recordings (
+
+    id
+        INTEGER
+        PRIMARY KEY ASC
+        NOT NULL,
+
+    started_at
+        TIMESTAMP
+        NOT NULL,
+
+    ended_at
+        TIMESTAMP,
+
+    size_bytes
+        BIGINT
+        NOT NULL
+        DEFAULT 0
+
+);
+
+CREATE INDEX idx_recording_started_at
+ON recordings (started_at);
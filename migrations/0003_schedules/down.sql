@@ -0,0 +1,3 @@
+DROP TABLE camera_schedules;
+
+DROP TABLE schedule_overrides;
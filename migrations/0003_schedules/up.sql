@@ -0,0 +1,53 @@
+CREATE TABLE camera_schedules (
+
+    id
+        INTEGER
+        PRIMARY KEY ASC
+        NOT NULL,
+
+    camera_id
+        INTEGER
+        NOT NULL
+        REFERENCES cameras (id)
+            ON DELETE CASCADE,
+
+    weekday
+        INTEGER
+        NOT NULL,
+
+    start_time
+        TIME
+        NOT NULL,
+
+    end_time
+        TIME
+        NOT NULL
+
+);
+
+CREATE TABLE schedule_overrides (
+
+    id
+        INTEGER
+        PRIMARY KEY ASC
+        NOT NULL,
+
+    camera_id
+        INTEGER
+        NOT NULL
+        REFERENCES cameras (id)
+            ON DELETE CASCADE,
+
+    enabled
+        BOOLEAN
+        NOT NULL,
+
+    starts_at
+        TIMESTAMP
+        NOT NULL,
+
+    ends_at
+        TIMESTAMP
+        NOT NULL
+
+);
@@ -0,0 +1,29 @@
+DROP TABLE camera_permissions;
+
+-- SQLite cannot drop columns, so the users table must be rebuilt
+CREATE TABLE users_old (
+
+    id
+        INTEGER
+        PRIMARY KEY ASC
+        NOT NULL,
+
+    username
+        TEXT
+        NOT NULL
+        UNIQUE,
+
+    pwhash
+        TEXT
+        NOT NULL
+
+);
+
+INSERT INTO users_old (id, username, pwhash)
+SELECT id, username, pwhash
+FROM users;
+
+DROP TABLE users;
+
+ALTER TABLE users_old
+RENAME TO users;
@@ -0,0 +1,24 @@
+-- Existing users were created with full privileges, so they become admins
+ALTER TABLE users
+ADD COLUMN role
+    INTEGER
+    NOT NULL
+    DEFAULT 0;
+
+CREATE TABLE camera_permissions (
+
+    user_id
+        INTEGER
+        NOT NULL
+        REFERENCES users (id)
+            ON DELETE CASCADE,
+
+    camera_id
+        INTEGER
+        NOT NULL
+        REFERENCES cameras (id)
+            ON DELETE CASCADE,
+
+    PRIMARY KEY (user_id, camera_id)
+
+);
@@ -0,0 +1,32 @@
+-- SQLite cannot drop columns, so the sessions table must be rebuilt
+CREATE TABLE sessions_old (
+
+    id
+        INTEGER
+        PRIMARY KEY ASC
+        NOT NULL,
+
+    key
+        TEXT
+        NOT NULL
+        UNIQUE,
+
+    user_id
+        INTEGER
+        NOT NULL
+        REFERENCES users (id)
+            ON DELETE CASCADE
+
+);
+
+INSERT INTO sessions_old (id, key, user_id)
+SELECT id, key, user_id
+FROM sessions;
+
+DROP TABLE sessions;
+
+ALTER TABLE sessions_old
+RENAME TO sessions;
+
+CREATE UNIQUE INDEX idx_session_key
+ON sessions (key);
@@ -0,0 +1,15 @@
+-- Existing sessions have no timestamps and would never expire, so everyone
+-- must log in again
+DELETE FROM sessions;
+
+ALTER TABLE sessions
+ADD COLUMN created_at
+    TIMESTAMP
+    NOT NULL
+    DEFAULT '1970-01-01 00:00:00';
+
+ALTER TABLE sessions
+ADD COLUMN last_seen_at
+    TIMESTAMP
+    NOT NULL
+    DEFAULT '1970-01-01 00:00:00';
@@ -0,0 +1,29 @@
+CREATE TABLE auth_events (
+
+    id
+        INTEGER
+        PRIMARY KEY ASC
+        NOT NULL,
+
+    occurred_at
+        TIMESTAMP
+        NOT NULL,
+
+    kind
+        INTEGER
+        NOT NULL,
+
+    username
+        TEXT
+        NOT NULL,
+
+    actor
+        TEXT,
+
+    remote_addr
+        TEXT
+
+);
+
+CREATE INDEX idx_auth_events_occurred_at
+ON auth_events (occurred_at);
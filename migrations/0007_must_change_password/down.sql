@@ -0,0 +1,32 @@
+-- SQLite cannot drop columns, so the users table must be rebuilt
+CREATE TABLE users_old (
+
+    id
+        INTEGER
+        PRIMARY KEY ASC
+        NOT NULL,
+
+    username
+        TEXT
+        NOT NULL
+        UNIQUE,
+
+    pwhash
+        TEXT
+        NOT NULL,
+
+    role
+        INTEGER
+        NOT NULL
+        DEFAULT 0
+
+);
+
+INSERT INTO users_old (id, username, pwhash, role)
+SELECT id, username, pwhash, role
+FROM users;
+
+DROP TABLE users;
+
+ALTER TABLE users_old
+RENAME TO users;
@@ -0,0 +1,38 @@
+CREATE TABLE api_tokens (
+
+    id
+        INTEGER
+        PRIMARY KEY ASC
+        NOT NULL,
+
+    user_id
+        INTEGER
+        NOT NULL
+        REFERENCES users (id)
+            ON DELETE CASCADE,
+
+    name
+        TEXT
+        NOT NULL,
+
+    token_hash
+        TEXT
+        NOT NULL
+        UNIQUE,
+
+    scope
+        INTEGER
+        NOT NULL
+        DEFAULT 0,
+
+    created_at
+        TIMESTAMP
+        NOT NULL,
+
+    expires_at
+        TIMESTAMP,
+
+    last_used_at
+        TIMESTAMP
+
+);
@@ -0,0 +1,45 @@
+-- SQLite cannot drop columns, so the cameras table must be rebuilt
+CREATE TABLE cameras_old (
+
+    id
+        INTEGER
+        PRIMARY KEY ASC
+        NOT NULL,
+
+    name
+        TEXT
+        NOT NULL,
+
+    address
+        TEXT
+        NOT NULL,
+
+    enabled
+        BOOLEAN
+        NOT NULL
+        DEFAULT FALSE,
+
+    orientation
+        INTEGER
+        NOT NULL
+        DEFAULT 0,
+
+    local
+        BOOLEAN
+        NOT NULL
+        DEFAULT FALSE,
+
+    key
+        BLOB
+        NOT NULL
+
+);
+
+INSERT INTO cameras_old (id, name, address, enabled, orientation, local, key)
+SELECT id, name, address, enabled, orientation, local, key
+FROM cameras;
+
+DROP TABLE cameras;
+
+ALTER TABLE cameras_old
+RENAME TO cameras;
@@ -0,0 +1,3 @@
+ALTER TABLE cameras
+ADD COLUMN pairing_key
+    BLOB;
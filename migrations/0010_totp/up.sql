@@ -0,0 +1,41 @@
+CREATE TABLE totp_credentials (
+
+    user_id
+        INTEGER
+        PRIMARY KEY ASC
+        NOT NULL
+        REFERENCES users (id)
+            ON DELETE CASCADE,
+
+    secret
+        BLOB
+        NOT NULL,
+
+    enabled
+        BOOLEAN
+        NOT NULL
+        DEFAULT FALSE,
+
+    last_step
+        BIGINT
+
+);
+
+CREATE TABLE totp_recovery_codes (
+
+    id
+        INTEGER
+        PRIMARY KEY ASC
+        NOT NULL,
+
+    user_id
+        INTEGER
+        NOT NULL
+        REFERENCES users (id)
+            ON DELETE CASCADE,
+
+    code_hash
+        TEXT
+        NOT NULL
+
+);
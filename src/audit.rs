@@ -0,0 +1,359 @@
+//! Authentication audit trail
+//!
+//! Security-relevant events such as logins and changes to user accounts are
+//! recorded in the database so that admins can review them later.
+
+
+use std::env;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+
+use actix_web::HttpRequest;
+use actix_web::web::{self, Data, Json, Query, ServiceConfig};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::backend::Backend;
+use diesel::deserialize::{self, FromSql};
+use diesel::prelude::*;
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::Integer;
+use lazy_static::lazy_static;
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::db::{ConnectionPool, PooledConnection};
+use crate::db::schema::auth_events;
+use crate::error::Result;
+use crate::users::{AuthenticationMiddleware, Role};
+
+
+/// Events older than this are discarded
+const RETENTION_DAYS: i64 = 90;
+
+/// Maximum number of events returned by the API
+const MAX_EVENTS: i64 = 500;
+
+
+/// Kind of authentication event
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(AsExpression, FromSqlRow)]
+#[sql_type = "Integer"]
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AuthEventKind {
+    LoginSucceeded,
+    LoginFailed,
+
+    /// Login was refused without checking the password, because of too many
+    /// recent failures
+    LoginLocked,
+    PasswordChanged,
+    UserCreated,
+    UserDeleted,
+    TotpEnabled,
+    TotpDisabled,
+}
+
+impl<B> FromSql<Integer, B> for AuthEventKind
+where
+    B: Backend,
+    i32: FromSql<Integer, B>,
+{
+    fn from_sql(bytes: Option<&B::RawValue>) -> deserialize::Result<Self> {
+        match i32::from_sql(bytes)? {
+            0 => Ok(Self::LoginSucceeded),
+            1 => Ok(Self::LoginFailed),
+            2 => Ok(Self::LoginLocked),
+            3 => Ok(Self::PasswordChanged),
+            4 => Ok(Self::UserCreated),
+            5 => Ok(Self::UserDeleted),
+            6 => Ok(Self::TotpEnabled),
+            7 => Ok(Self::TotpDisabled),
+            other => Err(format!("Unrecognized value \"{}\"", other).into()),
+        }
+    }
+}
+
+impl<B> ToSql<Integer, B> for AuthEventKind
+where
+    B: Backend,
+    i32: ToSql<Integer, B>,
+{
+    fn to_sql<W: Write>(&self, out: &mut Output<W, B>) -> serialize::Result {
+        let val = match *self {
+            Self::LoginSucceeded => 0,
+            Self::LoginFailed => 1,
+            Self::LoginLocked => 2,
+            Self::PasswordChanged => 3,
+            Self::UserCreated => 4,
+            Self::UserDeleted => 5,
+            Self::TotpEnabled => 6,
+            Self::TotpDisabled => 7,
+        };
+
+        val.to_sql(out)
+    }
+}
+
+
+/// Recorded authentication event
+#[derive(Serialize)]
+#[derive(Queryable)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthEvent {
+    pub id: i32,
+    pub occurred_at: NaiveDateTime,
+    pub kind: AuthEventKind,
+
+    /// User to which the event applies
+    pub username: String,
+
+    /// User who caused the event, if different from `username`
+    pub actor: Option<String>,
+
+    /// Address from which the request was made
+    pub remote_addr: Option<String>,
+}
+
+
+#[derive(Insertable)]
+#[table_name = "auth_events"]
+struct NewAuthEvent<'a> {
+    occurred_at: NaiveDateTime,
+    kind: AuthEventKind,
+    username: &'a str,
+    actor: Option<&'a str>,
+    remote_addr: Option<&'a str>,
+}
+
+
+lazy_static! {
+    /// Addresses of reverse proxies whose forwarding headers are trusted
+    ///
+    /// Read from the comma-separated LC_TRUSTED_PROXY environment variable,
+    /// defaulting to the loopback addresses from which the local Nginx or Caddy
+    /// forwards requests. Setting it to an empty string trusts no proxy.
+    static ref TRUSTED_PROXIES: Vec<IpAddr> = match env::var("LC_TRUSTED_PROXY") {
+        Ok(list) => list.split(',')
+            .map(str::trim)
+            .filter(|addr| !addr.is_empty())
+            .filter_map(|addr| match addr.parse() {
+                Ok(addr) => Some(addr),
+                Err(_) => {
+                    warn!("ignoring invalid trusted proxy address {}", addr);
+                    None
+                },
+            })
+            .collect(),
+        Err(_) => vec![
+            IpAddr::from([127, 0, 0, 1]),
+            IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1]),
+        ],
+    };
+}
+
+
+/// Address from which a request was made
+#[derive(Debug, PartialEq)]
+enum RemoteAddr {
+    /// Address of the client itself
+    Client(String),
+    /// Address of a trusted proxy which did not say which client it forwarded
+    /// the request for
+    Proxy(String),
+}
+
+
+/// Works out the address of the client which made a request
+///
+/// Forwarding headers are only honored when the request was made by one of
+/// the trusted proxies, as anyone else could use them to pose as a different
+/// client.
+fn resolve_remote_addr(peer: IpAddr, forwarded: Option<&str>, trusted: &[IpAddr]) -> RemoteAddr {
+
+    if !trusted.contains(&peer) {
+        return RemoteAddr::Client(peer.to_string());
+    }
+
+    // The last address was added by the trusted proxy itself, whereas earlier
+    // ones were supplied by the client
+    let forwarded = forwarded
+        .and_then(|value| value.rsplit(',').next())
+        .map(str::trim)
+        .filter(|addr| !addr.is_empty());
+    let remote = match forwarded {
+        Some(remote) => remote,
+        None => return RemoteAddr::Proxy(peer.to_string()),
+    };
+
+    // Strip the port, if any
+    let addr = match remote.parse::<SocketAddr>() {
+        Ok(addr) => addr.ip().to_string(),
+        Err(_) => remote.to_owned(),
+    };
+
+    RemoteAddr::Client(addr)
+}
+
+
+/// Works out the address of the client which made a request, using
+/// `TRUSTED_PROXIES`
+fn request_remote_addr(req: &HttpRequest) -> Option<RemoteAddr> {
+
+    let peer = req.peer_addr()?.ip();
+    let forwarded = req.headers().get("X-Forwarded-For")
+        .and_then(|value| value.to_str().ok());
+
+    Some(resolve_remote_addr(peer, forwarded, &TRUSTED_PROXIES))
+}
+
+
+/// Gets the address from which a request was made
+///
+/// This is the client's address where known, but falls back to the address of
+/// a trusted proxy which did not forward it.
+pub fn remote_addr(req: &HttpRequest) -> Option<String> {
+
+    match request_remote_addr(req)? {
+        RemoteAddr::Client(addr) | RemoteAddr::Proxy(addr) => Some(addr),
+    }
+}
+
+
+/// Gets the address of the client which made a request
+///
+/// Unlike `remote_addr`, this never returns the address of a trusted proxy, as
+/// that would lump together every client behind it.
+pub fn client_addr(req: &HttpRequest) -> Option<String> {
+
+    match request_remote_addr(req)? {
+        RemoteAddr::Client(addr) => Some(addr),
+        RemoteAddr::Proxy(_) => None,
+    }
+}
+
+
+/// Records an authentication event
+///
+/// Failing to record an event should not prevent the action being audited, so
+/// errors are logged rather than returned.
+pub fn record(
+    kind: AuthEventKind,
+    username: &str,
+    actor: Option<&str>,
+    req: &HttpRequest,
+    conn: &PooledConnection,
+) {
+    let remote_addr = remote_addr(req);
+    let now = Utc::now().naive_utc();
+
+    debug!("recording {:?} event for {}", kind, username);
+    let event = NewAuthEvent {
+        occurred_at: now,
+        kind,
+        username,
+        actor: actor.filter(|&actor| actor != username),
+        remote_addr: remote_addr.as_deref(),
+    };
+    let res = diesel::insert_into(auth_events::table)
+        .values(&event)
+        .execute(conn)
+        .and_then(|_| {
+            let cutoff = now - Duration::days(RETENTION_DAYS);
+            diesel::delete(auth_events::table.filter(auth_events::occurred_at.lt(cutoff)))
+                .execute(conn)
+        });
+
+    if let Err(err) = res {
+        error!("failed to record {:?} event for {}: {}", kind, username, err);
+    }
+}
+
+
+/// Retrieves the most recent events, newest first
+pub fn recent(username: Option<&str>, conn: &PooledConnection) -> Result<Vec<AuthEvent>> {
+
+    let mut query = auth_events::table
+        .order(auth_events::occurred_at.desc())
+        .limit(MAX_EVENTS)
+        .into_boxed();
+
+    if let Some(username) = username {
+        query = query.filter(auth_events::username.eq(username));
+    }
+
+    Ok(query.load(conn)?)
+}
+
+
+//#region API
+
+/// Filters accepted when retrieving events
+#[derive(Deserialize)]
+struct EventsQuery {
+    username: Option<String>,
+}
+
+/// Retrieves recent authentication events
+fn get_auth_events(
+    pool: Data<ConnectionPool>,
+    query: Query<EventsQuery>,
+) -> Result<Json<Vec<AuthEvent>>>
+{
+    let conn = pool.get()?;
+
+    Ok(Json(recent(query.username.as_deref(), &conn)?))
+}
+
+
+/// Configures the */auth-events* API resource
+pub fn configure_api(service: &mut ServiceConfig) {
+
+    service.service(
+        web::resource("/auth-events")
+            .route(web::get().to(get_auth_events))
+            .wrap(AuthenticationMiddleware::reject().require(Role::Admin))
+    );
+}
+
+//#endregion
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn trusted() -> Vec<IpAddr> {
+        vec![IpAddr::from([127, 0, 0, 1])]
+    }
+
+    #[test]
+    fn untrusted_peers_cannot_forward() {
+
+        let peer = IpAddr::from([192, 0, 2, 1]);
+        let remote = resolve_remote_addr(peer, Some("198.51.100.7"), &trusted());
+
+        assert_eq!(remote, RemoteAddr::Client("192.0.2.1".to_owned()));
+    }
+
+    #[test]
+    fn trusted_peers_forward_the_last_address() {
+
+        let peer = IpAddr::from([127, 0, 0, 1]);
+
+        let remote = resolve_remote_addr(peer, Some("203.0.113.9, 198.51.100.7"), &trusted());
+        assert_eq!(remote, RemoteAddr::Client("198.51.100.7".to_owned()));
+
+        let remote = resolve_remote_addr(peer, Some("198.51.100.7:4321"), &trusted());
+        assert_eq!(remote, RemoteAddr::Client("198.51.100.7".to_owned()));
+    }
+
+    #[test]
+    fn trusted_peers_without_forwarding_are_not_clients() {
+
+        let peer = IpAddr::from([127, 0, 0, 1]);
+
+        assert_eq!(resolve_remote_addr(peer, None, &trusted()), RemoteAddr::Proxy("127.0.0.1".to_owned()));
+        assert_eq!(resolve_remote_addr(peer, Some(" "), &trusted()), RemoteAddr::Proxy("127.0.0.1".to_owned()));
+    }
+}
@@ -1,25 +1,36 @@
 //! Camera management
 
 
-use std::fs;
-use std::path::Path;
-use std::sync::RwLock;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, RwLock};
+use std::time::Instant;
 
 use actix_web::http::{StatusCode};
+use actix_web::HttpResponse;
 use actix_web::web::{self, Data, Json, ServiceConfig};
 use diesel::prelude::*;
+use lazy_static::lazy_static;
 use log::{debug, error, info, trace, warn};
-use reqwest::Client;
+use reqwest::{Client, Method, RequestBuilder};
 use serde::{Deserialize, Serialize};
-use tera::{Context, Tera};
+use tera::Tera;
 
-use crate::do_write;
+use crate::do_lock;
+#[cfg(feature = "stream")]
+use crate::{do_read, do_write};
 use crate::db::{ConnectionPool, PooledConnection};
 use crate::db::schema::cameras;
+use crate::discovery::{self, DiscoveredCamera};
 use crate::error::{Error, Result};
+use crate::health::{self, CameraHealth};
+use crate::motion::{self, MotionEvent};
+use crate::pairing;
+use crate::recording::{self, Recording, Relay, Segment};
+use crate::schedules;
+use crate::prochost::HostDiagnostics;
 use crate::proxy;
-use crate::stream::{Orientation, Stream, StreamState, StreamUpdate};
-use crate::users::AuthenticationMiddleware;
+use crate::stream::{self, Orientation, Stream, StreamState, StreamUpdate};
+use crate::users::{self, AuthenticatedUser, AuthenticationMiddleware, Role};
 
 
 /// Representation of a streaming camera
@@ -34,14 +45,85 @@ pub struct Camera {
     pub orientation: Orientation,
     pub local: bool,
     pub key: Vec<u8>,
+
+    /// Key used to sign requests to a remote camera's stream API
+    #[serde(skip)]
+    pub pairing_key: Option<Vec<u8>>,
+}
+
+impl Camera {
+
+    /// Returns whether the portal holds a key for this camera's stream API
+    ///
+    /// Remote cameras added before pairing was introduced have no key, and
+    /// must be re-paired before they can be used.
+    pub fn is_paired(&self) -> bool {
+        self.local || self.pairing_key.is_some()
+    }
+
+    /// Builds a signed request to this camera's stream API
+    pub(crate) fn request(&self, client: &Client, method: Method, path: &str) -> Result<RequestBuilder> {
+
+        match self.pairing_key {
+            Some(ref key) => pairing::request(client, method, &self.address, path, key),
+            None => Error::web(StatusCode::CONFLICT, "camera is not paired"),
+        }
+    }
+
+    /// Builds a signed request to this camera's stream API, with a JSON body
+    pub(crate) fn request_json<T: Serialize>(
+        &self,
+        client: &Client,
+        method: Method,
+        path: &str,
+        body: &T,
+    ) -> Result<RequestBuilder>
+    {
+        match self.pairing_key {
+            Some(ref key) => pairing::request_json(client, method, &self.address, path, key, body),
+            None => Error::web(StatusCode::CONFLICT, "camera is not paired"),
+        }
+    }
+}
+
+
+/// Camera along with its most recently observed health
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CameraWithHealth {
+    #[serde(flatten)]
+    pub camera: Camera,
+
+    /// Not known for local cameras
+    pub health: Option<CameraHealth>,
+
+    /// Whether the camera is enabled, but could not be added to the proxy
+    /// configuration yet
+    pub proxy_pending: bool,
+
+    /// Whether the camera can be used, or must be re-paired first
+    pub paired: bool,
+}
+
+impl From<Camera> for CameraWithHealth {
+    fn from(camera: Camera) -> Self {
+        let health = health::get(camera.id);
+        let proxy_pending = camera.enabled && proxy_pending(camera.id);
+        let paired = camera.is_paired();
+        Self { camera, health, proxy_pending, paired }
+    }
 }
 
 
 /// Camera representation required by PUT requests
 #[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 struct PutCameraBody {
     name: String,
     address: String,
+
+    /// Code logged by the camera host, used to pair with it
+    pairing_code: String,
 }
 
 
@@ -54,124 +136,545 @@ struct NewCamera<'a> {
     orientation: Orientation,
     local: bool,
     key: &'a [u8],
+    pairing_key: Option<&'a [u8]>,
 }
 
 
-/// Creates a new camera
-fn put_camera(
-    client: Data<Client>,
-    pool: Data<ConnectionPool>,
-    templates: Data<Tera>,
-    body: Json<PutCameraBody>,
-) -> Result<Json<Camera>>
+/// Pairs with a camera host and adds it to the database
+fn add_camera(
+    client: &Client,
+    pool: &ConnectionPool,
+    templates: &Tera,
+    name: &str,
+    address: &str,
+    pairing_code: &str,
+) -> Result<Camera>
 {
     // Validate connection before touching the database
-    debug!("connecting to camera at {}", body.address);
-    let url = format!("http://{}/api/stream", body.address);
-    let stream: StreamState = client.get(&url)
+    let pairing_key = pairing::pair(client, address, pairing_code)?;
+
+    // The camera host will not pair again while it holds this key, so it must
+    // be released if the camera cannot be added after all
+    let camera = match insert_paired_camera(client, pool, name, address, &pairing_key) {
+        Ok(camera) => camera,
+        Err(err) => {
+            pairing::unpair(client, address, &pairing_key)
+                .unwrap_or_else(|e| error!("failed to unpair from camera at {}: {}", address, e));
+            return Err(err);
+        },
+    };
+
+    if camera.enabled {
+        write_proxy_config(&camera, client, templates)
+            .and_then(|_| proxy::reload())
+            .unwrap_or_else(|e|
+                error!("failed to configure proxy for camera {}: {}", camera.id, e)
+            );
+    }
+
+    info!("created new camera {}", camera.id);
+
+    Ok(camera)
+}
+
+
+/// Adds a camera host which has just been paired to the database
+fn insert_paired_camera(
+    client: &Client,
+    pool: &ConnectionPool,
+    name: &str,
+    address: &str,
+    pairing_key: &[u8],
+) -> Result<Camera>
+{
+    debug!("connecting to camera at {}", address);
+    let stream: StreamState = pairing::request(
+        client,
+        Method::GET,
+        address,
+        "/api/stream",
+        pairing_key,
+    )?
         .send()?
+        .error_for_status()?
         .json()?;
 
     debug!("adding new camera to database");
     let conn = pool.get()?;
     let new_cam = NewCamera {
-        name: &body.name,
-        address: &body.address,
+        name,
+        address,
         enabled: stream.enabled,
         orientation: stream.orientation,
         local: false,
         key: &stream.key,
+        pairing_key: Some(pairing_key),
     };
     diesel::insert_into(cameras::table)
         .values(&new_cam)
         .execute(&conn)?;
 
     // Get the row we just inserted
-    let camera: Camera = cameras::table.order(cameras::id.desc())
+    let camera = cameras::table.order(cameras::id.desc())
         .first(&conn)?;
 
-    if camera.enabled {
-        write_proxy_config(&camera, &client, &templates)
-            .and_then(|_| proxy::reload())
-            .unwrap_or_else(|e|
-                error!("failed to configure proxy for camera {}: {}", camera.id, e)
-            );
-    }
+    Ok(camera)
+}
 
-    info!("created new camera {}", camera.id);
 
-    Ok(Json(camera))
+/// Creates a new camera
+fn put_camera(
+    client: Data<Client>,
+    pool: Data<ConnectionPool>,
+    templates: Data<Tera>,
+    user: AuthenticatedUser,
+    body: Json<PutCameraBody>,
+) -> Result<Json<CameraWithHealth>>
+{
+    user.require(Role::Admin)?;
+
+    let camera = add_camera(
+        &client,
+        &pool,
+        &templates,
+        &body.name,
+        &body.address,
+        &body.pairing_code,
+    )?;
+
+    Ok(Json(camera.into()))
+}
+
+
+/// Lists camera hosts found on the local network which have not been added yet
+fn get_discovered_cameras(
+    pool: Data<ConnectionPool>,
+    user: AuthenticatedUser,
+) -> Result<Json<Vec<DiscoveredCamera>>>
+{
+    user.require(Role::Admin)?;
+
+    let conn = pool.get()?;
+    let addresses: Vec<String> = cameras::table.select(cameras::address)
+        .load(&conn)?;
+
+    let discovered = discovery::discovered()
+        .into_iter()
+        .filter(|camera| !addresses.contains(&camera.address))
+        .collect();
+
+    Ok(Json(discovered))
+}
+
+
+/// Camera representation required by PUT requests for discovered cameras
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PutDiscoveredCameraBody {
+
+    /// Name of the new camera, defaulting to the name advertised by its host
+    name: Option<String>,
+
+    /// Code logged by the camera host, used to pair with it
+    pairing_code: String,
+}
+
+
+/// Adopts a camera host found on the local network
+fn put_discovered_camera(
+    client: Data<Client>,
+    pool: Data<ConnectionPool>,
+    templates: Data<Tera>,
+    user: AuthenticatedUser,
+    path: web::Path<(String,)>,
+    body: Json<PutDiscoveredCameraBody>,
+) -> Result<Json<CameraWithHealth>>
+{
+    user.require(Role::Admin)?;
+
+    let discovered = match discovery::find(&path.0) {
+        Some(discovered) => discovered,
+        None => return Error::web(StatusCode::NOT_FOUND, "camera has not been discovered"),
+    };
+
+    let camera = add_camera(
+        &client,
+        &pool,
+        &templates,
+        body.name.as_ref().unwrap_or(&discovered.name),
+        &discovered.address,
+        &body.pairing_code,
+    )?;
+
+    Ok(Json(camera.into()))
 }
 
 
 /// Retrieves information about the specified camera
 fn get_camera(
     pool: Data<ConnectionPool>,
+    user: AuthenticatedUser,
     path: web::Path<(i32,)>,
-) -> Result<Json<Camera>>
+) -> Result<Json<CameraWithHealth>>
 {
     let id = path.0;
 
     debug!("retrieving camera {} from database", id);
     let conn = pool.get()?;
+    user.require_view(id, &conn)?;
     let camera: Camera = cameras::table.find(id)
         .get_result(&conn)?;
 
-    Ok(Json(camera))
+    Ok(Json(camera.into()))
 }
 
 
-/// Retrieves information about all cameras
+/// Retrieves information about all cameras, including their health
+///
+/// Viewers only see cameras to which they have been granted access.
 fn get_cameras(
     pool: Data<ConnectionPool>,
-) -> Result<Json<Vec<Camera>>>
+    user: AuthenticatedUser,
+) -> Result<Json<Vec<CameraWithHealth>>>
 {
     debug!("retrieving all cameras from database");
     let conn = pool.get()?;
-    let cameras = cameras::table.load(&conn)?;
+    let cameras = visible(&user, &conn)?;
 
     Ok(Json(cameras))
 }
 
 
+/// Retrieves diagnostic information about the specified camera's transcoder
+fn get_camera_diagnostics(
+    pool: Data<ConnectionPool>,
+    user: AuthenticatedUser,
+    client: Data<Client>,
+    #[cfg(feature = "stream")]
+    stream: Data<RwLock<Stream>>,
+    path: web::Path<(i32,)>,
+) -> Result<Json<HostDiagnostics>>
+{
+    let id = path.0;
+
+    // Transcoder output may reveal details of the host, so it is not shown to
+    // viewers
+    user.require(Role::Operator)?;
+
+    debug!("retrieving camera {} from database", id);
+    let conn = pool.get()?;
+    let camera: Camera = cameras::table.find(id)
+        .get_result(&conn)?;
+
+    #[cfg(feature = "stream")]
+    {
+        if camera.local {
+            debug!("retrieving local stream diagnostics");
+            return Ok(Json(do_read!(stream).diagnostics()));
+        }
+    }
+
+    debug!("retrieving stream diagnostics from {}", camera.address);
+    let diagnostics = camera.request(&client, Method::GET, "/api/stream/diagnostics")?
+        .send()?
+        .error_for_status()?
+        .json()?;
+
+    Ok(Json(diagnostics))
+}
+
+
+lazy_static! {
+    /// Recent snapshots of remote cameras, by camera ID
+    static ref SNAPSHOTS: Mutex<HashMap<i32, (Instant, Vec<u8>)>> = Mutex::new(HashMap::new());
+}
+
+
+/// Retrieves a JPEG image of the most recent keyframe of the specified camera
+fn get_camera_snapshot(
+    pool: Data<ConnectionPool>,
+    user: AuthenticatedUser,
+    client: Data<Client>,
+    #[cfg(feature = "stream")]
+    stream: Data<RwLock<Stream>>,
+    path: web::Path<(i32,)>,
+) -> Result<HttpResponse>
+{
+    let id = path.0;
+
+    debug!("retrieving camera {} from database", id);
+    let conn = pool.get()?;
+    user.require_view(id, &conn)?;
+    let camera: Camera = cameras::table.find(id)
+        .get_result(&conn)?;
+
+    // Local stream caches its own snapshots
+    #[cfg(feature = "stream")]
+    {
+        if camera.local {
+            let image = do_read!(stream).snapshot()?;
+            return Ok(stream::snapshot_response(image));
+        }
+    }
+
+    if let Some((taken, image)) = do_lock!(SNAPSHOTS).get(&id) {
+        if taken.elapsed() < stream::SNAPSHOT_MAX_AGE {
+            return Ok(stream::snapshot_response(image.clone()));
+        }
+    }
+
+    debug!("retrieving snapshot from {}", camera.address);
+    let mut image = Vec::new();
+    let response = camera.request(&client, Method::GET, "/api/stream/snapshot.jpg")?
+        .send()?;
+    if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+        return Error::web(StatusCode::SERVICE_UNAVAILABLE, "Stream is not running");
+    }
+    response.error_for_status()?
+        .copy_to(&mut image)?;
+
+    do_lock!(SNAPSHOTS).insert(id, (Instant::now(), image.clone()));
+
+    Ok(stream::snapshot_response(image))
+}
+
+
+/// Retrieves recent motion events for the specified camera
+fn get_camera_events(
+    pool: Data<ConnectionPool>,
+    user: AuthenticatedUser,
+    client: Data<Client>,
+    path: web::Path<(i32,)>,
+) -> Result<Json<Vec<MotionEvent>>>
+{
+    let id = path.0;
+
+    debug!("retrieving camera {} from database", id);
+    let conn = pool.get()?;
+    user.require_view(id, &conn)?;
+    let camera: Camera = cameras::table.find(id)
+        .get_result(&conn)?;
+
+    let events: Vec<MotionEvent> = if cfg!(feature = "stream") && camera.local {
+        debug!("retrieving local motion events");
+        motion::recent_events(&conn)?
+    } else {
+        debug!("retrieving motion events from {}", camera.address);
+        camera.request(&client, Method::GET, "/api/stream/events")?
+            .send()?
+            .error_for_status()?
+            .json()?
+    };
+
+    // Camera hosts do not know the ID their portal has given them
+    let events = events.into_iter()
+        .map(|event| MotionEvent { camera_id: Some(id), ..event })
+        .collect();
+
+    Ok(Json(events))
+}
+
+
+/// Restarts the specified camera's transcoder if it has failed
+fn post_camera_reset(
+    pool: Data<ConnectionPool>,
+    user: AuthenticatedUser,
+    client: Data<Client>,
+    #[cfg(feature = "stream")]
+    stream: Data<RwLock<Stream>>,
+    path: web::Path<(i32,)>,
+) -> Result<()>
+{
+    let id = path.0;
+
+    debug!("retrieving camera {} from database", id);
+    let conn = pool.get()?;
+    user.require(Role::Operator)?;
+    let camera: Camera = cameras::table.find(id)
+        .get_result(&conn)?;
+
+    #[cfg(feature = "stream")]
+    {
+        if camera.local {
+            debug!("resetting local stream");
+            do_write!(stream).reset()?;
+            info!("reset camera {}", id);
+            return Ok(());
+        }
+    }
+
+    debug!("resetting stream at {}", camera.address);
+    camera.request(&client, Method::POST, "/api/stream/reset")?
+        .send()?
+        .error_for_status()?;
+
+    info!("reset camera {}", id);
+
+    Ok(())
+}
+
+
+/// Retrieves recordings of the specified camera
+fn get_camera_recordings(
+    pool: Data<ConnectionPool>,
+    user: AuthenticatedUser,
+    client: Data<Client>,
+    path: web::Path<(i32,)>,
+) -> Result<Json<Vec<Recording>>>
+{
+    let id = path.0;
+
+    debug!("retrieving camera {} from database", id);
+    let conn = pool.get()?;
+    user.require_view(id, &conn)?;
+    let camera: Camera = cameras::table.find(id)
+        .get_result(&conn)?;
+
+    Ok(Json(recordings(&camera, &conn, &client)?))
+}
+
+
+/// Downloads a recording of the specified camera as an MPEG transport stream
+fn get_camera_recording_download(
+    pool: Data<ConnectionPool>,
+    user: AuthenticatedUser,
+    client: Data<Client>,
+    #[cfg(feature = "stream")]
+    stream: Data<RwLock<Stream>>,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse>
+{
+    let (id, recording_id) = *path;
+
+    debug!("retrieving camera {} from database", id);
+    let conn = pool.get()?;
+    user.require_view(id, &conn)?;
+    let camera: Camera = cameras::table.find(id)
+        .get_result(&conn)?;
+
+    #[cfg(feature = "stream")]
+    {
+        if camera.local {
+            debug!("exporting local recording {}", recording_id);
+            recording::find(recording_id, &conn)?;
+            let key = do_read!(stream).key;
+            let export = recording::export(recording_id, &key)?;
+            return Ok(recording::download_response(recording_id, export));
+        }
+    }
+
+    debug!("downloading recording {} from {}", recording_id, camera.address);
+    let path = format!("/api/stream/recordings/{}/download", recording_id);
+    let response = camera.request(&client, Method::GET, &path)?
+        .send()?
+        .error_for_status()?;
+
+    Ok(recording::download_response(recording_id, Relay::new(response)))
+}
+
+
+/// Deletes a recording of the specified camera
+fn delete_camera_recording(
+    pool: Data<ConnectionPool>,
+    user: AuthenticatedUser,
+    client: Data<Client>,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse>
+{
+    let (id, recording_id) = *path;
+
+    debug!("retrieving camera {} from database", id);
+    let conn = pool.get()?;
+    user.require(Role::Operator)?;
+    let camera: Camera = cameras::table.find(id)
+        .get_result(&conn)?;
+
+    if cfg!(feature = "stream") && camera.local {
+        recording::delete(recording_id, &conn)?;
+    } else {
+        debug!("deleting recording {} from {}", recording_id, camera.address);
+        let path = format!("/api/stream/recordings/{}", recording_id);
+        camera.request(&client, Method::DELETE, &path)?
+            .send()?
+            .error_for_status()?;
+    }
+
+    info!("deleted recording {} of camera {}", recording_id, id);
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+
 /// Camera representation required by PATCH requests
-#[derive(Deserialize)]
-struct PatchCameraBody {
-    name: Option<String>,
-    enabled: Option<bool>,
-    orientation: Option<Orientation>,
-    address: Option<String>,
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PatchCameraBody {
+    pub name: Option<String>,
+    pub enabled: Option<bool>,
+    pub orientation: Option<Orientation>,
+    pub address: Option<String>,
+    pub pairing_code: Option<String>,
 }
 
 
 /// Updates information about the specified camera
-#[allow(clippy::assertions_on_constants)]
-#[allow(clippy::cognitive_complexity)]
 fn patch_camera(
     pool: Data<ConnectionPool>,
+    user: AuthenticatedUser,
     client: Data<Client>,
     templates: Data<Tera>,
     #[cfg(feature = "stream")]
     stream: Data<RwLock<Stream>>,
     path: web::Path<(i32,)>,
     body: Json<PatchCameraBody>,
-) -> Result<Json<Camera>>
+) -> Result<Json<CameraWithHealth>>
 {
-    let id = path.0;
-    let body = body.into_inner();
+    // Connection settings decide where signed requests and the stream key are
+    // sent, so only admins may change them (like adding a camera)
+    if body.address.is_some() || body.pairing_code.is_some() {
+        user.require(Role::Admin)?;
+    } else {
+        user.require(Role::Operator)?;
+    }
+    let conn = pool.get()?;
+
+    let camera = update(
+        path.0,
+        body.into_inner(),
+        &conn,
+        &client,
+        &templates,
+        #[cfg(feature = "stream")]
+        &stream,
+    )?;
+
+    Ok(Json(camera.into()))
+}
+
 
+/// Updates information about the specified camera
+///
+/// Changes to the camera's stream are applied to the local stream or sent to
+/// the remote camera as appropriate.
+#[allow(clippy::assertions_on_constants)]
+pub fn update(
+    id: i32,
+    body: PatchCameraBody,
+    conn: &PooledConnection,
+    client: &Client,
+    templates: &Tera,
+    #[cfg(feature = "stream")]
+    stream: &RwLock<Stream>,
+) -> Result<Camera>
+{
     debug!("retrieving camera {} from database", id);
-    let conn = pool.get()?;
     let mut camera: Camera = cameras::table.find(id)
-        .get_result(&conn)?;
+        .get_result(conn)?;
 
     let mut do_connect = false;
     let mut do_update = false;
     let mut do_save = false;
-    let mut new_stream = StreamUpdate {
-        enabled: None,
-        orientation: None,
-    };
+    let mut new_stream = StreamUpdate::default();
 
     if let Some(name) = body.name {
         if camera.name != name {
@@ -216,12 +719,37 @@ fn patch_camera(
         }
     }
 
+    // A new pairing code is needed to re-pair with the camera, e.g. after it has
+    // been reset or moved to a different host
+    if let Some(code) = body.pairing_code {
+        if camera.local {
+            return Error::web(StatusCode::BAD_REQUEST, "cannot pair local camera");
+        }
+        let key = pairing::pair(client, &camera.address, &code)?;
+
+        // The camera host will not pair again while it holds this key, so save
+        // it straight away rather than risk losing it if anything below fails
+        debug!("saving pairing key of camera {}", id);
+        let saved = diesel::update(cameras::table.find(id))
+            .set((cameras::address.eq(&camera.address), cameras::pairing_key.eq(&key)))
+            .execute(conn);
+        if let Err(err) = saved {
+            pairing::unpair(client, &camera.address, &key)
+                .unwrap_or_else(|e| error!("failed to unpair from camera {}: {}", id, e));
+            return Err(err.into());
+        }
+
+        camera.pairing_key = Some(key);
+        do_connect = true;
+        do_save = true;
+    }
+
     // Validate new connection information before updating the database
-    let url = format!("http://{}/api/stream", camera.address);
     if do_connect {
         debug!("connecting to camera at {}", camera.address);
-        let current_stream: StreamState = client.get(&url)
+        let current_stream: StreamState = camera.request(client, Method::GET, "/api/stream")?
             .send()?
+            .error_for_status()?
             .json()?;
 
         // If successful, update the camera instance to reflect the settings of the connected
@@ -244,12 +772,12 @@ fn patch_camera(
             assert!(cfg!(feature = "stream"));
             debug!("updating local stream settings");
             #[cfg(feature = "stream")]
-            do_write!(stream).update(&new_stream, &conn, &templates)?;
+            do_write!(stream).update(&new_stream, conn, templates)?;
         } else {
             debug!("sending new stream settings to {}", camera.address);
-            client.patch(&url)
-                .json(&new_stream)
-                .send()?;
+            camera.request_json(client, Method::PATCH, "/api/stream", &new_stream)?
+                .send()?
+                .error_for_status()?;
         }
     }
 
@@ -257,9 +785,9 @@ fn patch_camera(
         debug!("saving changes to camera {}", id);
         diesel::update(&camera)
             .set(&camera)
-            .execute(&conn)?;
+            .execute(conn)?;
         if camera.enabled {
-            write_proxy_config(&camera, &client, &templates)
+            write_proxy_config(&camera, client, templates)
                 .unwrap_or_else(|e|
                     error!("failed to configure proxy for camera {}: {}", camera.id, e)
                 );
@@ -274,17 +802,20 @@ fn patch_camera(
     }
 
     info!("successfully updated camera {}", id);
-    Ok(Json(camera))
+    Ok(camera)
 }
 
 
 /// Deletes the specified camera
 fn delete_camera(
     pool: Data<ConnectionPool>,
+    user: AuthenticatedUser,
+    client: Data<Client>,
     path: web::Path<(i32,)>,
 ) -> Result<()>
 {
     let id = path.0;
+    user.require(Role::Admin)?;
     let conn = pool.get()?;
 
     debug!("retrieving camera {} from database", id);
@@ -297,9 +828,19 @@ fn delete_camera(
         );
     }
 
+    // Unpair so the camera host can be added to a portal again. The camera may no longer be
+    // reachable, which should not prevent it from being removed.
+    if let Some(ref key) = camera.pairing_key {
+        pairing::unpair(&client, &camera.address, key)
+            .unwrap_or_else(|e| warn!("failed to unpair from camera {}: {}", id, e));
+    }
+
     debug!("deleting camera {} from database", id);
+    schedules::clear(id, &conn)?;
+    users::clear_camera_permissions(id, &conn)?;
     diesel::delete(cameras::table.filter(cameras::id.eq(id)))
         .execute(&conn)?;
+    health::clear(id);
 
     clear_proxy_config(id)
         .and_then(|_| proxy::reload())
@@ -321,6 +862,19 @@ pub fn configure_api(service: &mut ServiceConfig) {
             .wrap(AuthenticationMiddleware::reject())
     );
 
+    // Must be registered before /cameras/{id}, which would otherwise match
+    service.service(
+        web::resource("/cameras/discovered")
+            .route(web::get().to(get_discovered_cameras))
+            .wrap(AuthenticationMiddleware::reject())
+    );
+
+    service.service(
+        web::resource("/cameras/discovered/{name}")
+            .route(web::put().to(put_discovered_camera))
+            .wrap(AuthenticationMiddleware::reject())
+    );
+
     service.service(
         web::resource("/cameras/{id}")
             .route(web::get().to(get_camera))
@@ -328,65 +882,133 @@ pub fn configure_api(service: &mut ServiceConfig) {
             .route(web::delete().to(delete_camera))
             .wrap(AuthenticationMiddleware::reject())
     );
-}
 
+    service.service(
+        web::resource("/cameras/{id}/diagnostics")
+            .route(web::get().to(get_camera_diagnostics))
+            .wrap(AuthenticationMiddleware::reject())
+    );
+
+    service.service(
+        web::resource("/cameras/{id}/snapshot.jpg")
+            .route(web::get().to(get_camera_snapshot))
+            .wrap(AuthenticationMiddleware::reject())
+    );
+
+    service.service(
+        web::resource("/cameras/{id}/events")
+            .route(web::get().to(get_camera_events))
+            .wrap(AuthenticationMiddleware::reject())
+    );
 
-/// Gets path of the proxy configuration file for the specified camera
-fn get_proxy_config_path(id: i32) -> Result<impl AsRef<Path>> {
+    service.service(
+        web::resource("/cameras/{id}/recordings")
+            .route(web::get().to(get_camera_recordings))
+            .wrap(AuthenticationMiddleware::reject())
+    );
 
-    let cfg_dir = proxy::config_dir()?;
-    let path = format!("{}/proxy-{}.conf", cfg_dir, id);
+    service.service(
+        web::resource("/cameras/{id}/recordings/{recording_id}")
+            .route(web::delete().to(delete_camera_recording))
+            .wrap(AuthenticationMiddleware::reject())
+    );
 
-    Ok(path)
+    service.service(
+        web::resource("/cameras/{id}/recordings/{recording_id}/download")
+            .route(web::get().to(get_camera_recording_download))
+            .wrap(AuthenticationMiddleware::reject())
+    );
+
+    service.service(
+        web::resource("/cameras/{id}/reset")
+            .route(web::post().to(post_camera_reset))
+            .wrap(AuthenticationMiddleware::reject())
+    );
+}
+
+
+lazy_static! {
+    /// Enabled cameras whose proxy configuration was skipped because they were
+    /// unreachable, by camera ID
+    static ref PENDING_PROXY_CONFIGS: Mutex<HashSet<i32>> = Mutex::new(HashSet::new());
 }
 
 
-/// Writes or removes the proxy configuration file for this camera
+/// Exposes this camera's stream through the proxy
 fn write_proxy_config(
     camera: &Camera,
     client: &Client,
     templates: &Tera
 ) -> Result<()> {
 
+    // Unpaired cameras cannot be reached until an admin re-pairs them, which
+    // writes their proxy configuration again
+    if !camera.is_paired() {
+        warn!("skipping proxy configuration until camera {} is re-paired", camera.id);
+        do_lock!(PENDING_PROXY_CONFIGS).insert(camera.id);
+        return Ok(());
+    }
+
     // Validate remote address before writing proxy config (otherwise, Nginx
     // will fail to start/reload, potentially making the web UI inaccessible)
     if !camera.local {
         debug!("validating connection to camera {}", camera.id);
-        let url = format!("http://{}/api/stream", camera.address);
-        if let Err(err) = client.get(&url).send() {
-            // Misconfigured camera should not bring down the whole system
+        let res = camera.request(client, Method::GET, "/api/stream")
+            .and_then(|request| Ok(request.send()?.error_for_status()?));
+        if let Err(err) = res {
+            // Misconfigured camera should not bring down the whole system. The
+            // health monitor tries again once the camera is reachable.
             error!("failed to connect to camera {}: {}", camera.id, err);
-            warn!("skipping proxy configuration");
+            warn!("skipping proxy configuration until camera {} is reachable", camera.id);
+            do_lock!(PENDING_PROXY_CONFIGS).insert(camera.id);
             return Ok(());
         }
     }
 
-    let mut context = Context::new();
-    context.insert("camera", camera);
-    let config = templates.render("proxy.conf", &context)?;
-
-    debug!("writing proxy configuration for camera {}", camera.id);
-    let config_path = get_proxy_config_path(camera.id)?;
-    fs::write(&config_path, config)?;
+    proxy::configure_camera(camera, templates)?;
+    do_lock!(PENDING_PROXY_CONFIGS).remove(&camera.id);
 
     Ok(())
 }
 
 
-/// Removes proxy configuration for the specified camera
-fn clear_proxy_config(id: i32) -> Result<()> {
+/// Returns whether proxy configuration of the specified camera was skipped
+/// because the camera was unreachable
+pub fn proxy_pending(id: i32) -> bool {
 
-    let config_path = get_proxy_config_path(id)?;
+    do_lock!(PENDING_PROXY_CONFIGS).contains(&id)
+}
+
+
+/// Configures the proxy for a camera which was previously unreachable
+///
+/// Does nothing if the camera's proxy configuration is not pending.
+pub fn retry_proxy_config(camera: &Camera, client: &Client, templates: &Tera) -> Result<()> {
+
+    if !camera.enabled || !camera.is_paired() || !proxy_pending(camera.id) {
+        return Ok(());
+    }
 
-    if fs::metadata(&config_path).is_ok() {
-        debug!("clearing proxy configuration for camera {}", id);
-        fs::remove_file(&config_path)?;
+    info!("retrying proxy configuration for camera {}", camera.id);
+    write_proxy_config(camera, client, templates)?;
+    if !proxy_pending(camera.id) {
+        proxy::reload()?;
     }
 
     Ok(())
 }
 
 
+/// Removes proxy configuration for the specified camera
+fn clear_proxy_config(id: i32) -> Result<()> {
+
+    do_lock!(PENDING_PROXY_CONFIGS).remove(&id);
+
+    debug!("clearing proxy configuration for camera {}", id);
+    proxy::clear_camera(id)
+}
+
+
 /// Initializes the `cameras` module
 ///
 /// Performs the following operations to make this module usable:
@@ -408,6 +1030,12 @@ pub fn initialize(
     let cameras: Vec<Camera> = cameras::table.load(conn)?;
 
     for camera in &cameras {
+        if !camera.is_paired() {
+            warn!(
+                "camera {} is not paired, enter the pairing code logged by its host to re-pair it",
+                camera.id,
+            );
+        }
         if camera.enabled {
             write_proxy_config(&camera, client, templates)
                 .unwrap_or_else(|e|
@@ -441,6 +1069,7 @@ pub fn initialize(
                 orientation: stream.orientation,
                 local: true,
                 key: &stream.key,
+                pairing_key: None,
             };
             diesel::insert_into(cameras::table)
                 .values(&local_cam)
@@ -455,9 +1084,95 @@ pub fn initialize(
 /// Retrieves serializable representation of all cameras
 pub fn all(conn: &PooledConnection) -> Result<impl Serialize> {
 
-    let users: Vec<Camera> = cameras::table.load(conn)?;
+    let cameras: Vec<Camera> = cameras::table.load(conn)?;
+    let cameras: Vec<CameraWithHealth> = cameras.into_iter()
+        .map(CameraWithHealth::from)
+        .collect();
+
+    Ok(cameras)
+}
+
+
+/// Retrieves all cameras which the given user may view
+pub fn visible(user: &AuthenticatedUser, conn: &PooledConnection) -> Result<Vec<CameraWithHealth>> {
+
+    let cameras: Vec<Camera> = cameras::table.load(conn)?;
+
+    let mut visible = Vec::with_capacity(cameras.len());
+    for camera in cameras {
+        if user.can_view(camera.id, conn)? {
+            visible.push(camera.into());
+        }
+    }
+
+    Ok(visible)
+}
+
+
+/// Retrieves recordings of the specified camera, newest first
+pub fn recordings(
+    camera: &Camera,
+    conn: &PooledConnection,
+    client: &Client,
+) -> Result<Vec<Recording>>
+{
+    if cfg!(feature = "stream") && camera.local {
+        debug!("retrieving local recordings");
+        return recording::list(conn);
+    }
+
+    debug!("retrieving recordings from {}", camera.address);
+    let recordings = camera.request(client, Method::GET, "/api/stream/recordings")?
+        .send()?
+        .error_for_status()?
+        .json()?;
+
+    Ok(recordings)
+}
+
+
+/// Retrieves the segments making up a recording of the specified camera
+pub fn recording_segments(
+    camera: &Camera,
+    recording_id: i32,
+    client: &Client,
+) -> Result<Vec<Segment>>
+{
+    if cfg!(feature = "stream") && camera.local {
+        return recording::read_segments(recording_id);
+    }
+
+    let path = format!("/api/stream/recordings/{}/segments", recording_id);
+    let segments = camera.request(client, Method::GET, &path)?
+        .send()?
+        .error_for_status()?
+        .json()?;
+
+    Ok(segments)
+}
+
+
+/// Retrieves a single (encrypted) segment of a recording of the specified
+/// camera
+pub fn recording_segment(
+    camera: &Camera,
+    recording_id: i32,
+    file: &str,
+    client: &Client,
+) -> Result<Vec<u8>>
+{
+    if cfg!(feature = "stream") && camera.local {
+        return recording::read_segment(recording_id, file);
+    }
+
+    let path = format!("/api/stream/recordings/{}/segments/{}", recording_id, file);
+    let mut data = Vec::new();
+    camera.request(client, Method::GET, &path)?
+        .send()?
+        .error_for_status()?
+        .copy_to(&mut data)?;
 
-    Ok(users)
+    Ok(data)
 }
 
 
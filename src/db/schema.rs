@@ -7,6 +7,77 @@ table! {
         orientation -> Integer,
         local -> Bool,
         key -> Binary,
+        pairing_key -> Nullable<Binary>,
+    }
+}
+
+table! {
+    api_tokens (id) {
+        id -> Integer,
+        user_id -> Integer,
+        name -> Text,
+        token_hash -> Text,
+        scope -> Integer,
+        created_at -> Timestamp,
+        expires_at -> Nullable<Timestamp>,
+        last_used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    auth_events (id) {
+        id -> Integer,
+        occurred_at -> Timestamp,
+        kind -> Integer,
+        username -> Text,
+        actor -> Nullable<Text>,
+        remote_addr -> Nullable<Text>,
+    }
+}
+
+table! {
+    camera_permissions (user_id, camera_id) {
+        user_id -> Integer,
+        camera_id -> Integer,
+    }
+}
+
+table! {
+    camera_schedules (id) {
+        id -> Integer,
+        camera_id -> Integer,
+        weekday -> Integer,
+        start_time -> Time,
+        end_time -> Time,
+    }
+}
+
+table! {
+    motion_events (id) {
+        id -> Integer,
+        started_at -> Timestamp,
+        ended_at -> Nullable<Timestamp>,
+        peak_score -> Float,
+        camera_id -> Nullable<Integer>,
+    }
+}
+
+table! {
+    recordings (id) {
+        id -> Integer,
+        started_at -> Timestamp,
+        ended_at -> Nullable<Timestamp>,
+        size_bytes -> BigInt,
+    }
+}
+
+table! {
+    schedule_overrides (id) {
+        id -> Integer,
+        camera_id -> Integer,
+        enabled -> Bool,
+        starts_at -> Timestamp,
+        ends_at -> Timestamp,
     }
 }
 
@@ -15,6 +86,8 @@ table! {
         id -> Integer,
         key -> Text,
         user_id -> Integer,
+        created_at -> Timestamp,
+        last_seen_at -> Timestamp,
     }
 }
 
@@ -25,19 +98,54 @@ table! {
     }
 }
 
+table! {
+    totp_credentials (user_id) {
+        user_id -> Integer,
+        secret -> Binary,
+        enabled -> Bool,
+        last_step -> Nullable<BigInt>,
+    }
+}
+
+table! {
+    totp_recovery_codes (id) {
+        id -> Integer,
+        user_id -> Integer,
+        code_hash -> Text,
+    }
+}
+
 table! {
     users (id) {
         id -> Integer,
         username -> Text,
         pwhash -> Text,
+        role -> Integer,
+        must_change_password -> Bool,
     }
 }
 
+joinable!(api_tokens -> users (user_id));
+joinable!(camera_permissions -> cameras (camera_id));
+joinable!(camera_permissions -> users (user_id));
+joinable!(camera_schedules -> cameras (camera_id));
+joinable!(schedule_overrides -> cameras (camera_id));
 joinable!(sessions -> users (user_id));
+joinable!(totp_credentials -> users (user_id));
+joinable!(totp_recovery_codes -> users (user_id));
 
 allow_tables_to_appear_in_same_query!(
+    api_tokens,
+    auth_events,
+    camera_permissions,
+    camera_schedules,
     cameras,
+    motion_events,
+    recordings,
+    schedule_overrides,
     sessions,
     settings,
+    totp_credentials,
+    totp_recovery_codes,
     users,
 );
@@ -0,0 +1,1180 @@
+//! Discovery of camera hosts on the local network
+//!
+//! Camera hosts built with the *stream-api* feature advertise themselves using
+//! multicast DNS service discovery (RFC 6762 and RFC 6763) as instances of
+//! *_lunacam._tcp.local*, with a description of their stream in TXT records.
+//! The portal periodically browses for these instances, so that cameras can be
+//! added without typing in their address.
+//!
+//! Only the small subset of mDNS needed by LunaCam is implemented. The portal
+//! sends its queries from an ephemeral port, so camera hosts answer it directly
+//! ("legacy unicast" in RFC 6762) rather than over multicast.
+//!
+//! The following environment variables control discovery:
+//!
+//! * LC_DISCOVERY_INTERFACE: IPv4 address of the interface used for multicast.
+//!   Defaults to the system's choice. Set this to 127.0.0.1 to test discovery
+//!   between several instances of lcsvc on one machine (the loopback interface
+//!   must have multicast enabled).
+//! * LC_DISCOVERY_NAME: name advertised by a camera host. Defaults to the
+//!   hostname.
+//! * LC_DISCOVERY_PORT: port on which a camera host's stream API is reachable.
+//!   Defaults to 80, where nginx forwards requests to lcsvc.
+
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::{Mutex, RwLock};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use actix_web::web::Data;
+use chrono::{NaiveDateTime, Utc};
+use lazy_static::lazy_static;
+use log::{debug, error, info, trace, warn};
+use serde::Serialize;
+
+use crate::db::ConnectionPool;
+use crate::do_lock;
+use crate::do_read;
+use crate::error::{Error, Result};
+use crate::pairing;
+use crate::stream::Stream;
+
+
+const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+const SERVICE_NAME: &str = "_lunacam._tcp.local";
+
+/// TTL of advertised records
+const RECORD_TTL: u32 = 120;
+
+/// RFC 6762 limits the TTL of records in answers to legacy unicast queries
+const LEGACY_UNICAST_TTL: u32 = 10;
+
+const DEFAULT_PORT: u16 = 80;
+
+
+fn interface() -> Ipv4Addr {
+
+    env::var("LC_DISCOVERY_INTERFACE")
+        .ok()
+        .and_then(|addr| addr.parse().ok())
+        .unwrap_or(Ipv4Addr::UNSPECIFIED)
+}
+
+
+//#region DNS messages
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+
+/// Set in the class of unique records sent over multicast, telling receivers to
+/// replace any cached records of the same name and type
+const CACHE_FLUSH: u16 = 0x8000;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+
+const MAX_MESSAGE_SIZE: usize = 9000;
+
+
+#[derive(Debug, PartialEq)]
+enum RecordData {
+    A(Ipv4Addr),
+    Ptr(String),
+    Srv { port: u16, target: String },
+    Txt(Vec<String>),
+    Other,
+}
+
+#[derive(Debug, PartialEq)]
+struct Record {
+    name: String,
+    ttl: u32,
+    data: RecordData,
+}
+
+impl Record {
+
+    fn rtype(&self) -> u16 {
+        match self.data {
+            RecordData::A(_) => TYPE_A,
+            RecordData::Ptr(_) => TYPE_PTR,
+            RecordData::Srv { .. } => TYPE_SRV,
+            RecordData::Txt(_) => TYPE_TXT,
+            RecordData::Other => 0,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Question {
+    name: String,
+    qtype: u16,
+}
+
+#[derive(Debug, PartialEq)]
+struct Message {
+    id: u16,
+    is_response: bool,
+    questions: Vec<Question>,
+    records: Vec<Record>,
+}
+
+
+fn write_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn write_name(buf: &mut Vec<u8>, name: &str) {
+
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label);
+    }
+    buf.push(0);
+}
+
+fn write_question(buf: &mut Vec<u8>, question: &Question) {
+
+    write_name(buf, &question.name);
+    write_u16(buf, question.qtype);
+    write_u16(buf, CLASS_IN);
+}
+
+fn write_record(buf: &mut Vec<u8>, record: &Record, flush: bool) {
+
+    write_name(buf, &record.name);
+    write_u16(buf, record.rtype());
+
+    // PTR records are shared between all instances of the service
+    let flush = flush && record.rtype() != TYPE_PTR;
+    write_u16(buf, if flush { CLASS_IN | CACHE_FLUSH } else { CLASS_IN });
+    buf.extend_from_slice(&record.ttl.to_be_bytes());
+
+    // Length is filled in once the data has been written
+    let len_pos = buf.len();
+    write_u16(buf, 0);
+
+    match record.data {
+        RecordData::A(addr) => buf.extend_from_slice(&addr.octets()),
+        RecordData::Ptr(ref name) => write_name(buf, name),
+        RecordData::Srv { port, ref target } => {
+            write_u16(buf, 0); // priority
+            write_u16(buf, 0); // weight
+            write_u16(buf, port);
+            write_name(buf, target);
+        },
+        RecordData::Txt(ref strings) => {
+            for s in strings {
+                let s = &s.as_bytes()[..s.len().min(255)];
+                buf.push(s.len() as u8);
+                buf.extend_from_slice(s);
+            }
+        },
+        RecordData::Other => (),
+    }
+
+    let len = (buf.len() - len_pos - 2) as u16;
+    buf[len_pos..len_pos + 2].copy_from_slice(&len.to_be_bytes());
+}
+
+fn write_message(message: &Message, flush: bool) -> Vec<u8> {
+
+    let mut buf = Vec::new();
+
+    write_u16(&mut buf, message.id);
+    write_u16(&mut buf, if message.is_response { FLAG_RESPONSE | FLAG_AUTHORITATIVE } else { 0 });
+    write_u16(&mut buf, message.questions.len() as u16);
+    write_u16(&mut buf, message.records.len() as u16);
+    write_u16(&mut buf, 0);
+    write_u16(&mut buf, 0);
+
+    for question in &message.questions {
+        write_question(&mut buf, question);
+    }
+    for record in &message.records {
+        write_record(&mut buf, record, flush);
+    }
+
+    buf
+}
+
+
+fn read_u16(packet: &[u8], pos: &mut usize) -> Option<u16> {
+
+    let bytes = packet.get(*pos..*pos + 2)?;
+    *pos += 2;
+
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(packet: &[u8], pos: &mut usize) -> Option<u32> {
+
+    let bytes = packet.get(*pos..*pos + 4)?;
+    *pos += 4;
+
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Reads a possibly compressed name
+fn read_name(packet: &[u8], pos: &mut usize) -> Option<String> {
+
+    let mut labels = Vec::new();
+    let mut cursor = *pos;
+    let mut jumped = false;
+
+    // Bound the number of compression pointers followed, so that a malicious
+    // message cannot send us around in circles
+    for _ in 0..128 {
+        let len = *packet.get(cursor)? as usize;
+
+        if len & 0xc0 == 0xc0 {
+            let offset = (read_u16(packet, &mut cursor)? & 0x3fff) as usize;
+            if !jumped {
+                *pos = cursor;
+                jumped = true;
+            }
+            cursor = offset;
+            continue;
+        }
+
+        cursor += 1;
+        if len == 0 {
+            if !jumped {
+                *pos = cursor;
+            }
+            return Some(labels.join("."));
+        }
+
+        let label = packet.get(cursor..cursor + len)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        cursor += len;
+    }
+
+    None
+}
+
+fn read_record(packet: &[u8], pos: &mut usize) -> Option<Record> {
+
+    let name = read_name(packet, pos)?;
+    let rtype = read_u16(packet, pos)?;
+    let _class = read_u16(packet, pos)?;
+    let ttl = read_u32(packet, pos)?;
+    let len = read_u16(packet, pos)? as usize;
+
+    let start = *pos;
+    let end = start + len;
+    let rdata = packet.get(start..end)?;
+    *pos = end;
+
+    let mut cursor = start;
+    let data = match rtype {
+        TYPE_A if len == 4 => RecordData::A(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3])),
+        TYPE_PTR => RecordData::Ptr(read_name(packet, &mut cursor)?),
+        TYPE_SRV => {
+            cursor += 4; // priority and weight
+            let port = read_u16(packet, &mut cursor)?;
+            let target = read_name(packet, &mut cursor)?;
+            RecordData::Srv { port, target }
+        },
+        TYPE_TXT => {
+            let mut strings = Vec::new();
+            let mut rest = rdata;
+            while let Some((&len, tail)) = rest.split_first() {
+                let s = tail.get(..len as usize)?;
+                strings.push(String::from_utf8_lossy(s).into_owned());
+                rest = &tail[len as usize..];
+            }
+            RecordData::Txt(strings)
+        },
+        _ => RecordData::Other,
+    };
+
+    Some(Record { name, ttl, data })
+}
+
+fn read_message(packet: &[u8]) -> Option<Message> {
+
+    let mut pos = 0;
+    let id = read_u16(packet, &mut pos)?;
+    let flags = read_u16(packet, &mut pos)?;
+    let question_count = read_u16(packet, &mut pos)?;
+    let record_count = read_u16(packet, &mut pos)? as usize
+        + read_u16(packet, &mut pos)? as usize
+        + read_u16(packet, &mut pos)? as usize;
+
+    let mut questions = Vec::new();
+    for _ in 0..question_count {
+        let name = read_name(packet, &mut pos)?;
+        let qtype = read_u16(packet, &mut pos)?;
+        let _class = read_u16(packet, &mut pos)?;
+        questions.push(Question { name, qtype });
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..record_count {
+        records.push(read_record(packet, &mut pos)?);
+    }
+
+    Some(Message {
+        id,
+        is_response: flags & FLAG_RESPONSE != 0,
+        questions,
+        records,
+    })
+}
+
+//#endregion
+
+
+//#region Sockets
+
+/// Sets a socket option which std does not expose
+fn set_socket_option<T>(socket: &impl AsRawFd, level: libc::c_int, name: libc::c_int, value: &T) -> io::Result<()> {
+
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Sends multicast traffic from the given socket over the chosen interface
+fn set_multicast_interface(socket: &UdpSocket, interface: Ipv4Addr) -> io::Result<()> {
+
+    if interface.is_unspecified() {
+        return Ok(());
+    }
+
+    let addr = libc::in_addr { s_addr: u32::from(interface).to_be() };
+    set_socket_option(socket, libc::IPPROTO_IP, libc::IP_MULTICAST_IF, &addr)
+}
+
+/// Binds a socket to the mDNS port, shared with any other mDNS responders on
+/// this machine (including other instances of lcsvc)
+fn bind_mdns_socket(interface: Ipv4Addr) -> Result<UdpSocket> {
+
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // Take ownership right away, so that the descriptor is closed on error
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+
+    // The port must be shared before binding, which std does not support
+    let on: libc::c_int = 1;
+    set_socket_option(&socket, libc::SOL_SOCKET, libc::SO_REUSEADDR, &on)?;
+    set_socket_option(&socket, libc::SOL_SOCKET, libc::SO_REUSEPORT, &on)?;
+
+    let addr = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: MDNS_PORT.to_be(),
+        sin_addr: libc::in_addr { s_addr: u32::from(Ipv4Addr::UNSPECIFIED).to_be() },
+        sin_zero: [0; 8],
+    };
+    let res = unsafe {
+        libc::bind(
+            fd,
+            &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    socket.join_multicast_v4(&MDNS_ADDR, &interface)?;
+    socket.set_multicast_loop_v4(true)?;
+    set_multicast_interface(&socket, interface)?;
+
+    Ok(socket)
+}
+
+/// Determines the address of the local interface used to reach `peer`
+fn local_addr_for(peer: IpAddr) -> Option<Ipv4Addr> {
+
+    // Connecting a UDP socket sends nothing, but makes the OS choose a route
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect((peer, MDNS_PORT)).ok()?;
+
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V4(addr) => Some(addr),
+        IpAddr::V6(_) => None,
+    }
+}
+
+//#endregion
+
+
+//#region Advertising
+
+/// Gets this machine's hostname
+fn hostname() -> String {
+
+    let mut buf = [0u8; 256];
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if res != 0 {
+        return "lunacam".into();
+    }
+
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// Serializes a unit enum variant the same way as the API does
+fn variant_name<T: Serialize>(value: &T) -> String {
+
+    serde_json::to_value(value)
+        .ok()
+        .and_then(|value| value.as_str().map(String::from))
+        .unwrap_or_default()
+}
+
+
+/// Capabilities of a camera host's stream, published as TXT records
+fn stream_properties(pool: &ConnectionPool, stream: &RwLock<Stream>) -> Vec<String> {
+
+    let state = do_read!(stream).state();
+    let paired = pool.get()
+        .map_err(Error::from)
+        .and_then(|conn| pairing::is_paired(&conn))
+        .unwrap_or_else(|err| {
+            error!("failed to check pairing status: {}", err);
+            false
+        });
+
+    vec![
+        "txtvers=1".into(),
+        format!("version={}", env!("CARGO_PKG_VERSION")),
+        format!("paired={}", paired),
+        format!("enabled={}", state.enabled),
+        format!("orientation={}", variant_name(&state.orientation)),
+        format!("backend={}", variant_name(&state.transcoder.backend)),
+        format!("resolution={}x{}", state.transcoder.width, state.transcoder.height),
+        format!("framerate={}", state.transcoder.framerate),
+    ]
+}
+
+
+/// Produces the TXT properties of an advertisement each time it is sent
+type PropertySource = Box<dyn Fn() -> Vec<String> + Send>;
+
+
+/// Describes this camera host to the portal
+struct Advertisement {
+    interface: Ipv4Addr,
+
+    /// Full name of the advertised service instance
+    instance: String,
+    host: String,
+    port: u16,
+    properties: PropertySource,
+}
+
+impl Advertisement {
+
+    fn new(name: &str, hostname: &str, port: u16, interface: Ipv4Addr, properties: PropertySource) -> Self {
+
+        // Dots would be taken as label separators
+        let name = name.replace('.', "-");
+
+        Self {
+            interface,
+            instance: format!("{}.{}", name, SERVICE_NAME),
+            host: format!("{}.local", hostname),
+            port,
+            properties,
+        }
+    }
+
+    /// Describes this camera host's stream, as configured by the environment
+    fn from_env(pool: Data<ConnectionPool>, stream: Data<RwLock<Stream>>) -> Self {
+
+        let hostname = hostname();
+        let hostname = hostname.split('.').next().unwrap_or("lunacam");
+
+        let name = env::var("LC_DISCOVERY_NAME")
+            .unwrap_or_else(|_| hostname.to_owned());
+        let port = env::var("LC_DISCOVERY_PORT")
+            .ok()
+            .and_then(|port| port.parse().ok())
+            .unwrap_or(DEFAULT_PORT);
+        let properties = Box::new(move || stream_properties(&pool, &stream));
+
+        Self::new(&name, hostname, port, interface(), properties)
+    }
+
+    fn records(&self, addr: Option<Ipv4Addr>, ttl: u32) -> Vec<Record> {
+
+        let mut records = vec![
+            Record {
+                name: SERVICE_NAME.into(),
+                ttl,
+                data: RecordData::Ptr(self.instance.clone()),
+            },
+            Record {
+                name: self.instance.clone(),
+                ttl,
+                data: RecordData::Srv { port: self.port, target: self.host.clone() },
+            },
+            Record {
+                name: self.instance.clone(),
+                ttl,
+                data: RecordData::Txt((self.properties)()),
+            },
+        ];
+
+        if let Some(addr) = addr {
+            records.push(Record {
+                name: self.host.clone(),
+                ttl,
+                data: RecordData::A(addr),
+            });
+        }
+
+        records
+    }
+
+    /// Address at which `peer` can reach this host
+    fn addr_for(&self, peer: IpAddr) -> Option<Ipv4Addr> {
+
+        if self.interface.is_unspecified() {
+            local_addr_for(peer)
+        } else {
+            Some(self.interface)
+        }
+    }
+
+    /// Returns whether a query asks about this service instance
+    fn matches(&self, question: &Question) -> bool {
+
+        let is_service = question.name.eq_ignore_ascii_case(SERVICE_NAME)
+            && (question.qtype == TYPE_PTR || question.qtype == TYPE_ANY);
+        let is_instance = question.name.eq_ignore_ascii_case(&self.instance);
+
+        is_service || is_instance
+    }
+
+    /// Sends records to the whole network, e.g. on startup or (with a TTL of
+    /// zero) on shutdown
+    fn announce(&self, socket: &UdpSocket, ttl: u32) -> Result<()> {
+
+        let message = Message {
+            id: 0,
+            is_response: true,
+            questions: Vec::new(),
+            records: self.records(self.addr_for(IpAddr::V4(MDNS_ADDR)), ttl),
+        };
+        socket.send_to(&write_message(&message, true), (MDNS_ADDR, MDNS_PORT))?;
+
+        Ok(())
+    }
+
+    fn respond(&self, socket: &UdpSocket, query: Message, src: SocketAddr) -> Result<()> {
+
+        if query.is_response || !query.questions.iter().any(|q| self.matches(q)) {
+            return Ok(());
+        }
+
+        trace!("answering discovery query from {}", src);
+        let addr = self.addr_for(src.ip());
+
+        // Queries from a port other than 5353 come from simple resolvers (like
+        // the portal) which only listen for a direct reply
+        if src.port() != MDNS_PORT {
+            let message = Message {
+                id: query.id,
+                is_response: true,
+                questions: query.questions,
+                records: self.records(addr, LEGACY_UNICAST_TTL),
+            };
+            socket.send_to(&write_message(&message, false), src)?;
+        } else {
+            let message = Message {
+                id: 0,
+                is_response: true,
+                questions: Vec::new(),
+                records: self.records(addr, RECORD_TTL),
+            };
+            socket.send_to(&write_message(&message, true), (MDNS_ADDR, MDNS_PORT))?;
+        }
+
+        Ok(())
+    }
+}
+
+
+/// Answers discovery queries until a message is sent over (or the sender of)
+/// `cancel` is dropped
+fn advertise(advert: Advertisement, socket: UdpSocket, cancel: &Receiver<()>) {
+
+    if let Err(err) = advert.announce(&socket, RECORD_TTL) {
+        warn!("failed to announce camera: {}", err);
+    }
+
+    let mut buf = vec![0; MAX_MESSAGE_SIZE];
+    while cancel.try_recv() == Err(TryRecvError::Empty) {
+
+        let (len, src) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut =>
+                continue,
+            Err(err) => {
+                error!("failed to receive discovery query: {}", err);
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            },
+        };
+
+        if let Some(query) = read_message(&buf[..len]) {
+            if let Err(err) = advert.respond(&socket, query, src) {
+                warn!("failed to answer discovery query from {}: {}", src, err);
+            }
+        }
+    }
+
+    // Let the portal know right away that this camera has gone
+    if let Err(err) = advert.announce(&socket, 0) {
+        warn!("failed to withdraw camera announcement: {}", err);
+    }
+
+    debug!("discovery advertiser exiting");
+}
+
+
+/// How often background threads check whether they should exit
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+
+/// Handle to the background task which advertises this camera host
+pub struct Advertiser {
+    cancel: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Advertiser {
+
+    /// Starts advertising this camera host on the local network
+    pub fn start(pool: Data<ConnectionPool>, stream: Data<RwLock<Stream>>) -> Result<Self> {
+
+        Self::spawn(Advertisement::from_env(pool, stream))
+    }
+
+    /// Starts answering queries with the given advertisement
+    fn spawn(advert: Advertisement) -> Result<Self> {
+
+        let socket = bind_mdns_socket(advert.interface)?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+
+        info!("advertising camera as {}", advert.instance);
+        let (cancel, cancel_rx) = mpsc::channel();
+        let thread = thread::spawn(move || advertise(advert, socket, &cancel_rx));
+
+        Ok(Self {
+            cancel: Some(cancel),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Advertiser {
+    fn drop(&mut self) {
+
+        // Send only fails if the thread has already exited
+        if let Some(cancel) = self.cancel.take() {
+            let _ = cancel.send(());
+        }
+
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("discovery advertiser thread panicked");
+            }
+        }
+    }
+}
+
+//#endregion
+
+
+//#region Browsing
+
+/// How often the portal looks for camera hosts
+const BROWSE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long the portal waits for answers to each query
+const BROWSE_WAIT: Duration = Duration::from_secs(2);
+
+/// Camera hosts are forgotten if they have not answered for this long
+const DISCOVERY_EXPIRY: Duration = Duration::from_secs(3 * 30);
+
+
+/// Camera host found on the local network
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiscoveredCamera {
+
+    /// Name advertised by the camera host
+    pub name: String,
+
+    /// Address at which the camera host's stream API can be reached
+    pub address: String,
+
+    /// Description of the camera host's stream, from its TXT records
+    pub properties: BTreeMap<String, String>,
+
+    pub last_seen: NaiveDateTime,
+
+    #[serde(skip)]
+    seen_at: Instant,
+}
+
+
+lazy_static! {
+    /// Discovered camera hosts, keyed by name
+    static ref DISCOVERED: Mutex<HashMap<String, DiscoveredCamera>> = Mutex::new(HashMap::new());
+}
+
+
+/// Makes sense of the answers from one camera host
+fn parse_answer(message: &Message, src: SocketAddr) -> Vec<DiscoveredCamera> {
+
+    let service_suffix = format!(".{}", SERVICE_NAME);
+    let find = |name: &str, rtype: u16| message.records.iter()
+        .find(move |r| r.rtype() == rtype && r.name.eq_ignore_ascii_case(name));
+
+    let instances = message.records.iter()
+        .filter(|r| r.name.eq_ignore_ascii_case(SERVICE_NAME))
+        .filter_map(|r| match r.data {
+            RecordData::Ptr(ref instance) if r.ttl > 0 => Some(instance),
+            _ => None,
+        });
+
+    let mut cameras = Vec::new();
+    for instance in instances {
+        let instance = instance.as_str();
+
+        let (port, target) = match find(instance, TYPE_SRV).map(|r| &r.data) {
+            Some(RecordData::Srv { port, target }) => (*port, target),
+            _ => continue,
+        };
+
+        // Fall back to the address the answer came from
+        let ip = match find(target.as_str(), TYPE_A).map(|r| &r.data) {
+            Some(RecordData::A(addr)) => IpAddr::V4(*addr),
+            _ => src.ip(),
+        };
+        let address = if port == DEFAULT_PORT {
+            ip.to_string()
+        } else {
+            SocketAddr::new(ip, port).to_string()
+        };
+
+        let mut properties = BTreeMap::new();
+        if let Some(RecordData::Txt(strings)) = find(instance, TYPE_TXT).map(|r| &r.data) {
+            for s in strings {
+                let mut kv = s.splitn(2, '=');
+                if let Some(key) = kv.next() {
+                    properties.insert(key.to_owned(), kv.next().unwrap_or("").to_owned());
+                }
+            }
+        }
+
+        let name = instance.get(..instance.len().saturating_sub(service_suffix.len()))
+            .filter(|_| instance.to_lowercase().ends_with(&service_suffix))
+            .unwrap_or(instance)
+            .to_owned();
+
+        cameras.push(DiscoveredCamera {
+            name,
+            address,
+            properties,
+            last_seen: Utc::now().naive_utc(),
+            seen_at: Instant::now(),
+        });
+    }
+
+    cameras
+}
+
+
+/// Queries the local network for camera hosts
+fn browse_once(interface: Ipv4Addr) -> Result<Vec<DiscoveredCamera>> {
+
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_multicast_loop_v4(true)?;
+    set_multicast_interface(&socket, interface)?;
+
+    let id = rand::random();
+    let query = Message {
+        id,
+        is_response: false,
+        questions: vec![Question { name: SERVICE_NAME.into(), qtype: TYPE_PTR }],
+        records: Vec::new(),
+    };
+    socket.send_to(&write_message(&query, false), (MDNS_ADDR, MDNS_PORT))?;
+
+    let mut cameras = Vec::new();
+    let mut buf = vec![0; MAX_MESSAGE_SIZE];
+    let deadline = Instant::now() + BROWSE_WAIT;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+
+        let (len, src) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut =>
+                break,
+            Err(err) => return Err(err.into()),
+        };
+
+        match read_message(&buf[..len]) {
+            Some(ref message) if message.is_response && message.id == id =>
+                cameras.extend(parse_answer(message, src)),
+            _ => trace!("ignoring unexpected discovery message from {}", src),
+        }
+    }
+
+    Ok(cameras)
+}
+
+
+/// Repeatedly browses for camera hosts until a message is sent over (or the
+/// sender of) `cancel` is dropped
+fn browse(cancel: &Receiver<()>) {
+
+    let interface = interface();
+
+    loop {
+        match browse_once(interface) {
+            Ok(cameras) => {
+                let mut discovered = do_lock!(DISCOVERED);
+                for camera in cameras {
+                    if !discovered.contains_key(&camera.name) {
+                        info!("discovered camera {} at {}", camera.name, camera.address);
+                    }
+                    discovered.insert(camera.name.clone(), camera);
+                }
+                discovered.retain(|_, camera| camera.seen_at.elapsed() < DISCOVERY_EXPIRY);
+            },
+            Err(err) => warn!("failed to browse for cameras: {}", err),
+        }
+
+        if cancel.recv_timeout(BROWSE_INTERVAL) != Err(mpsc::RecvTimeoutError::Timeout) {
+            break;
+        }
+    }
+
+    debug!("discovery browser exiting");
+}
+
+
+/// Handle to the background task which looks for camera hosts
+pub struct Browser {
+    cancel: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Browser {
+
+    /// Starts looking for camera hosts on the local network
+    pub fn start() -> Self {
+
+        debug!("starting discovery browser");
+        let (cancel, cancel_rx) = mpsc::channel();
+        let thread = thread::spawn(move || browse(&cancel_rx));
+
+        Self {
+            cancel: Some(cancel),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Browser {
+    fn drop(&mut self) {
+
+        // Send only fails if the thread has already exited
+        if let Some(cancel) = self.cancel.take() {
+            let _ = cancel.send(());
+        }
+
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("discovery browser thread panicked");
+            }
+        }
+    }
+}
+
+
+/// Retrieves the camera hosts which have recently answered, sorted by name
+pub fn discovered() -> Vec<DiscoveredCamera> {
+
+    let mut cameras: Vec<_> = do_lock!(DISCOVERED)
+        .values()
+        .filter(|camera| camera.seen_at.elapsed() < DISCOVERY_EXPIRY)
+        .cloned()
+        .collect();
+    cameras.sort_by(|a, b| a.name.cmp(&b.name));
+
+    cameras
+}
+
+
+/// Retrieves a discovered camera host by name
+pub fn find(name: &str) -> Option<DiscoveredCamera> {
+
+    discovered()
+        .into_iter()
+        .find(|camera| camera.name == name)
+}
+
+//#endregion
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+
+    fn sample_message() -> Message {
+        Message {
+            id: 0x1234,
+            is_response: true,
+            questions: vec![
+                Question { name: SERVICE_NAME.into(), qtype: TYPE_PTR },
+            ],
+            records: vec![
+                Record {
+                    name: SERVICE_NAME.into(),
+                    ttl: RECORD_TTL,
+                    data: RecordData::Ptr(format!("front-door.{}", SERVICE_NAME)),
+                },
+                Record {
+                    name: format!("front-door.{}", SERVICE_NAME),
+                    ttl: RECORD_TTL,
+                    data: RecordData::Srv { port: 8080, target: "pi.local".into() },
+                },
+                Record {
+                    name: format!("front-door.{}", SERVICE_NAME),
+                    ttl: LEGACY_UNICAST_TTL,
+                    data: RecordData::Txt(vec!["txtvers=1".into(), "paired=false".into(), "".into()]),
+                },
+                Record {
+                    name: "pi.local".into(),
+                    ttl: 0,
+                    data: RecordData::A(Ipv4Addr::new(192, 168, 1, 20)),
+                },
+            ],
+        }
+    }
+
+    /// Builds a message header announcing the given number of questions and
+    /// answers
+    fn header(questions: u16, answers: u16) -> Vec<u8> {
+
+        let mut buf = Vec::new();
+        write_u16(&mut buf, 0);
+        write_u16(&mut buf, FLAG_RESPONSE);
+        write_u16(&mut buf, questions);
+        write_u16(&mut buf, answers);
+        write_u16(&mut buf, 0);
+        write_u16(&mut buf, 0);
+
+        buf
+    }
+
+    #[test]
+    fn messages_round_trip() {
+
+        let message = sample_message();
+
+        for &flush in &[false, true] {
+            let packet = write_message(&message, flush);
+            assert_eq!(read_message(&packet), Some(sample_message()));
+        }
+    }
+
+    #[test]
+    fn queries_round_trip() {
+
+        let query = Message {
+            id: 7,
+            is_response: false,
+            questions: vec![
+                Question { name: SERVICE_NAME.into(), qtype: TYPE_PTR },
+                Question { name: format!("back-yard.{}", SERVICE_NAME), qtype: TYPE_ANY },
+            ],
+            records: Vec::new(),
+        };
+
+        let packet = write_message(&query, false);
+        assert_eq!(read_message(&packet), Some(query));
+    }
+
+    #[test]
+    fn compressed_names_are_followed() {
+
+        // Question for _lunacam._tcp.local, then a PTR record whose name points
+        // back at it and whose data is "cam" followed by another pointer
+        let mut packet = header(1, 1);
+        write_name(&mut packet, SERVICE_NAME);
+        write_u16(&mut packet, TYPE_PTR);
+        write_u16(&mut packet, CLASS_IN);
+        packet.extend_from_slice(&[0xc0, 12]);
+        write_u16(&mut packet, TYPE_PTR);
+        write_u16(&mut packet, CLASS_IN);
+        packet.extend_from_slice(&RECORD_TTL.to_be_bytes());
+        write_u16(&mut packet, 6);
+        packet.extend_from_slice(&[3, b'c', b'a', b'm', 0xc0, 12]);
+
+        let message = read_message(&packet).unwrap();
+        assert_eq!(message.records[0].name, SERVICE_NAME);
+        assert_eq!(message.records[0].data, RecordData::Ptr(format!("cam.{}", SERVICE_NAME)));
+    }
+
+    #[test]
+    fn truncated_messages_are_rejected() {
+
+        let packet = write_message(&sample_message(), false);
+
+        for len in 0..packet.len() {
+            assert!(read_message(&packet[..len]).is_none(), "accepted {} of {} bytes", len, packet.len());
+        }
+    }
+
+    #[test]
+    fn record_data_beyond_packet_is_rejected() {
+
+        let mut packet = header(0, 1);
+        write_name(&mut packet, "pi.local");
+        write_u16(&mut packet, TYPE_A);
+        write_u16(&mut packet, CLASS_IN);
+        packet.extend_from_slice(&RECORD_TTL.to_be_bytes());
+        write_u16(&mut packet, 0xffff);
+        packet.extend_from_slice(&[192, 168, 1, 20]);
+
+        assert!(read_message(&packet).is_none());
+    }
+
+    #[test]
+    fn compression_loops_are_rejected() {
+
+        // Name pointing at itself
+        let mut packet = header(1, 0);
+        packet.extend_from_slice(&[0xc0, 12]);
+        write_u16(&mut packet, TYPE_PTR);
+        write_u16(&mut packet, CLASS_IN);
+        assert!(read_message(&packet).is_none());
+
+        // Two labels pointing at each other
+        let mut packet = header(1, 0);
+        packet.extend_from_slice(&[1, b'a', 0xc0, 16, 1, b'b', 0xc0, 12]);
+        write_u16(&mut packet, TYPE_PTR);
+        write_u16(&mut packet, CLASS_IN);
+        assert!(read_message(&packet).is_none());
+
+        // Pointer past the end of the packet
+        let mut packet = header(1, 0);
+        packet.extend_from_slice(&[0xff, 0xff]);
+        write_u16(&mut packet, TYPE_PTR);
+        write_u16(&mut packet, CLASS_IN);
+        assert!(read_message(&packet).is_none());
+    }
+
+    #[test]
+    fn oversized_txt_strings_are_rejected() {
+
+        // Record data is 4 bytes long, but its only string claims 200
+        let mut packet = header(0, 1);
+        write_name(&mut packet, SERVICE_NAME);
+        write_u16(&mut packet, TYPE_TXT);
+        write_u16(&mut packet, CLASS_IN);
+        packet.extend_from_slice(&RECORD_TTL.to_be_bytes());
+        write_u16(&mut packet, 4);
+        packet.extend_from_slice(&[200, b'a', b'=', b'b']);
+
+        // Followed by enough bytes that the string would fit in the packet
+        packet.extend_from_slice(&[b'x'; 255]);
+
+        assert!(read_message(&packet).is_none());
+    }
+
+    #[test]
+    fn excessive_record_counts_are_rejected() {
+
+        let packet = header(0xffff, 0xffff);
+        assert!(read_message(&packet).is_none());
+    }
+
+    #[test]
+    fn browser_finds_advertisers_over_loopback() {
+
+        let loopback = Ipv4Addr::LOCALHOST;
+
+        // Names are made unique, in case other tests are running on this machine
+        let prefix = format!("lctest{}", std::process::id());
+        let name_a = format!("{}-a", prefix);
+        let name_b = format!("{}-b", prefix);
+
+        let advert = |name: &str, port: u16, enabled: bool| Advertisement::new(
+            name,
+            "lctest",
+            port,
+            loopback,
+            Box::new(move || vec!["txtvers=1".into(), format!("enabled={}", enabled)]),
+        );
+        let _advertiser_a = Advertiser::spawn(advert(&name_a, 8001, true)).unwrap();
+        let _advertiser_b = Advertiser::spawn(advert(&name_b, DEFAULT_PORT, false)).unwrap();
+
+        // Advertisers start answering as soon as their threads are running,
+        // but give them a few chances in case the machine is busy
+        let mut found = HashMap::new();
+        for _ in 0..3 {
+            for camera in browse_once(loopback).unwrap() {
+                if camera.name.starts_with(&prefix) {
+                    found.insert(camera.name.clone(), camera);
+                }
+            }
+            if found.len() == 2 {
+                break;
+            }
+        }
+
+        let camera_a = found.get(&name_a).expect("first advertiser was not found");
+        assert_eq!(camera_a.address, "127.0.0.1:8001");
+        assert_eq!(camera_a.properties.get("enabled").map(String::as_str), Some("true"));
+
+        let camera_b = found.get(&name_b).expect("second advertiser was not found");
+        assert_eq!(camera_b.address, "127.0.0.1");
+        assert_eq!(camera_b.properties.get("enabled").map(String::as_str), Some("false"));
+    }
+}
@@ -0,0 +1,268 @@
+//! Health monitoring of remote cameras
+//!
+//! A background task periodically polls the stream API of each remote camera,
+//! recording whether it is reachable and how quickly it answers. While polling,
+//! the task also corrects any drift between the portal's configuration of a
+//! camera and the actual state of its stream (for example, after the camera
+//! host has been restored from a backup). Cameras which were unreachable when
+//! their proxy configuration was written are added to the proxy as soon as
+//! they come online.
+
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use actix_web::web::Data;
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use lazy_static::lazy_static;
+use log::{debug, error, info, trace, warn};
+use reqwest::{Client, Method};
+use serde::Serialize;
+use tera::Tera;
+
+use crate::cameras::{self, Camera};
+use crate::db::ConnectionPool;
+use crate::db::schema::cameras as cameras_table;
+use crate::do_lock;
+use crate::error::Result;
+use crate::stream::{StreamState, StreamUpdate};
+
+
+/// Interval at which remote cameras are polled
+const POLL_SECONDS: u64 = 30;
+
+/// How long to wait for a remote camera to answer before considering it offline
+const TIMEOUT_SECONDS: u64 = 5;
+
+
+/// Whether a camera is reachable
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum HealthStatus {
+    Online,
+    Offline,
+}
+
+
+/// Most recently observed health of a remote camera
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CameraHealth {
+    pub status: HealthStatus,
+
+    /// Round-trip time of the most recent successful poll
+    pub latency_ms: Option<u64>,
+
+    /// Time of the most recent successful poll (UTC)
+    pub last_seen: Option<NaiveDateTime>,
+
+    /// Time of the most recent poll (UTC)
+    pub last_checked: NaiveDateTime,
+
+    /// Reason the camera is offline
+    pub error: Option<String>,
+}
+
+
+lazy_static! {
+    /// Health of remote cameras, by camera ID
+    static ref HEALTH: Mutex<HashMap<i32, CameraHealth>> = Mutex::new(HashMap::new());
+}
+
+
+/// Retrieves the most recently observed health of the specified camera
+///
+/// Nothing is known about local cameras, or about remote cameras which have not
+/// been polled yet.
+pub fn get(id: i32) -> Option<CameraHealth> {
+
+    do_lock!(HEALTH).get(&id).cloned()
+}
+
+
+/// Forgets the health of the specified camera, e.g. after it has been deleted
+pub fn clear(id: i32) {
+
+    do_lock!(HEALTH).remove(&id);
+}
+
+
+/// State of the health monitor worker
+struct Worker {
+    pool: Data<ConnectionPool>,
+    templates: Data<Tera>,
+    client: Client,
+}
+
+impl Worker {
+
+    /// Polls all remote cameras
+    fn tick(&self) -> Result<()> {
+
+        trace!("polling remote cameras");
+
+        let conn = self.pool.get()?;
+        let cameras: Vec<Camera> = cameras_table::table
+            .filter(cameras_table::local.eq(false))
+            .load(&conn)?;
+
+        let ids: Vec<i32> = cameras.iter()
+            .filter(|c| c.is_paired())
+            .map(|c| c.id)
+            .collect();
+        do_lock!(HEALTH).retain(|id, _| ids.contains(id));
+
+        // Unpaired cameras would only fail every poll, and are reported once at
+        // startup instead
+        for camera in cameras.iter().filter(|c| c.is_paired()) {
+            self.poll(camera);
+        }
+
+        Ok(())
+    }
+
+    /// Checks the health of one camera and records the result
+    fn poll(&self, camera: &Camera) {
+
+        let started = Instant::now();
+        let result = camera.request(&self.client, Method::GET, "/api/stream")
+            .and_then(|request| Ok(request.send()?.error_for_status()?.json::<StreamState>()?));
+        let latency = started.elapsed();
+        let now = Utc::now().naive_utc();
+
+        let previous = get(camera.id);
+        let was_online = previous.as_ref().map(|h| h.status) == Some(HealthStatus::Online);
+
+        let health = match result {
+            Ok(state) => {
+                if !was_online {
+                    info!("camera {} is online", camera.id);
+                }
+                self.reconcile(camera, &state);
+                cameras::retry_proxy_config(camera, &self.client, &self.templates)
+                    .unwrap_or_else(|e|
+                        error!("failed to configure proxy for camera {}: {}", camera.id, e)
+                    );
+                CameraHealth {
+                    status: HealthStatus::Online,
+                    latency_ms: Some(latency.as_millis() as u64),
+                    last_seen: Some(now),
+                    last_checked: now,
+                    error: None,
+                }
+            },
+            Err(err) => {
+                if was_online || previous.is_none() {
+                    warn!("camera {} is offline: {}", camera.id, err);
+                }
+                CameraHealth {
+                    status: HealthStatus::Offline,
+                    latency_ms: None,
+                    last_seen: previous.and_then(|h| h.last_seen),
+                    last_checked: now,
+                    error: Some(err.to_string()),
+                }
+            },
+        };
+
+        do_lock!(HEALTH).insert(camera.id, health);
+    }
+
+    /// Restores the configured state of a camera's stream if it has drifted
+    ///
+    /// The portal's database is authoritative, since it reflects the choices of
+    /// users and of camera schedules.
+    fn reconcile(&self, camera: &Camera, state: &StreamState) {
+
+        let mut update = StreamUpdate::default();
+        if state.enabled != camera.enabled {
+            update.enabled = Some(camera.enabled);
+        }
+        if state.orientation != camera.orientation {
+            update.orientation = Some(camera.orientation);
+        }
+        if update.enabled.is_none() && update.orientation.is_none() {
+            return;
+        }
+
+        warn!("stream of camera {} differs from its configuration, restoring", camera.id);
+        let result = camera.request_json(&self.client, Method::PATCH, "/api/stream", &update)
+            .and_then(|request| {
+                request.send()?.error_for_status()?;
+                Ok(())
+            });
+        if let Err(err) = result {
+            error!("failed to restore stream of camera {}: {}", camera.id, err);
+        }
+    }
+}
+
+
+/// Repeatedly polls remote cameras until a message is sent over (or the sender
+/// of) `cancel` is dropped
+fn monitor(worker: Worker, cancel: &Receiver<()>) {
+
+    let interval = Duration::from_secs(POLL_SECONDS);
+
+    loop {
+        if let Err(err) = worker.tick() {
+            error!("failed to poll remote cameras: {}", err);
+        }
+
+        if cancel.recv_timeout(interval) != Err(RecvTimeoutError::Timeout) {
+            break;
+        }
+    }
+
+    debug!("health monitor exiting");
+}
+
+
+/// Handle to the background task which monitors the health of remote cameras
+pub struct HealthMonitor {
+    cancel: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl HealthMonitor {
+
+    /// Starts polling remote cameras in the background
+    pub fn start(pool: Data<ConnectionPool>, templates: Data<Tera>) -> Result<Self> {
+
+        debug!("starting health monitor");
+
+        // Unreachable cameras should not hold up polling of the others for long
+        let client = Client::builder()
+            .timeout(Duration::from_secs(TIMEOUT_SECONDS))
+            .build()?;
+
+        let worker = Worker { pool, templates, client };
+        let (cancel, cancel_rx) = mpsc::channel();
+        let thread = thread::spawn(move || monitor(worker, &cancel_rx));
+
+        Ok(Self {
+            cancel: Some(cancel),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for HealthMonitor {
+    fn drop(&mut self) {
+
+        // Send only fails if the thread has already exited
+        if let Some(cancel) = self.cancel.take() {
+            let _ = cancel.send(());
+        }
+
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("health monitor thread panicked");
+            }
+        }
+    }
+}
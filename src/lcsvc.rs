@@ -1,22 +1,41 @@
 use std::env;
 use std::mem;
+#[cfg(feature = "stream")]
 use std::sync::RwLock;
+use std::time::Duration;
 
+#[cfg(debug_assertions)]
 use actix_files::Files;
 use actix_web::{App, HttpServer};
 use actix_web::web::{self, Data};
 use env_logger::Env;
-use log::{debug, trace};
+use log::{debug, info, trace};
+#[cfg(feature = "stream-api")]
+use log::warn;
 use reqwest::Client;
 use tera::Tera;
 
 use lunacam::cameras;
 use lunacam::db;
+use lunacam::discovery;
 use lunacam::error::Result;
-use lunacam::stream;
-use lunacam::ui;
+use lunacam::pairing;
+use lunacam::proxy::{self, BackendKind};
 use lunacam::users;
 
+#[cfg(feature = "stream")]
+use lunacam::stream;
+
+#[cfg(feature = "portal")]
+use lunacam::{
+    audit,
+    health::HealthMonitor,
+    schedules::{self, Scheduler},
+    tokens,
+    totp,
+    ui,
+};
+
 
 #[cfg(not(any(feature = "portal", feature = "stream-api")))]
 compile_error!("invalid feature selection");
@@ -53,10 +72,42 @@ fn load_templates() -> Result<Tera> {
 }
 
 
+/// Default time taken to hash a password after tuning Argon2 parameters
+const DEFAULT_ARGON2_TARGET_MS: u64 = 1000;
+
+
+/// Benchmarks password hashing on this machine and stores Argon2 parameters
+/// which take about the given time (in milliseconds, defaulting to one second)
+///
+/// Existing password hashes are upgraded as users log in.
+fn tune_argon2(target_ms: Option<String>) -> Result<()> {
+
+    let target_ms = match target_ms {
+        Some(ms) => ms.parse()?,
+        None => DEFAULT_ARGON2_TARGET_MS,
+    };
+
+    let pool = db::connect()?;
+    let params = users::tune_argon2(Duration::from_millis(target_ms), &pool.get()?)?;
+    info!(
+        "password hashing now uses {} iterations and {} KiB of memory",
+        params.iterations,
+        params.memory_size,
+    );
+
+    Ok(())
+}
+
+
 fn main() -> Result<()> {
 
     init_logging();
 
+    let mut args = env::args().skip(1);
+    if args.next().as_deref() == Some("tune-argon2") {
+        return tune_argon2(args.next());
+    }
+
     let client    = Data::new(Client::new());
     let templates = Data::new(load_templates()?);
     let pool      = Data::new(db::connect()?);
@@ -65,7 +116,11 @@ fn main() -> Result<()> {
     let conn = pool.get()?;
 
     #[cfg(feature = "stream")]
-    let stream = stream::initialize(&conn, &templates)?;
+    let stream = stream::initialize(&pool, &templates)?;
+
+    if cfg!(feature = "stream-api") {
+        pairing::initialize(&conn)?;
+    }
 
     if cfg!(feature = "portal") {
         cameras::initialize(
@@ -84,6 +139,31 @@ fn main() -> Result<()> {
     // Finished performing initialization requiring database access
     mem::drop(conn);
 
+    #[cfg(feature = "portal")]
+    let _scheduler = Scheduler::start(
+        pool.clone(),
+        client.clone(),
+        templates.clone(),
+        #[cfg(feature = "stream")]
+        stream.clone(),
+    );
+
+    #[cfg(feature = "portal")]
+    let _health_monitor = HealthMonitor::start(pool.clone(), templates.clone())?;
+
+    let _proxy_refresher = proxy::Refresher::start();
+
+    #[cfg(feature = "stream-api")]
+    let _advertiser = discovery::Advertiser::start(pool.clone(), stream.clone())
+        .map_err(|err| warn!("failed to advertise camera on the local network: {}", err))
+        .ok();
+
+    #[cfg(feature = "portal")]
+    let _browser = discovery::Browser::start();
+
+    let listen_addr = proxy::listen_addr();
+    let builtin_proxy = proxy::kind() == BackendKind::Builtin;
+
     HttpServer::new(move || {
 
             let app = App::new()
@@ -97,7 +177,11 @@ fn main() -> Result<()> {
             let api = web::scope("api");
             #[cfg(feature = "portal")]
             let api = api
+                .configure(audit::configure_api)
                 .configure(cameras::configure_api)
+                .configure(schedules::configure_api)
+                .configure(tokens::configure_api)
+                .configure(totp::configure_api)
                 .configure(users::configure_api);
             #[cfg(feature = "stream-api")]
             let api = api.configure(stream::configure_api);
@@ -108,12 +192,19 @@ fn main() -> Result<()> {
                 .service(Files::new("/static/js",  "client/js"))
                 .service(Files::new("/static/css", "build/css"));
 
+            // Must come before the UI, which matches every path
+            let app = if builtin_proxy {
+                app.configure(proxy::builtin::configure)
+            } else {
+                app
+            };
+
             #[cfg(feature = "portal")]
             let app = app.configure(ui::configure);
 
             app
         })
-        .bind("127.0.0.1:9351")?
+        .bind(listen_addr)?
         .run()?;
 
     Ok(())
@@ -10,13 +10,23 @@ extern crate diesel;
 extern crate diesel_migrations;
 
 
+pub mod audit;
 pub mod cameras;
 pub mod db;
+pub mod discovery;
 pub mod error;
+pub mod health;
 mod locks;
+pub mod motion;
+pub mod pairing;
 pub mod prochost;
 pub mod proxy;
+pub mod recording;
+pub mod schedules;
 pub mod settings;
 pub mod stream;
+pub mod tokens;
+pub mod totp;
+pub mod transcoder;
 pub mod ui;
 pub mod users;
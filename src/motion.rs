@@ -0,0 +1,690 @@
+//! Motion detection
+//!
+//! While motion detection is enabled, the transcoder writes a small grayscale
+//! frame to disk several times per second. A background worker compares each
+//! frame against the previous one and records periods of motion in the
+//! database as `MotionEvent`s.
+
+
+use std::env;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+use actix_web::http::StatusCode;
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use log::{debug, error, info, trace};
+use serde::{Deserialize, Serialize};
+
+use crate::db::{ConnectionPool, PooledConnection};
+use crate::db::schema::{cameras, motion_events};
+use crate::do_lock;
+use crate::error::{Error, Result};
+
+
+/// Width of sampled frames, in pixels
+pub const FRAME_WIDTH: usize = 64;
+
+/// Height of sampled frames, in pixels
+pub const FRAME_HEIGHT: usize = 48;
+
+/// Number of frames sampled per second
+pub const FRAME_RATE: u32 = 2;
+
+/// Minimum change in brightness for a pixel to be considered changed
+const PIXEL_THRESHOLD: u8 = 25;
+
+
+//#region Configuration
+
+/// Rectangular area of the frame in which motion is detected
+///
+/// Coordinates are expressed as fractions of the frame's width and height.
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Deserialize, Serialize)]
+pub struct Region {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Region {
+
+    /// Returns whether the given pixel of a sampled frame lies in this region
+    fn contains(&self, col: usize, row: usize) -> bool {
+
+        let x = (col as f32 + 0.5) / FRAME_WIDTH as f32;
+        let y = (row as f32 + 0.5) / FRAME_HEIGHT as f32;
+
+        x >= self.x && x < self.x + self.width
+            && y >= self.y && y < self.y + self.height
+    }
+}
+
+
+/// Motion detection settings
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct MotionConfig {
+
+    /// Whether motion detection is enabled
+    pub enabled: bool,
+
+    /// Value between 0 and 1; higher values detect smaller motions
+    ///
+    /// Motion is detected when more than `1 - sensitivity` of the pixels in the
+    /// masked area change between consecutive frames.
+    pub sensitivity: f32,
+
+    /// Areas of the frame in which motion is detected (whole frame if empty)
+    pub regions: Vec<Region>,
+
+    /// Time without motion after which an event is considered finished, in seconds
+    pub cooldown_secs: u64,
+}
+
+impl Default for MotionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sensitivity: 0.95,
+            regions: Vec::new(),
+            cooldown_secs: 10,
+        }
+    }
+}
+
+impl MotionConfig {
+
+    /// Checks that this configuration is usable
+    pub fn validate(&self) -> Result<()> {
+
+        if !(0.0..=1.0).contains(&self.sensitivity) {
+            return Error::web(StatusCode::BAD_REQUEST, "sensitivity must be between 0 and 1");
+        }
+
+        let valid_region = |r: &Region| {
+            r.x >= 0.0 && r.y >= 0.0 && r.width > 0.0 && r.height > 0.0
+                && r.x + r.width <= 1.0 && r.y + r.height <= 1.0
+        };
+        if !self.regions.iter().all(valid_region) {
+            return Error::web(StatusCode::BAD_REQUEST, "regions must lie within the frame");
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether a frame with the given score (see `score`) shows motion
+    fn is_motion(&self, score: f32) -> bool {
+
+        score > 1.0 - self.sensitivity
+    }
+
+    /// Computes which pixels of a sampled frame are considered for motion
+    fn mask(&self) -> Vec<bool> {
+
+        (0..FRAME_WIDTH * FRAME_HEIGHT)
+            .map(|i| {
+                let (col, row) = (i % FRAME_WIDTH, i / FRAME_WIDTH);
+                self.regions.is_empty() || self.regions.iter().any(|r| r.contains(col, row))
+            })
+            .collect()
+    }
+}
+
+
+/// Gets the path of the file to which sampled frames are written
+///
+/// Frames are written under RUNTIME_DIRECTORY rather than alongside the HLS
+/// stream, so that unencrypted images are never served by the proxy.
+pub fn frame_path() -> Result<String> {
+
+    let rt_dir = env::var("RUNTIME_DIRECTORY")?;
+
+    Ok(format!("{}/motion.pgm", rt_dir))
+}
+
+//#endregion
+
+
+//#region Motion events
+
+/// Period of time during which motion was detected
+#[derive(Deserialize, Serialize)]
+#[derive(Identifiable, Queryable)]
+#[serde(rename_all = "camelCase")]
+#[table_name = "motion_events"]
+pub struct MotionEvent {
+    pub id: i32,
+    pub started_at: NaiveDateTime,
+    pub ended_at: Option<NaiveDateTime>,
+    pub peak_score: f32,
+
+    /// Camera whose stream the event was detected in
+    ///
+    /// Only known to hosts with a portal, as a camera host does not know the ID
+    /// its portal has given it. The portal fills it in when retrieving events
+    /// from a camera host.
+    pub camera_id: Option<i32>,
+}
+
+
+#[derive(Insertable)]
+#[table_name = "motion_events"]
+struct NewMotionEvent {
+    started_at: NaiveDateTime,
+    peak_score: f32,
+    camera_id: Option<i32>,
+}
+
+
+/// Maximum number of events returned by `recent_events`
+const MAX_EVENTS: i64 = 100;
+
+
+/// Retrieves the most recent motion events, newest first
+pub fn recent_events(conn: &PooledConnection) -> Result<Vec<MotionEvent>> {
+
+    let events = motion_events::table
+        .order(motion_events::started_at.desc())
+        .limit(MAX_EVENTS)
+        .load(conn)?;
+
+    Ok(events)
+}
+
+//#endregion
+
+
+//#region Detection
+
+/// Parses a binary PGM image, returning its pixel data
+///
+/// Returns `None` if the image is malformed or is not the expected size (e.g.
+/// because it was read while being written).
+fn parse_frame(data: &[u8]) -> Option<&[u8]> {
+
+    // Header consists of four whitespace-separated tokens: magic number,
+    // width, height and maximum value, followed by a single whitespace byte
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while tokens.len() < 4 {
+        while data.get(pos)?.is_ascii_whitespace() {
+            pos += 1;
+        }
+        let start = pos;
+        while !data.get(pos)?.is_ascii_whitespace() {
+            pos += 1;
+        }
+        tokens.push(std::str::from_utf8(&data[start..pos]).ok()?);
+    }
+    pos += 1;
+
+    let expected_width = FRAME_WIDTH.to_string();
+    let expected_height = FRAME_HEIGHT.to_string();
+    if tokens != ["P5", expected_width.as_str(), expected_height.as_str(), "255"] {
+        return None;
+    }
+
+    let pixels = data.get(pos..)?;
+    if pixels.len() == FRAME_WIDTH * FRAME_HEIGHT {
+        Some(pixels)
+    } else {
+        None
+    }
+}
+
+
+/// Computes the fraction of masked pixels which changed between two frames
+fn score(prev: &[u8], cur: &[u8], mask: &[bool]) -> f32 {
+
+    let mut total = 0;
+    let mut changed = 0;
+
+    for ((a, b), m) in prev.iter().zip(cur).zip(mask) {
+        if *m {
+            total += 1;
+            if a.max(b) - a.min(b) >= PIXEL_THRESHOLD {
+                changed += 1;
+            }
+        }
+    }
+
+    if total == 0 {
+        0.0
+    } else {
+        changed as f32 / total as f32
+    }
+}
+
+
+/// Motion event which has not yet finished
+struct OpenEvent {
+    id: i32,
+    peak_score: f32,
+    last_motion: Instant,
+}
+
+
+/// State of the motion detection worker
+struct Detector {
+    config: Arc<Mutex<MotionConfig>>,
+    pool: ConnectionPool,
+    frame_path: String,
+    last_modified: Option<SystemTime>,
+    prev_frame: Option<Vec<u8>>,
+    event: Option<OpenEvent>,
+}
+
+impl Detector {
+
+    /// Reads the next sampled frame, if one has been written since the last call
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+
+        let modified = fs::metadata(&self.frame_path)
+            .and_then(|m| m.modified())
+            .ok()?;
+        if self.last_modified == Some(modified) {
+            return None;
+        }
+
+        let data = fs::read(&self.frame_path).ok()?;
+        let frame = parse_frame(&data)?.to_vec();
+        self.last_modified = Some(modified);
+
+        Some(frame)
+    }
+
+    /// Samples a frame and updates the current motion event
+    fn tick(&mut self) -> Result<()> {
+
+        let frame = match self.next_frame() {
+            Some(frame) => frame,
+            None => return self.maybe_close_event(),
+        };
+
+        let config = do_lock!(self.config).clone();
+        let score = match self.prev_frame {
+            Some(ref prev) => score(prev, &frame, &config.mask()),
+            None => 0.0,
+        };
+        self.prev_frame = Some(frame);
+
+        if config.is_motion(score) {
+            trace!("detected motion with score {}", score);
+            match self.event {
+                Some(ref mut event) => {
+                    event.peak_score = event.peak_score.max(score);
+                    event.last_motion = Instant::now();
+                },
+                None => self.open_event(score)?,
+            }
+        }
+
+        self.maybe_close_event()
+    }
+
+    /// Records the start of a new motion event
+    fn open_event(&mut self, score: f32) -> Result<()> {
+
+        let conn = self.pool.get()?;
+
+        // Portals list their own stream as a local camera
+        let camera_id = cameras::table
+            .filter(cameras::local.eq(true))
+            .select(cameras::id)
+            .first(&conn)
+            .optional()?;
+
+        let new_event = NewMotionEvent {
+            started_at: Utc::now().naive_utc(),
+            peak_score: score,
+            camera_id,
+        };
+        diesel::insert_into(motion_events::table)
+            .values(&new_event)
+            .execute(&conn)?;
+
+        // Get the row we just inserted
+        let event: MotionEvent = motion_events::table.order(motion_events::id.desc())
+            .first(&conn)?;
+
+        info!("motion event {} started", event.id);
+
+        self.event = Some(OpenEvent {
+            id: event.id,
+            peak_score: score,
+            last_motion: Instant::now(),
+        });
+
+        Ok(())
+    }
+
+    /// Records the end of the current motion event if its cooldown has elapsed
+    fn maybe_close_event(&mut self) -> Result<()> {
+
+        let cooldown = Duration::from_secs(do_lock!(self.config).cooldown_secs);
+
+        match self.event {
+            Some(ref event) if event.last_motion.elapsed() >= cooldown => self.close_event(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Records the end of the current motion event, if any
+    fn close_event(&mut self) -> Result<()> {
+
+        if let Some(event) = self.event.take() {
+            let conn = self.pool.get()?;
+            diesel::update(motion_events::table.find(event.id))
+                .set((
+                    motion_events::ended_at.eq(Utc::now().naive_utc()),
+                    motion_events::peak_score.eq(event.peak_score),
+                ))
+                .execute(&conn)?;
+
+            info!("motion event {} finished", event.id);
+        }
+
+        Ok(())
+    }
+}
+
+
+/// Repeatedly samples frames until a message is sent over (or the sender of)
+/// `cancel` is dropped
+fn detect(mut detector: Detector, cancel: &Receiver<()>) {
+
+    let interval = Duration::from_millis(1000 / u64::from(FRAME_RATE));
+
+    while let Err(RecvTimeoutError::Timeout) = cancel.recv_timeout(interval) {
+        if let Err(err) = detector.tick() {
+            error!("failed to process motion frame: {}", err);
+        }
+    }
+
+    if let Err(err) = detector.close_event() {
+        error!("failed to finish motion event: {}", err);
+    }
+
+    debug!("motion detector exiting");
+}
+
+
+/// Handle to a running motion detection thread
+struct Worker {
+    cancel: Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl Worker {
+
+    /// Signals the worker thread to exit and waits for it to do so
+    fn join(self) {
+
+        // Send only fails if the thread has already exited
+        let _ = self.cancel.send(());
+
+        if self.thread.join().is_err() {
+            error!("motion detector thread panicked");
+        }
+    }
+}
+
+
+/// Detects motion in the current host's video stream
+///
+/// A background worker runs for as long as motion detection is enabled.
+pub struct MotionDetector {
+    config: Arc<Mutex<MotionConfig>>,
+    pool: ConnectionPool,
+    worker: Option<Worker>,
+}
+
+impl MotionDetector {
+
+    /// Creates a new `MotionDetector`, starting it if `config` is enabled
+    pub fn new(config: MotionConfig, pool: ConnectionPool) -> Result<Self> {
+
+        let mut detector = Self {
+            config: Arc::new(Mutex::new(MotionConfig::default())),
+            pool,
+            worker: None,
+        };
+        detector.set_config(config)?;
+
+        Ok(detector)
+    }
+
+    /// Gets the current motion detection settings
+    pub fn config(&self) -> MotionConfig {
+
+        do_lock!(self.config).clone()
+    }
+
+    /// Checks that the given settings are usable, including that the worker
+    /// could be started if they enable motion detection
+    pub fn validate_config(&self, config: &MotionConfig) -> Result<()> {
+
+        config.validate()?;
+
+        if config.enabled && self.worker.is_none() {
+            frame_path()?;
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the current motion detection settings
+    ///
+    /// The worker is started or stopped as needed. Other changes take effect
+    /// with the next sampled frame. If the worker cannot be started, the
+    /// current settings are left unchanged.
+    pub fn set_config(&mut self, config: MotionConfig) -> Result<()> {
+
+        let enabled = config.enabled;
+        let start_path = if enabled && self.worker.is_none() {
+            Some(frame_path()?)
+        } else {
+            None
+        };
+
+        *do_lock!(self.config) = config;
+
+        if let Some(frame_path) = start_path {
+            debug!("starting motion detector");
+            let detector = Detector {
+                config: self.config.clone(),
+                pool: self.pool.clone(),
+                frame_path,
+                last_modified: None,
+                prev_frame: None,
+                event: None,
+            };
+            let (cancel, cancel_rx) = mpsc::channel();
+            let thread = thread::spawn(move || detect(detector, &cancel_rx));
+            self.worker = Some(Worker { cancel, thread });
+        } else if !enabled {
+            if let Some(worker) = self.worker.take() {
+                debug!("stopping motion detector");
+                worker.join();
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for MotionDetector {
+    fn drop(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            worker.join();
+        } else {
+            trace!("motion detector was not running");
+        }
+    }
+}
+
+//#endregion
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+
+    fn frame(value: u8) -> Vec<u8> {
+        vec![value; FRAME_WIDTH * FRAME_HEIGHT]
+    }
+
+    fn pgm(header: &str, pixels: &[u8]) -> Vec<u8> {
+        let mut data = header.as_bytes().to_vec();
+        data.extend_from_slice(pixels);
+        data
+    }
+
+    fn region(x: f32, y: f32, width: f32, height: f32) -> Region {
+        Region { x, y, width, height }
+    }
+
+
+    #[test]
+    fn frames_are_parsed() {
+
+        let pixels = frame(7);
+
+        let data = pgm("P5\n64 48\n255\n", &pixels);
+        assert_eq!(parse_frame(&data), Some(pixels.as_slice()));
+
+        let data = pgm("P5  64\t48\r\n255 ", &pixels);
+        assert_eq!(parse_frame(&data), Some(pixels.as_slice()));
+    }
+
+    #[test]
+    fn malformed_frames_are_rejected() {
+
+        let pixels = frame(7);
+
+        assert_eq!(parse_frame(b""), None);
+        assert_eq!(parse_frame(b"P5\n64 48"), None);
+        assert_eq!(parse_frame(&pgm("P6\n64 48\n255\n", &pixels)), None);
+        assert_eq!(parse_frame(&pgm("P5\n48 64\n255\n", &pixels)), None);
+        assert_eq!(parse_frame(&pgm("P5\n64 48\n65535\n", &pixels)), None);
+
+        // Partially written
+        assert_eq!(parse_frame(&pgm("P5\n64 48\n255\n", &pixels[1..])), None);
+        assert_eq!(parse_frame(&pgm("P5\n64 48\n255\n", &[&pixels[..], &[0u8][..]].concat())), None);
+    }
+
+    #[test]
+    fn small_changes_are_ignored() {
+
+        let mask = MotionConfig::default().mask();
+        let prev = frame(100);
+
+        assert_eq!(score(&prev, &prev, &mask), 0.0);
+        assert_eq!(score(&prev, &frame(100 + PIXEL_THRESHOLD - 1), &mask), 0.0);
+        assert_eq!(score(&prev, &frame(100 - PIXEL_THRESHOLD + 1), &mask), 0.0);
+        assert_eq!(score(&prev, &frame(100 + PIXEL_THRESHOLD), &mask), 1.0);
+        assert_eq!(score(&prev, &frame(100 - PIXEL_THRESHOLD), &mask), 1.0);
+    }
+
+    #[test]
+    fn score_is_fraction_of_changed_pixels() {
+
+        let mask = MotionConfig::default().mask();
+        let prev = frame(0);
+        let mut cur = frame(0);
+        for pixel in cur.iter_mut().take(FRAME_WIDTH * FRAME_HEIGHT / 4) {
+            *pixel = 255;
+        }
+
+        assert_eq!(score(&prev, &cur, &mask), 0.25);
+    }
+
+    #[test]
+    fn unmasked_pixels_are_ignored() {
+
+        // Only the top half of the frame changes
+        let prev = frame(0);
+        let mut cur = frame(0);
+        for pixel in cur.iter_mut().take(FRAME_WIDTH * FRAME_HEIGHT / 2) {
+            *pixel = 255;
+        }
+
+        let config = |regions| MotionConfig { regions, ..MotionConfig::default() };
+
+        let top = config(vec![region(0.0, 0.0, 1.0, 0.5)]);
+        assert_eq!(score(&prev, &cur, &top.mask()), 1.0);
+
+        let bottom = config(vec![region(0.0, 0.5, 1.0, 0.5)]);
+        assert_eq!(score(&prev, &cur, &bottom.mask()), 0.0);
+
+        let empty_mask = vec![false; FRAME_WIDTH * FRAME_HEIGHT];
+        assert_eq!(score(&prev, &cur, &empty_mask), 0.0);
+    }
+
+    #[test]
+    fn motion_follows_sensitivity() {
+
+        let config = |sensitivity| MotionConfig { sensitivity, ..MotionConfig::default() };
+
+        assert!(config(0.95).is_motion(0.06));
+        assert!(!config(0.95).is_motion(0.05));
+        assert!(!config(0.95).is_motion(0.0));
+
+        assert!(config(1.0).is_motion(0.01));
+        assert!(!config(1.0).is_motion(0.0));
+
+        assert!(!config(0.0).is_motion(1.0));
+    }
+
+    #[test]
+    fn empty_regions_mask_whole_frame() {
+
+        let mask = MotionConfig::default().mask();
+
+        assert_eq!(mask.len(), FRAME_WIDTH * FRAME_HEIGHT);
+        assert!(mask.iter().all(|m| *m));
+    }
+
+    #[test]
+    fn regions_mask_covered_pixels() {
+
+        let config = MotionConfig {
+            regions: vec![region(0.0, 0.0, 0.5, 0.5), region(0.75, 0.75, 0.25, 0.25)],
+            ..MotionConfig::default()
+        };
+        let mask = config.mask();
+        let masked = |col: usize, row: usize| mask[row * FRAME_WIDTH + col];
+
+        assert!(masked(0, 0));
+        assert!(masked(FRAME_WIDTH / 2 - 1, FRAME_HEIGHT / 2 - 1));
+        assert!(!masked(FRAME_WIDTH / 2, 0));
+        assert!(!masked(0, FRAME_HEIGHT / 2));
+        assert!(masked(FRAME_WIDTH - 1, FRAME_HEIGHT - 1));
+        assert!(!masked(FRAME_WIDTH * 3 / 4 - 1, FRAME_HEIGHT - 1));
+
+        let count = mask.iter().filter(|m| **m).count();
+        assert_eq!(count, FRAME_WIDTH * FRAME_HEIGHT / 4 + FRAME_WIDTH * FRAME_HEIGHT / 16);
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+
+        let config = |sensitivity, regions| MotionConfig { sensitivity, regions, ..MotionConfig::default() };
+
+        assert!(config(0.5, vec![region(0.0, 0.0, 1.0, 1.0)]).validate().is_ok());
+        assert!(config(1.5, vec![]).validate().is_err());
+        assert!(config(-0.1, vec![]).validate().is_err());
+        assert!(config(0.5, vec![region(0.5, 0.0, 0.6, 0.5)]).validate().is_err());
+        assert!(config(0.5, vec![region(0.0, 0.0, 0.0, 0.5)]).validate().is_err());
+        assert!(config(0.5, vec![region(-0.1, 0.0, 0.5, 0.5)]).validate().is_err());
+    }
+}
@@ -0,0 +1,580 @@
+//! Authentication of requests from the portal to camera hosts
+//!
+//! A camera host starts out unpaired, and logs a short pairing code. When the
+//! camera is added to the portal, the portal presents this code in exchange for
+//! a shared key. From then on, every request the portal makes to the stream API
+//! is signed with an HMAC of its method, path, timestamp, a random nonce and
+//! its body, and the camera host rejects any request without a valid signature.
+//! Camera hosts remember the nonces of recently accepted requests, so that a
+//! captured request cannot be replayed.
+
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::{Error as ActixError, HttpMessage, HttpRequest, HttpResponse};
+use actix_web::dev::{Payload, RequestHead, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::PayloadError;
+use actix_web::http::StatusCode;
+use actix_web::web::{self, Bytes, BytesMut, Data, Json, ServiceConfig};
+use futures::{Future, Poll, Stream};
+use futures::future::{self, Either, FutureResult};
+use lazy_static::lazy_static;
+use log::{debug, info, trace, warn};
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use rand::Rng;
+use reqwest::{Client, Method, RequestBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::db::{ConnectionPool, PooledConnection};
+use crate::do_lock;
+use crate::error::{Error, Result};
+use crate::settings;
+
+
+const TIMESTAMP_HEADER: &str = "X-LunaCam-Timestamp";
+const SIGNATURE_HEADER: &str = "X-LunaCam-Signature";
+const NONCE_HEADER: &str = "X-LunaCam-Nonce";
+
+/// Signed requests are only accepted for this long, which bounds how long
+/// their nonces must be remembered
+const MAX_CLOCK_SKEW_SECS: u64 = 60;
+
+/// Length of request nonces, in bytes (before encoding)
+const NONCE_LENGTH: usize = 16;
+
+
+lazy_static! {
+    /// Nonces of accepted requests, along with their timestamps
+    static ref SEEN_NONCES: Mutex<HashMap<String, u64>> = Mutex::new(HashMap::new());
+}
+
+/// Largest request body accepted by signed resources
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+
+//#region Signatures
+
+fn unix_time() -> u64 {
+
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time is before the Unix epoch")
+        .as_secs()
+}
+
+
+/// Computes the signature of a request
+fn sign(key: &[u8], method: &str, path: &str, timestamp: u64, nonce: &str, body: &[u8]) -> Result<Vec<u8>> {
+
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+
+    signer.update(format!("{}\n{}\n{}\n{}\n", method, path, timestamp, nonce).as_bytes())?;
+    signer.update(body)?;
+
+    Ok(signer.sign_to_vec()?)
+}
+
+
+/// Builds a signed request to the stream API of a camera host
+///
+/// `path` includes the */api* prefix, e.g. */api/stream*.
+pub fn request(
+    client: &Client,
+    method: Method,
+    address: &str,
+    path: &str,
+    key: &[u8],
+) -> Result<RequestBuilder>
+{
+    request_with_body(client, method, address, path, key, Vec::new())
+}
+
+
+/// Builds a signed request to the stream API of a camera host, with the given
+/// value as its JSON body
+pub fn request_json<T: Serialize>(
+    client: &Client,
+    method: Method,
+    address: &str,
+    path: &str,
+    key: &[u8],
+    body: &T,
+) -> Result<RequestBuilder>
+{
+    let body = serde_json::to_vec(body)?;
+
+    let request = request_with_body(client, method, address, path, key, body)?
+        .header("Content-Type", "application/json");
+
+    Ok(request)
+}
+
+
+fn request_with_body(
+    client: &Client,
+    method: Method,
+    address: &str,
+    path: &str,
+    key: &[u8],
+    body: Vec<u8>,
+) -> Result<RequestBuilder>
+{
+    let headers = signature_headers(key, method.as_str(), path, &body)?;
+
+    let url = format!("http://{}{}", address, path);
+    let mut request = client.request(method, &url);
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+
+    Ok(request.body(body))
+}
+
+
+/// Computes the headers which sign a request to a camera host, for clients
+/// other than `request`'s (e.g. Actix's)
+pub fn signature_headers(
+    key: &[u8],
+    method: &str,
+    path: &str,
+    body: &[u8],
+) -> Result<Vec<(&'static str, String)>>
+{
+    let timestamp = unix_time();
+    let nonce = base64::encode(&rand::thread_rng().gen::<[u8; NONCE_LENGTH]>());
+    let signature = sign(key, method, path, timestamp, &nonce, body)?;
+
+    Ok(vec![
+        (TIMESTAMP_HEADER, timestamp.to_string()),
+        (NONCE_HEADER, nonce),
+        (SIGNATURE_HEADER, base64::encode(&signature)),
+    ])
+}
+
+
+/// Records the nonce of an accepted request, returning `false` if it has been
+/// seen before
+///
+/// Nonces are forgotten once their requests' timestamps are out of range, as
+/// such requests would be rejected anyway.
+fn remember_nonce(nonce: &str, timestamp: u64, now: u64) -> bool {
+
+    let mut seen = do_lock!(SEEN_NONCES);
+    seen.retain(|_, &mut seen_at| seen_at + MAX_CLOCK_SKEW_SECS >= now);
+
+    if seen.contains_key(nonce) {
+        return false;
+    }
+    seen.insert(nonce.to_owned(), timestamp);
+
+    true
+}
+
+
+/// Checks the signature of a request received from the portal
+fn verify(req: &RequestHead, body: &[u8], conn: &PooledConnection) -> Result<()> {
+
+    let key = match get_key(conn)? {
+        Some(key) => key,
+        None => return Error::web(StatusCode::UNAUTHORIZED, "camera is not paired"),
+    };
+
+    let header = |name| req.headers
+        .get(name)
+        .and_then(|value| value.to_str().ok());
+
+    let timestamp: u64 = match header(TIMESTAMP_HEADER).and_then(|t| t.parse().ok()) {
+        Some(timestamp) => timestamp,
+        None => return Error::web(StatusCode::UNAUTHORIZED, "request is not signed"),
+    };
+    let signature = match header(SIGNATURE_HEADER).and_then(|s| base64::decode(s).ok()) {
+        Some(signature) => signature,
+        None => return Error::web(StatusCode::UNAUTHORIZED, "request is not signed"),
+    };
+    let nonce = match header(NONCE_HEADER) {
+        Some(nonce) if !nonce.is_empty() => nonce,
+        _ => return Error::web(StatusCode::UNAUTHORIZED, "request is not signed"),
+    };
+
+    let now = unix_time();
+    if timestamp.max(now) - timestamp.min(now) > MAX_CLOCK_SKEW_SECS {
+        return Error::web(StatusCode::UNAUTHORIZED, "request timestamp is out of range");
+    }
+
+    let path = req.uri
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or_else(|| req.uri.path());
+    let expected = sign(&key, req.method.as_str(), path, timestamp, nonce, body)?;
+    if expected.len() != signature.len() || !memcmp::eq(&expected, &signature) {
+        return Error::web(StatusCode::UNAUTHORIZED, "invalid request signature");
+    }
+
+    // Only checked once the signature is known to be valid, so that forged
+    // requests cannot fill the cache
+    if !remember_nonce(nonce, timestamp, now) {
+        return Error::web(StatusCode::UNAUTHORIZED, "request has already been received");
+    }
+
+    trace!("verified signature of {} {}", req.method, path);
+    Ok(())
+}
+
+//#endregion
+
+
+//#region Pairing
+
+const PAIRING_KEY_SETTING: &str = "pairingKey";
+const PAIRING_CODE_SETTING: &str = "pairingCode";
+
+/// Characters used in pairing codes, omitting those which are easily confused
+const PAIRING_CODE_CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const PAIRING_CODE_LENGTH: usize = 8;
+
+/// A new pairing code is generated after this many incorrect guesses
+const MAX_PAIRING_ATTEMPTS: u32 = 5;
+
+
+/// Pairing code and the number of incorrect attempts made against it
+#[derive(Deserialize, Serialize)]
+struct PairingCode {
+    code: String,
+    attempts: u32,
+}
+
+
+// Pairing settings are set to null rather than deleted when cleared, hence
+// the nested options
+
+fn get_key(conn: &PooledConnection) -> Result<Option<Vec<u8>>> {
+
+    let key = match settings::get::<Option<String>>(PAIRING_KEY_SETTING, conn)?.flatten() {
+        Some(key) => Some(base64::decode(&key)?),
+        None => None,
+    };
+
+    Ok(key)
+}
+
+fn get_pairing_code(conn: &PooledConnection) -> Result<Option<PairingCode>> {
+
+    let pairing = settings::get::<Option<PairingCode>>(PAIRING_CODE_SETTING, conn)?
+        .flatten();
+
+    Ok(pairing)
+}
+
+
+/// Generates and logs a new pairing code
+fn reset_pairing_code(conn: &PooledConnection) -> Result<()> {
+
+    let mut rng = rand::thread_rng();
+    let code: String = (0..PAIRING_CODE_LENGTH)
+        .map(|_| PAIRING_CODE_CHARS[rng.gen_range(0, PAIRING_CODE_CHARS.len())] as char)
+        .collect();
+
+    settings::set(PAIRING_CODE_SETTING, &Some(PairingCode { code, attempts: 0 }), conn)?;
+    log_pairing_code(conn)
+}
+
+
+fn log_pairing_code(conn: &PooledConnection) -> Result<()> {
+
+    if let Some(pairing) = get_pairing_code(conn)? {
+        warn!("camera is not paired with a portal, pairing code is {}", pairing.code);
+    }
+
+    Ok(())
+}
+
+
+/// Prepares this camera host to be paired with a portal
+///
+/// If the host has not been paired yet, its pairing code is logged so that an
+/// administrator can add the camera to a portal.
+pub fn initialize(conn: &PooledConnection) -> Result<()> {
+
+    if get_key(conn)?.is_some() {
+        debug!("camera is paired");
+        return Ok(());
+    }
+
+    if get_pairing_code(conn)?.is_none() {
+        reset_pairing_code(conn)?;
+    } else {
+        log_pairing_code(conn)?;
+    }
+
+    Ok(())
+}
+
+
+/// Returns whether this camera host has been paired with a portal
+pub fn is_paired(conn: &PooledConnection) -> Result<bool> {
+
+    Ok(get_key(conn)?.is_some())
+}
+
+
+/// Body of a pairing request
+#[derive(Deserialize)]
+struct PostPairingBody {
+    code: String,
+}
+
+
+/// Response to a successful pairing request
+#[derive(Deserialize, Serialize)]
+pub struct PairingResponse {
+    key: String,
+}
+
+
+/// Pairs this camera host with the portal presenting the pairing code
+fn post_pairing(
+    pool: Data<ConnectionPool>,
+    body: Json<PostPairingBody>,
+) -> Result<Json<PairingResponse>>
+{
+    let conn = pool.get()?;
+
+    if get_key(&conn)?.is_some() {
+        return Error::web(StatusCode::CONFLICT, "camera is already paired");
+    }
+
+    let mut pairing = match get_pairing_code(&conn)? {
+        Some(pairing) => pairing,
+        None => return Error::web(StatusCode::CONFLICT, "camera is not ready to be paired"),
+    };
+
+    let code = body.code.trim().to_uppercase();
+    if !memcmp_str(&code, &pairing.code) {
+        pairing.attempts += 1;
+        if pairing.attempts >= MAX_PAIRING_ATTEMPTS {
+            warn!("too many incorrect pairing attempts");
+            reset_pairing_code(&conn)?;
+        } else {
+            settings::set(PAIRING_CODE_SETTING, &Some(pairing), &conn)?;
+        }
+        return Error::web(StatusCode::UNAUTHORIZED, "incorrect pairing code");
+    }
+
+    let key: [u8; 32] = rand::thread_rng().gen();
+    let key = base64::encode(&key);
+    settings::set(PAIRING_KEY_SETTING, &Some(key.clone()), &conn)?;
+    settings::set::<Option<PairingCode>>(PAIRING_CODE_SETTING, &None, &conn)?;
+
+    info!("paired with portal");
+
+    Ok(Json(PairingResponse { key }))
+}
+
+
+/// Unpairs this camera host, allowing it to be paired with a portal again
+///
+/// This is called by the paired portal when the camera is removed, so it must
+/// be signed like any other request.
+fn delete_pairing(
+    pool: Data<ConnectionPool>,
+    req: HttpRequest,
+) -> Result<HttpResponse>
+{
+    let conn = pool.get()?;
+
+    verify(req.head(), &[], &conn)?;
+
+    settings::set::<Option<String>>(PAIRING_KEY_SETTING, &None, &conn)?;
+    info!("unpaired from portal");
+    reset_pairing_code(&conn)?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+
+fn memcmp_str(a: &str, b: &str) -> bool {
+
+    a.len() == b.len() && memcmp::eq(a.as_bytes(), b.as_bytes())
+}
+
+
+/// Exchanges a pairing code for the key used to sign requests to a camera host
+pub fn pair(client: &Client, address: &str, code: &str) -> Result<Vec<u8>> {
+
+    debug!("pairing with camera at {}", address);
+    let url = format!("http://{}/api/stream/pairing", address);
+    let response = client.post(&url)
+        .json(&serde_json::json!({ "code": code }))
+        .send()?;
+
+    if response.status() == StatusCode::UNAUTHORIZED {
+        return Error::web(StatusCode::BAD_REQUEST, "incorrect pairing code");
+    }
+    if response.status() == StatusCode::CONFLICT {
+        return Error::web(StatusCode::CONFLICT, "camera is already paired with a portal");
+    }
+
+    let pairing: PairingResponse = response.error_for_status()?
+        .json()?;
+    let key = base64::decode(&pairing.key)?;
+
+    Ok(key)
+}
+
+
+/// Releases the key obtained by `pair`, so that the camera host can be paired
+/// again
+pub fn unpair(client: &Client, address: &str, key: &[u8]) -> Result<()> {
+
+    debug!("unpairing from camera at {}", address);
+    request(client, Method::DELETE, address, "/api/stream/pairing", key)?
+        .send()?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+
+/// Configures the */stream/pairing* API resource
+pub fn configure_api(service: &mut ServiceConfig) {
+
+    service.service(
+        web::resource("/stream/pairing")
+            .route(web::post().to(post_pairing))
+            .route(web::delete().to(delete_pairing))
+    );
+}
+
+//#endregion
+
+
+//#region Signature Middleware
+
+pub struct SignatureService<S> {
+    service: Rc<RefCell<S>>,
+}
+
+impl<S> Service for SignatureService<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = ActixError> + 'static,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+    type Error = ActixError;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.service.borrow_mut().poll_ready()
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+
+        let service = self.service.clone();
+
+        // The body is part of the signature, so it must be read in full before
+        // being handed back to the request
+        let body = req.take_payload()
+            .map_err(ActixError::from)
+            .fold(BytesMut::new(), |mut body, chunk| {
+                if body.len() + chunk.len() > MAX_BODY_SIZE {
+                    return Err(ActixError::from(PayloadError::Overflow));
+                }
+                body.extend_from_slice(&chunk);
+                Ok(body)
+            });
+
+        Box::new(body.and_then(move |body| {
+
+            let body = body.freeze();
+
+            let pool: Data<ConnectionPool> = req.app_data()
+                .expect("failed to retrieve connection pool");
+            let res = pool.get()
+                .map_err(Error::from)
+                .and_then(|conn| verify(req.head(), &body, &conn));
+
+            if let Err(err) = res {
+                debug!("rejecting {} {}: {}", req.method(), req.path(), err);
+                let response = HttpResponse::Unauthorized()
+                    .finish();
+                return Either::A(future::ok(req.into_response(response)));
+            }
+
+            let stream = futures::stream::once::<Bytes, PayloadError>(Ok(body));
+            req.set_payload(Payload::Stream(Box::new(stream)));
+
+            Either::B(service.borrow_mut().call(req))
+        }))
+    }
+}
+
+/// Rejects requests which are not signed with the pairing key
+pub struct SignatureMiddleware;
+
+impl<S> Transform<S> for SignatureMiddleware
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = ActixError> + 'static,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+    type Error = ActixError;
+    type InitError = ();
+    type Transform = SignatureService<S>;
+    type Future = FutureResult<Self::Transform, Self::InitError>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(SignatureService {
+            service: Rc::new(RefCell::new(service)),
+        })
+    }
+}
+
+//#endregion
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+
+    #[test]
+    fn repeated_nonces_are_rejected() {
+
+        let now = unix_time();
+
+        assert!(remember_nonce("test-repeated", now, now));
+        assert!(!remember_nonce("test-repeated", now, now));
+        assert!(!remember_nonce("test-repeated", now, now + MAX_CLOCK_SKEW_SECS));
+        assert!(remember_nonce("test-other", now, now));
+    }
+
+    #[test]
+    fn expired_nonces_are_forgotten() {
+
+        let then = unix_time() - 10 * MAX_CLOCK_SKEW_SECS;
+
+        assert!(remember_nonce("test-expired", then, then));
+        assert!(remember_nonce("test-expired", then, then + MAX_CLOCK_SKEW_SECS + 1));
+    }
+
+    #[test]
+    fn signatures_cover_the_nonce() {
+
+        let key = [7u8; 32];
+        let a = sign(&key, "GET", "/stream/stream.m3u8", 1000, "nonce-a", b"").unwrap();
+        let b = sign(&key, "GET", "/stream/stream.m3u8", 1000, "nonce-b", b"").unwrap();
+
+        assert_ne!(a, b);
+    }
+}
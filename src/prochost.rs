@@ -1,42 +1,300 @@
 //! Child process lifecycle management
 
-use std::process::{Child, Command};
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader};
+use std::process::{Child, ChildStderr, Command, Stdio};
 use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use actix_web::http::StatusCode;
+use chrono::{DateTime, Utc};
+use libc::c_int;
 use log::{debug, error, trace, warn};
-use crate::error::Result;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use crate::error::{Error, Result};
 use crate::do_lock;
 
 
+/// Maximum number of lines of stderr retained from the child process
+const STDERR_CAPACITY: usize = 200;
+
+
+/// Lifecycle state of a `ProcHost`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum HostStatus {
+
+    /// Host has not been started, or has been stopped
+    #[default]
+    Stopped,
+
+    /// Child process is running
+    Running,
+
+    /// Child process exited unexpectedly and is waiting to be restarted
+    BackingOff,
+
+    /// Child process exited too many times and will not be restarted until the
+    /// host is reset
+    Failed,
+}
+
+/// Policy governing how the watchdog restarts a child process
+///
+/// After each unexpected exit, the child process is restarted after a delay
+/// which doubles with every exit in the current window (plus random jitter) up
+/// to `max_backoff_ms`. If the child process exits more than `max_restarts`
+/// times within `window_secs`, the host gives up and enters the failed state.
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RestartPolicy {
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub max_restarts: u32,
+    pub window_secs: u64,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 60_000,
+            max_restarts: 5,
+            window_secs: 300,
+        }
+    }
+}
+
+impl RestartPolicy {
+
+    /// Checks that this policy is usable
+    pub fn validate(&self) -> Result<()> {
+
+        if self.initial_backoff_ms == 0 || self.max_backoff_ms < self.initial_backoff_ms {
+            return Error::web(StatusCode::BAD_REQUEST, "invalid restart backoff limits");
+        }
+        if self.window_secs == 0 {
+            return Error::web(StatusCode::BAD_REQUEST, "restart window must be nonzero");
+        }
+
+        Ok(())
+    }
+
+    /// Computes the delay before restarting after the given number of recent exits
+    fn backoff(&self, exits: usize) -> Duration {
+
+        let exponent = exits.saturating_sub(1).min(16) as u32;
+        let delay = self.initial_backoff_ms
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff_ms);
+
+        // Half of the delay is fixed and half is random, so that multiple hosts
+        // failing together do not restart in lockstep
+        let jitter = rand::thread_rng().gen_range(0, delay / 2 + 1);
+
+        Duration::from_millis(delay - delay / 2 + jitter)
+    }
+}
+
+
+/// Describes how a child process is asked to exit when its host is stopped
+///
+/// The child process is first sent `signal`, giving it a chance to clean up
+/// (e.g. finalizing output files). If it has not exited after `grace_period`,
+/// it is forcibly killed.
+#[derive(Clone, Copy, Debug)]
+pub struct ShutdownPolicy {
+    pub signal: c_int,
+    pub grace_period: Duration,
+}
+
+impl Default for ShutdownPolicy {
+    fn default() -> Self {
+        Self {
+            signal: libc::SIGTERM,
+            grace_period: Duration::from_secs(5),
+        }
+    }
+}
+
+
+/// Diagnostic information about a hosted child process
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HostDiagnostics {
+
+    /// Current lifecycle state of the host
+    pub status: HostStatus,
+
+    /// Number of times the child process has been restarted by the watchdog
+    pub restart_count: u32,
+
+    /// Time at which the child process was most recently started
+    pub started_at: Option<DateTime<Utc>>,
+
+    /// Time at which the child process most recently exited unexpectedly
+    pub last_exit_at: Option<DateTime<Utc>>,
+
+    /// Description of the child process's most recent unexpected exit status
+    pub last_exit_status: Option<String>,
+
+    /// Most recent lines written by the child process to stderr
+    pub stderr: Vec<String>,
+}
+
+
 /// Internal state of the process host
 struct HostState {
     cmd: Command,
     child: Option<Child>,
+    status: HostStatus,
+    policy: RestartPolicy,
+    shutdown: ShutdownPolicy,
+    recent_exits: VecDeque<Instant>,
+    next_restart: Option<Instant>,
+    diagnostics: HostDiagnostics,
+    stderr: Arc<Mutex<VecDeque<String>>>,
 }
 
+impl HostState {
 
-/// Watchdog tick interval
-const WDG_TICK_SECONDS: u64 = 2;
+    /// Spawns the child process, capturing its stderr
+    fn spawn(&mut self) -> io::Result<()> {
 
+        let mut child = self.cmd.spawn()?;
 
-/// Periodically checks that the child process is still running and restarts it if necessary
-fn host_wdg(hi: &Mutex<HostState>) {
+        if let Some(stderr) = child.stderr.take() {
+            let buffer = self.stderr.clone();
+            thread::spawn(move || capture_stderr(stderr, &buffer));
+        }
 
-    let tick_duration = Duration::from_secs(WDG_TICK_SECONDS);
+        self.diagnostics.started_at = Some(Utc::now());
+        self.child.replace(child);
+        self.status = HostStatus::Running;
+        self.next_restart = None;
 
-    loop {
-        trace!("watchdog tick");
+        Ok(())
+    }
 
-        // Tick body scoped to ensure lock is not held
-        {
-            let mut hi = do_lock!(hi);
+    /// Records an unexpected exit and decides when (or whether) to restart
+    fn schedule_restart(&mut self, exit_status: String) {
+
+        let now = Instant::now();
+        let window = Duration::from_secs(self.policy.window_secs);
 
-            let wait_res = if let Some(ref mut child) = hi.child {
-                child.try_wait()
+        self.child = None;
+        self.diagnostics.last_exit_at = Some(Utc::now());
+        self.diagnostics.last_exit_status = Some(exit_status);
+
+        self.recent_exits.push_back(now);
+        while let Some(exit) = self.recent_exits.front() {
+            if now.duration_since(*exit) > window {
+                self.recent_exits.pop_front();
             } else {
-                trace!("host has been stopped");
                 break;
+            }
+        }
+
+        if self.recent_exits.len() > self.policy.max_restarts as usize {
+            error!(
+                "child process exited {} times within {} seconds, giving up",
+                self.recent_exits.len(),
+                self.policy.window_secs,
+            );
+            self.status = HostStatus::Failed;
+            self.next_restart = None;
+            return;
+        }
+
+        let delay = self.policy.backoff(self.recent_exits.len());
+        debug!("restarting child process in {} ms", delay.as_millis());
+        self.status = HostStatus::BackingOff;
+        self.next_restart = Some(now + delay);
+    }
+}
+
+
+/// Copies lines from the child process's stderr into a bounded buffer
+///
+/// Returns once the child process closes its stderr (i.e. when it exits).
+fn capture_stderr(stderr: ChildStderr, buffer: &Mutex<VecDeque<String>>) {
+
+    for line in BufReader::new(stderr).lines() {
+        match line {
+            Ok(line) => {
+                let mut buffer = do_lock!(buffer);
+                if buffer.len() == STDERR_CAPACITY {
+                    buffer.pop_front();
+                }
+                buffer.push_back(line);
+            },
+            Err(err) => {
+                warn!("failed to read child process stderr: {}", err);
+                break;
+            },
+        }
+    }
+
+    trace!("child process stderr closed");
+}
+
+
+/// Interval at which a terminating child process is polled for exit
+const TERMINATE_POLL_MILLIS: u64 = 100;
+
+
+/// Asks the child process to exit, killing it if it does not do so in time
+///
+/// The child process is always reaped before returning, so that no zombie
+/// process is left behind.
+fn terminate(mut child: Child, shutdown: ShutdownPolicy) -> io::Result<()> {
+
+    let pid = child.id();
+
+    debug!("sending signal {} to child process {}", shutdown.signal, pid);
+    // The child has not been reaped yet, so its pid cannot have been reused
+    if unsafe { libc::kill(pid as libc::pid_t, shutdown.signal) } != 0 {
+        warn!("failed to signal child process: {}", io::Error::last_os_error());
+    }
+
+    let deadline = Instant::now() + shutdown.grace_period;
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait()? {
+            debug!("child process exited with status {}", status);
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(TERMINATE_POLL_MILLIS));
+    }
+
+    warn!("child process did not exit within grace period, killing it");
+    child.kill()?;
+    let status = child.wait()?;
+    debug!("child process exited with status {}", status);
+
+    Ok(())
+}
+
+
+/// Watchdog tick interval
+const WDG_TICK_SECONDS: u64 = 2;
+
+
+/// Performs a single watchdog tick, returning how long to wait until the next one
+fn host_wdg_tick(hi: &mut HostState, tick_duration: Duration) -> Duration {
+
+    match hi.status {
+
+        // Nothing to supervise
+        HostStatus::Stopped | HostStatus::Failed => (),
+
+        HostStatus::Running => {
+            let wait_res = match hi.child {
+                Some(ref mut child) => child.try_wait(),
+                None => Ok(None),
             };
 
             match wait_res {
@@ -47,85 +305,193 @@ fn host_wdg(hi: &Mutex<HostState>) {
                 // Child process no longer running
                 Ok(Some(status)) => {
                     warn!("child process exited unexpectedly with status {}", status);
-                    debug!("restarting child process");
-                    match hi.cmd.spawn() {
-                        Ok(child) => {
-                            hi.child.replace(child);
-                        },
-                        Err(err) => {
-                            error!("failed to restart child process: {}", err);
-                            break;
-                        }
-                    }
+                    hi.schedule_restart(status.to_string());
                 },
 
                 // Error checking status
                 Err(err) => {
                     error!("failed to check child process status: {}", err);
-                    break;
+                    hi.status = HostStatus::Failed;
                 },
             }
-        }
+        },
+
+        HostStatus::BackingOff => {
+            let due = match hi.next_restart {
+                Some(t) => t <= Instant::now(),
+                None => true,
+            };
+
+            if due {
+                debug!("restarting child process");
+                match hi.spawn() {
+                    Ok(()) => {
+                        hi.diagnostics.restart_count += 1;
+                    },
+                    Err(err) => {
+                        error!("failed to restart child process: {}", err);
+                        hi.schedule_restart(err.to_string());
+                    },
+                }
+            }
+        },
+    }
+
+    // Wake up early if a restart is due before the next tick
+    hi.next_restart
+        .map(|t| t.saturating_duration_since(Instant::now()))
+        .map_or(tick_duration, |d| d.min(tick_duration))
+}
 
-        thread::sleep(tick_duration);
+
+/// Periodically checks that the child process is still running and restarts it if necessary
+///
+/// Runs until a message is sent over (or the sender of) `cancel` is dropped.
+fn host_wdg(hi: &Mutex<HostState>, cancel: &mpsc::Receiver<()>) {
+
+    let tick_duration = Duration::from_secs(WDG_TICK_SECONDS);
+    let mut sleep_duration = Duration::from_secs(0);
+
+    while let Err(RecvTimeoutError::Timeout) = cancel.recv_timeout(sleep_duration) {
+
+        trace!("watchdog tick");
+        let mut hi = do_lock!(hi);
+        sleep_duration = host_wdg_tick(&mut hi, tick_duration);
     }
 
     debug!("watchdog exiting");
 }
 
 
+/// Handle to a running watchdog thread
+struct Watchdog {
+    cancel: Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl Watchdog {
+
+    /// Starts a watchdog thread supervising the given host
+    fn spawn(hi: Arc<Mutex<HostState>>) -> Self {
+
+        let (cancel, cancel_rx) = mpsc::channel();
+        let thread = thread::spawn(move || host_wdg(&hi, &cancel_rx));
+
+        Self { cancel, thread }
+    }
+
+    /// Signals the watchdog thread to exit and waits for it to do so
+    fn join(self) {
+
+        // Send only fails if the thread has already exited
+        let _ = self.cancel.send(());
+
+        if self.thread.join().is_err() {
+            error!("watchdog thread panicked");
+        }
+    }
+}
+
+
 /// Hosts and monitors a child process
 ///
 /// When the host is placed into the running state, the specified child process
 /// is started and checked periodically to ensure it is still running. If the
 /// process exits for any reason other than being terminated via the host (i.e.
-/// by calling `ProcHost::stop`), it is automatically restarted.
-pub struct ProcHost(Arc<Mutex<HostState>>);
+/// by calling `ProcHost::stop`), it is automatically restarted according to the
+/// host's `RestartPolicy`.
+///
+/// Each host owns at most one watchdog thread, which runs for as long as the
+/// host is in the running state and is joined when the host is stopped or
+/// dropped.
+pub struct ProcHost {
+    state: Arc<Mutex<HostState>>,
+    watchdog: Option<Watchdog>,
+}
 
 impl ProcHost {
 
     /// Creates a new `ProcHost`
     ///
-    /// Hosted process is started/restarted according to `cmd`
-    pub fn new(cmd: Command) -> Self {
+    /// Hosted process is started/restarted according to `cmd` and `policy`,
+    /// and stopped according to `shutdown`. The child's stderr is captured and
+    /// made available through `ProcHost::diagnostics`.
+    pub fn new(mut cmd: Command, policy: RestartPolicy, shutdown: ShutdownPolicy) -> Self {
+
+        cmd.stderr(Stdio::piped());
 
-        Self(Arc::new(Mutex::new(HostState {
+        let state = HostState {
             cmd,
             child: None,
-        })))
+            status: HostStatus::Stopped,
+            policy,
+            shutdown,
+            recent_exits: Default::default(),
+            next_restart: None,
+            diagnostics: Default::default(),
+            stderr: Default::default(),
+        };
+
+        Self {
+            state: Arc::new(Mutex::new(state)),
+            watchdog: None,
+        }
     }
 
     /// Starts the child process
     ///
-    /// If child is already running, no action is taken.
+    /// If the host has failed, it is reset and the child process is started
+    /// again. If child is already running, no action is taken.
     pub fn start(&mut self) -> Result<()> {
 
-        let wdg_hi = self.0.clone();
-        let mut hi = do_lock!(self.0);
+        {
+            let mut hi = do_lock!(self.state);
 
-        if hi.child.is_some() {
-            trace!("start called, but child process already running");
-        } else {
-            debug!("starting child process");
-            let child = hi.cmd.spawn()?;
-            hi.child.replace(child);
+            match hi.status {
+                HostStatus::Stopped | HostStatus::Failed => {
+                    debug!("starting child process");
+                    hi.recent_exits.clear();
+                    hi.spawn()?;
+                },
+                HostStatus::Running | HostStatus::BackingOff => {
+                    trace!("start called, but child process already running");
+                },
+            }
         }
 
-        thread::spawn(move || host_wdg(&wdg_hi));
+        if self.watchdog.is_none() {
+            trace!("starting watchdog");
+            self.watchdog = Some(Watchdog::spawn(self.state.clone()));
+        }
 
         Ok(())
     }
 
     /// Stops the child process
     ///
-    /// If child is not currently running, no action is taken.
+    /// The watchdog thread is joined, then the child process is terminated
+    /// according to this host's `ShutdownPolicy`, which may block for up to the
+    /// policy's grace period. If child is not currently running, no action is
+    /// taken.
     pub fn stop(&mut self) -> Result<()> {
 
-        let mut hi = do_lock!(self.0);
+        if let Some(watchdog) = self.watchdog.take() {
+            trace!("stopping watchdog");
+            watchdog.join();
+        }
+
+        // Lock is released before terminating, so that other users of the host
+        // are not blocked for the duration of the grace period
+        let (child, shutdown) = {
+            let mut hi = do_lock!(self.state);
+            hi.status = HostStatus::Stopped;
+            hi.next_restart = None;
+            (hi.child.take(), hi.shutdown)
+        };
 
-        if let Some(mut child) = hi.child.take() {
+        if let Some(child) = child {
             debug!("stopping child process");
-            child.kill()?;
+            terminate(child, shutdown)?;
         } else {
             trace!("stop called, but child process not running");
         }
@@ -133,12 +499,83 @@ impl ProcHost {
         Ok(())
     }
 
+    /// Resets a failed host, restarting its child process
+    ///
+    /// If the host has not failed, no action is taken.
+    pub fn reset(&mut self) -> Result<()> {
+
+        if self.status() == HostStatus::Failed {
+            debug!("resetting failed host");
+            self.start()?;
+        } else {
+            trace!("reset called, but host has not failed");
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the command and shutdown policy used for the child process
+    ///
+    /// Changes take effect the next time the child process is started, so
+    /// callers will usually want to stop the host first. Diagnostics and the
+    /// restart policy are preserved.
+    pub fn reconfigure(&mut self, mut cmd: Command, shutdown: ShutdownPolicy) {
+
+        cmd.stderr(Stdio::piped());
+
+        let mut hi = do_lock!(self.state);
+
+        hi.cmd = cmd;
+        hi.shutdown = shutdown;
+    }
+
     /// Returns whether this host is currently in the running state
+    ///
+    /// A host remains in the running state while its child process is waiting
+    /// to be restarted, and after it has failed (until it is stopped or reset).
     pub fn running(&self) -> bool {
 
-        let hi = do_lock!(self.0);
+        self.status() != HostStatus::Stopped
+    }
 
-        hi.child.is_some()
+    /// Gets the current lifecycle state of this host
+    pub fn status(&self) -> HostStatus {
+
+        let hi = do_lock!(self.state);
+
+        hi.status
+    }
+
+    /// Gets this host's restart policy
+    pub fn policy(&self) -> RestartPolicy {
+
+        let hi = do_lock!(self.state);
+
+        hi.policy.clone()
+    }
+
+    /// Replaces this host's restart policy
+    ///
+    /// The new policy takes effect the next time the child process exits.
+    pub fn set_policy(&mut self, policy: RestartPolicy) {
+
+        let mut hi = do_lock!(self.state);
+
+        hi.policy = policy;
+    }
+
+    /// Retrieves diagnostic information about the child process
+    pub fn diagnostics(&self) -> HostDiagnostics {
+
+        let hi = do_lock!(self.state);
+
+        let mut diagnostics = hi.diagnostics.clone();
+        diagnostics.status = hi.status;
+        diagnostics.stderr = do_lock!(hi.stderr).iter()
+            .cloned()
+            .collect();
+
+        diagnostics
     }
 }
 
@@ -150,3 +587,144 @@ impl Drop for ProcHost {
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs;
+    use std::process::Command;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+
+
+    /// Builds a long-running command which can be told apart from the children
+    /// of other tests by its argument
+    fn sleep_command(seconds: &str) -> Command {
+
+        let mut cmd = Command::new("sleep");
+        cmd.arg(seconds);
+        cmd
+    }
+
+    fn shutdown_policy() -> ShutdownPolicy {
+        ShutdownPolicy {
+            signal: libc::SIGTERM,
+            grace_period: Duration::from_secs(2),
+        }
+    }
+
+    /// Counts processes (including unreaped zombies) whose parent is this test
+    /// process and which were started with the given command line
+    fn count_children(cmdline: &[&str]) -> usize {
+
+        let own_pid = std::process::id().to_string();
+        let expected: Vec<u8> = cmdline.iter()
+            .flat_map(|arg| arg.bytes().chain(Some(0)))
+            .collect();
+
+        fs::read_dir("/proc").unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                let path = entry.path();
+                let stat = match fs::read_to_string(path.join("stat")) {
+                    Ok(stat) => stat,
+                    Err(_) => return false,
+                };
+
+                // The command name may contain spaces, so fields are counted
+                // from the closing parenthesis which ends it
+                let ppid = stat.rsplit(')').next()
+                    .and_then(|rest| rest.split_whitespace().nth(1));
+
+                ppid == Some(own_pid.as_str())
+                    && fs::read(path.join("cmdline")).ok().as_ref() == Some(&expected)
+            })
+            .count()
+    }
+
+    /// Counts watchdog threads supervising the host, each of which holds a
+    /// reference to the host's state
+    fn count_supervisors(host: &ProcHost) -> usize {
+
+        Arc::strong_count(&host.state) - 1
+    }
+
+    #[test]
+    fn repeated_cycles_leave_one_supervisor_and_child() {
+
+        let mut host = ProcHost::new(
+            sleep_command("60.1"),
+            RestartPolicy::default(),
+            shutdown_policy(),
+        );
+
+        for _ in 0..5 {
+
+            host.start().unwrap();
+            host.start().unwrap();
+            assert_eq!(host.status(), HostStatus::Running);
+            assert_eq!(count_supervisors(&host), 1);
+            assert!(host.watchdog.is_some());
+            assert_eq!(count_children(&["sleep", "60.1"]), 1);
+
+            host.stop().unwrap();
+            assert_eq!(host.status(), HostStatus::Stopped);
+            assert_eq!(count_supervisors(&host), 0);
+            assert_eq!(count_children(&["sleep", "60.1"]), 0);
+
+            // Reconfiguring while running takes effect on the next start
+            host.start().unwrap();
+            host.reconfigure(sleep_command("60.2"), shutdown_policy());
+            assert_eq!(count_supervisors(&host), 1);
+            assert_eq!(count_children(&["sleep", "60.1"]), 1);
+
+            host.stop().unwrap();
+            host.start().unwrap();
+            assert_eq!(count_supervisors(&host), 1);
+            assert_eq!(count_children(&["sleep", "60.1"]), 0);
+            assert_eq!(count_children(&["sleep", "60.2"]), 1);
+
+            host.stop().unwrap();
+            host.reconfigure(sleep_command("60.1"), shutdown_policy());
+        }
+
+        host.start().unwrap();
+        let state = Arc::downgrade(&host.state);
+        drop(host);
+
+        assert!(state.upgrade().is_none(), "watchdog outlived its host");
+        assert_eq!(count_children(&["sleep", "60.1"]), 0);
+        assert_eq!(count_children(&["sleep", "60.2"]), 0);
+    }
+
+    #[test]
+    fn restarted_child_is_not_duplicated() {
+
+        let mut host = ProcHost::new(
+            sleep_command("60.3"),
+            RestartPolicy { initial_backoff_ms: 10, max_backoff_ms: 10, ..Default::default() },
+            shutdown_policy(),
+        );
+        host.start().unwrap();
+
+        // Kill the child behind the host's back, then let the watchdog restart it
+        let pid = do_lock!(host.state).child.as_ref().unwrap().id();
+        unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) };
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while host.diagnostics().restart_count == 0 {
+            assert!(Instant::now() < deadline, "child process was not restarted");
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        assert_eq!(count_supervisors(&host), 1);
+        assert_eq!(count_children(&["sleep", "60.3"]), 1);
+
+        host.stop().unwrap();
+        assert_eq!(count_supervisors(&host), 0);
+        assert_eq!(count_children(&["sleep", "60.3"]), 0);
+    }
+}
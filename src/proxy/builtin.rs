@@ -0,0 +1,304 @@
+//! Built-in proxy backend
+//!
+//! Instead of configuring a separate server, lcsvc serves this host's HLS
+//! output directory at */stream/* and each camera's stream at */streams/{id}/*
+//! itself, streaming requests for the streams of remote cameras from the camera
+//! hosts. Cameras are looked up on every request, so there is nothing to
+//! reload. This standalone mode is meant for development and small setups
+//! where running Nginx is not worth the trouble.
+//!
+//! Unlike *hls.conf*, */stream/* is not served to anonymous clients. A camera
+//! host only serves it to its portal, whose requests are signed with the
+//! pairing key, so a camera host using this backend must be added to a portal
+//! which also uses it. A portal only serves its own */stream/* to signed-in
+//! users, who normally watch it at */streams/{id}/* instead.
+
+
+use std::env;
+use std::ffi::OsStr;
+use std::path::Path as FsPath;
+use std::sync::RwLock;
+
+use actix_files::{Files, NamedFile};
+use actix_web::{client as awc, HttpRequest, HttpResponse, Responder};
+use actix_web::http::{header, HeaderValue, Method, StatusCode};
+use actix_web::web::{self, Data, Path, ServiceConfig};
+use futures::{future, Future};
+use lazy_static::lazy_static;
+use log::{debug, trace, warn};
+use tera::Tera;
+
+use crate::cameras::{self, Camera};
+use crate::db::ConnectionPool;
+use crate::error::{Error, Result};
+use crate::pairing::{self, SignatureMiddleware};
+use crate::ui;
+use crate::users::{AuthenticatedUser, AuthenticationMiddleware};
+use crate::{do_read, do_write};
+
+use super::ProxyBackend;
+
+
+lazy_static! {
+    /// HLS output directory of this host, if the stream is enabled
+    static ref STREAM_DIR: RwLock<Option<String>> = RwLock::new(None);
+}
+
+
+/// Proxies streams from within lcsvc
+pub struct Builtin;
+
+impl ProxyBackend for Builtin {
+
+    fn configure_camera(&self, _camera: &Camera, _templates: &Tera) -> Result<()> {
+        Ok(())
+    }
+
+    fn clear_camera(&self, _id: i32) -> Result<()> {
+        Ok(())
+    }
+
+    fn configure_stream(&self, hls_dir: &str, _templates: &Tera) -> Result<()> {
+
+        trace!("serving HLS stream from {}", hls_dir);
+        *do_write!(STREAM_DIR) = Some(hls_dir.into());
+
+        Ok(())
+    }
+
+    fn clear_stream(&self) -> Result<()> {
+
+        *do_write!(STREAM_DIR) = None;
+
+        Ok(())
+    }
+
+    fn reload(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+
+/// Response to requests which are handled asynchronously
+type StreamFuture = Box<dyn Future<Item = HttpResponse, Error = Error>>;
+
+
+thread_local! {
+    /// Client used to forward requests to camera hosts
+    ///
+    /// Actix's client cannot be shared between threads, so each worker has its
+    /// own.
+    static CLIENT: awc::Client = awc::Client::default();
+}
+
+
+/// Content type of a file in the HLS output directory, where it cannot be
+/// guessed correctly from the extension
+fn stream_content_type(file: &str) -> Option<&'static str> {
+
+    match FsPath::new(file).extension().and_then(OsStr::to_str) {
+        Some("m3u8") => Some("application/vnd.apple.mpegurl"),
+        Some("ts") => Some("video/mp2t"),
+        _ => None,
+    }
+}
+
+
+/// Adds the headers which *hls.conf* adds to stream responses
+fn add_stream_headers(response: &mut HttpResponse) {
+
+    let headers = response.headers_mut();
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+    headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, HeaderValue::from_static("Content-Length"));
+}
+
+
+/// Opens a file in this host's HLS output directory
+fn open_stream_file(file: &str) -> Result<NamedFile> {
+
+    // Path segments cannot contain slashes, but may refer to parent directories
+    if file.starts_with('.') {
+        return Error::web(StatusCode::NOT_FOUND, "file not found");
+    }
+
+    let dir = match do_read!(STREAM_DIR).clone() {
+        Some(dir) => dir,
+        None => return Error::web(StatusCode::NOT_FOUND, "stream is not enabled"),
+    };
+
+    Ok(NamedFile::open(FsPath::new(&dir).join(file))?)
+}
+
+
+/// Serves a file from this host's HLS output directory
+///
+/// Range and conditional requests are handled by `NamedFile`.
+fn serve_stream_file(req: &HttpRequest, file: &str) -> Result<HttpResponse> {
+
+    let mut response = open_stream_file(file)?
+        .respond_to(req)
+        .map_err(|err| {
+            warn!("failed to serve stream file {}: {}", file, err);
+            Error::Web(StatusCode::INTERNAL_SERVER_ERROR, "failed to serve stream file")
+        })?;
+
+    if let Some(content_type) = stream_content_type(file) {
+        response.headers_mut()
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    }
+    add_stream_headers(&mut response);
+
+    Ok(response)
+}
+
+
+/// Headers of a camera host's response which are passed on to the client
+const FORWARDED_HEADERS: &[header::HeaderName] = &[
+    header::ACCEPT_RANGES,
+    header::CONTENT_RANGE,
+    header::CONTENT_TYPE,
+    header::ETAG,
+    header::LAST_MODIFIED,
+];
+
+
+/// Forwards a request for a file of a remote camera's stream to the camera
+/// host, streaming the response back to the client
+fn forward_stream_request(req: &HttpRequest, camera: &Camera, file: &str) -> StreamFuture {
+
+    let key = match camera.pairing_key {
+        Some(ref key) => key,
+        None => return Box::new(future::err(Error::Web(StatusCode::CONFLICT, "camera is not paired"))),
+    };
+
+    let path = format!("/stream/{}", file);
+    let headers = match pairing::signature_headers(key, "GET", &path, &[]) {
+        Ok(headers) => headers,
+        Err(err) => return Box::new(future::err(err)),
+    };
+
+    let url = format!("http://{}{}", camera.address, path);
+    let mut request = CLIENT.with(|client| client.get(&url));
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+
+    // Let the camera host deal with partial and conditional requests
+    for name in &[header::RANGE, header::IF_NONE_MATCH, header::IF_MODIFIED_SINCE] {
+        if let Some(value) = req.headers().get(name) {
+            request = request.header(name.clone(), value.clone());
+        }
+    }
+
+    let address = camera.address.clone();
+    let response = request.send()
+        .map_err(move |err| {
+            warn!("failed to forward stream request to {}: {}", address, err);
+            Error::Web(StatusCode::BAD_GATEWAY, "camera is unreachable")
+        })
+        .map(|remote| {
+            let mut builder = HttpResponse::build(remote.status());
+            for name in FORWARDED_HEADERS {
+                if let Some(value) = remote.headers().get(name) {
+                    builder.header(name.clone(), value.clone());
+                }
+            }
+
+            let mut response = builder.streaming(remote);
+            add_stream_headers(&mut response);
+            response
+        });
+
+    Box::new(response)
+}
+
+
+/// Serves a file from this host's HLS output directory
+fn get_stream_file(req: HttpRequest, path: Path<(String,)>) -> Result<HttpResponse> {
+
+    serve_stream_file(&req, &path.0)
+}
+
+
+/// Answers CORS preflight requests for stream files
+fn options_stream_file() -> HttpResponse {
+
+    HttpResponse::NoContent()
+        .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+        .header(header::ACCESS_CONTROL_MAX_AGE, "1728000")
+        .finish()
+}
+
+
+/// Retrieves a camera whose stream the given user may watch
+fn get_streaming_camera(id: i32, pool: &ConnectionPool, user: &AuthenticatedUser) -> Result<Camera> {
+
+    let conn = pool.get()?;
+    user.require_view(id, &conn)?;
+
+    let camera = cameras::get(id, &conn)?;
+    if !camera.enabled {
+        return Error::web(StatusCode::NOT_FOUND, "camera is not enabled");
+    }
+
+    Ok(camera)
+}
+
+
+/// Serves a file of the specified camera's stream
+fn get_camera_stream_file(
+    req: HttpRequest,
+    pool: Data<ConnectionPool>,
+    user: AuthenticatedUser,
+    path: Path<(i32, String)>,
+) -> StreamFuture
+{
+    let (id, ref file) = *path;
+
+    let camera = match get_streaming_camera(id, &pool, &user) {
+        Ok(camera) => camera,
+        Err(err) => return Box::new(future::err(err)),
+    };
+
+    if camera.local {
+        return Box::new(future::result(serve_stream_file(&req, file)));
+    }
+
+    debug!("forwarding request for {} to camera {}", file, id);
+    forward_stream_request(&req, &camera, file)
+}
+
+
+/// Configures an Actix service to serve streams
+///
+/// Must be registered before the UI, whose scope matches every path.
+///
+/// If the LC_STATIC environment variable is set, static assets are also served
+/// from the directory it names, which Nginx would otherwise serve.
+pub fn configure(service: &mut ServiceConfig) {
+
+    let stream = web::resource("/stream/{file}")
+        .route(web::get().to(get_stream_file))
+        .route(web::head().to(get_stream_file))
+        .route(web::method(Method::OPTIONS).to(options_stream_file));
+
+    if cfg!(feature = "stream-api") {
+        service.service(stream.wrap(SignatureMiddleware));
+    } else {
+        service.service(stream.wrap(AuthenticationMiddleware::reject()));
+    }
+
+    if cfg!(feature = "portal") {
+        service.service(
+            web::scope("/streams")
+                .route("/{id}/stream.key", web::get().to(ui::camera_key))
+                .route("/{id}/{file}",     web::get().to_async(get_camera_stream_file))
+                .wrap(AuthenticationMiddleware::reject())
+        );
+    }
+
+    if let Ok(static_dir) = env::var("LC_STATIC") {
+        service.service(Files::new("/static", static_dir));
+    }
+}
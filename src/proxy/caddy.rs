@@ -0,0 +1,313 @@
+//! Caddy proxy backend
+//!
+//! LunaCam's routes are kept in a single route (tagged with the ID *lunacam*)
+//! of one of Caddy's HTTP servers, which is replaced through Caddy's admin API
+//! on every reload. The rest of Caddy's configuration, such as the route which
+//! forwards application requests to lcsvc, is left alone.
+//!
+//! Caddy forgets routes pushed through its admin API when it restarts, and may
+//! not be reachable at all when lcsvc starts. Failing to push the routes is
+//! therefore only logged, and the routes are pushed again whenever the proxy is
+//! refreshed and finds them missing.
+//!
+//! The following environment variables control this backend:
+//!
+//! * LC_CADDY_ADMIN: base URL of Caddy's admin API. Defaults to
+//!   *http://localhost:2019*.
+//! * LC_CADDY_SERVER: name of the HTTP server to which LunaCam's routes are
+//!   added. Defaults to *lunacam*.
+
+
+use std::collections::BTreeMap;
+use std::env;
+use std::sync::Mutex;
+
+use log::{debug, info, trace, warn};
+use reqwest::{Client, StatusCode};
+use serde_json::{json, Value};
+use tera::Tera;
+
+use crate::cameras::Camera;
+use crate::do_lock;
+use crate::error::Result;
+
+use super::{listen_addr, ProxyBackend};
+
+
+const DEFAULT_ADMIN_URL: &str = "http://localhost:2019";
+const DEFAULT_SERVER: &str = "lunacam";
+
+/// Caddy object ID of the route containing all of LunaCam's routes
+const ROUTE_ID: &str = "lunacam";
+
+
+/// Routes which are pushed to Caddy on the next reload
+#[derive(Default)]
+struct Routes {
+    cameras: BTreeMap<i32, Vec<Value>>,
+    stream: Vec<Value>,
+
+    /// Whether the routes were pushed to Caddy successfully
+    synced: bool,
+}
+
+
+/// Routes requests for a camera's key to lcsvc
+fn key_route(id: i32) -> Value {
+
+    json!({
+        "match": [{ "path": [format!("/streams/{}/stream.key", id)] }],
+        "handle": [
+            { "handler": "rewrite", "uri": format!("/cameras/{}/key", id) },
+            { "handler": "reverse_proxy", "upstreams": [{ "dial": listen_addr() }] },
+        ],
+        "terminal": true,
+    })
+}
+
+
+/// Rewrites requests for a camera's stream to the camera host's */stream/*
+fn stream_rewrite(id: i32) -> Value {
+
+    json!({
+        "handler": "rewrite",
+        "uri_substring": [{
+            "find": format!("/streams/{}/", id),
+            "replace": "/stream/",
+            "limit": 1,
+        }],
+    })
+}
+
+
+/// Routes for a camera's stream
+fn camera_routes(camera: &Camera) -> Vec<Value> {
+
+    let stream_match = json!([{ "path": [format!("/streams/{}/*", camera.id)] }]);
+
+    // A local camera's stream is served by the routes for this host's HLS
+    // output directory, which follow
+    let stream_route = if camera.local {
+        json!({
+            "match": stream_match,
+            "handle": [stream_rewrite(camera.id)],
+        })
+    } else {
+        let dial = if camera.address.contains(':') {
+            camera.address.clone()
+        } else {
+            format!("{}:80", camera.address)
+        };
+        json!({
+            "match": stream_match,
+            "handle": [
+                stream_rewrite(camera.id),
+                { "handler": "reverse_proxy", "upstreams": [{ "dial": dial }] },
+            ],
+            "terminal": true,
+        })
+    };
+
+    vec![key_route(camera.id), stream_route]
+}
+
+
+/// Routes which serve this host's HLS output directory, with the same headers
+/// as *hls.conf*
+fn stream_routes(hls_dir: &str) -> Vec<Value> {
+
+    let content_type = |pattern: &str, mime: &str| json!({
+        "match": [{ "path": [format!("/stream/{}", pattern)] }],
+        "handle": [{ "handler": "headers", "response": { "set": { "Content-Type": [mime] } } }],
+    });
+
+    vec![
+        json!({
+            "match": [{ "path": ["/stream/*"], "method": ["OPTIONS"] }],
+            "handle": [
+                {
+                    "handler": "headers",
+                    "response": {
+                        "set": {
+                            "Access-Control-Allow-Origin": ["*"],
+                            "Access-Control-Max-Age": ["1728000"],
+                        },
+                    },
+                },
+                { "handler": "static_response", "status_code": 204 },
+            ],
+            "terminal": true,
+        }),
+        content_type("*.m3u8", "application/vnd.apple.mpegurl"),
+        content_type("*.ts", "video/mp2t"),
+        json!({
+            "match": [{ "path": ["/stream/*"] }],
+            "handle": [
+                {
+                    "handler": "headers",
+                    "response": {
+                        "set": {
+                            "Cache-Control": ["no-cache"],
+                            "Access-Control-Allow-Origin": ["*"],
+                            "Access-Control-Expose-Headers": ["Content-Length"],
+                        },
+                    },
+                },
+                { "handler": "rewrite", "strip_path_prefix": "/stream" },
+                { "handler": "file_server", "root": hls_dir },
+            ],
+            "terminal": true,
+        }),
+    ]
+}
+
+
+/// Proxies streams using Caddy
+pub struct Caddy {
+    admin_url: String,
+    server: String,
+    client: Client,
+    routes: Mutex<Routes>,
+}
+
+impl Caddy {
+
+    pub fn new() -> Self {
+
+        let admin_url = env::var("LC_CADDY_ADMIN")
+            .unwrap_or_else(|_| DEFAULT_ADMIN_URL.into());
+        let server = env::var("LC_CADDY_SERVER")
+            .unwrap_or_else(|_| DEFAULT_SERVER.into());
+
+        Self {
+            admin_url: admin_url.trim_end_matches('/').into(),
+            server,
+            client: Client::new(),
+            routes: Mutex::new(Routes::default()),
+        }
+    }
+
+    /// Replaces LunaCam's route in Caddy with the staged routes
+    fn push(&self) -> Result<()> {
+
+        // Camera routes rewrite requests for local streams, so they must come
+        // before the routes which serve the HLS output directory
+        let routes: Vec<Value> = {
+            let staged = do_lock!(self.routes);
+            staged.cameras.values()
+                .flatten()
+                .chain(staged.stream.iter())
+                .cloned()
+                .collect()
+        };
+        let route = json!({
+            "@id": ROUTE_ID,
+            "handle": [{ "handler": "subroute", "routes": routes }],
+        });
+
+        debug!("replacing Caddy routes");
+        let res = self.client.patch(&format!("{}/id/{}", self.admin_url, ROUTE_ID))
+            .json(&route)
+            .send()?;
+
+        // The route does not exist until the first reload after Caddy starts.
+        // It is inserted first, so that it takes precedence over the route to
+        // lcsvc.
+        if res.status() == StatusCode::NOT_FOUND {
+            debug!("adding LunaCam route to Caddy server {}", self.server);
+            let url = format!(
+                "{}/config/apps/http/servers/{}/routes/0",
+                self.admin_url,
+                self.server,
+            );
+            self.client.put(&url)
+                .json(&route)
+                .send()?
+                .error_for_status()?;
+        } else {
+            res.error_for_status()?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether Caddy still has LunaCam's route, e.g. after a restart
+    fn has_route(&self) -> Result<bool> {
+
+        let res = self.client.get(&format!("{}/id/{}", self.admin_url, ROUTE_ID))
+            .send()?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        res.error_for_status()?;
+
+        Ok(true)
+    }
+}
+
+impl Default for Caddy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProxyBackend for Caddy {
+
+    fn configure_camera(&self, camera: &Camera, _templates: &Tera) -> Result<()> {
+
+        trace!("staging Caddy routes for camera {}", camera.id);
+        do_lock!(self.routes).cameras.insert(camera.id, camera_routes(camera));
+
+        Ok(())
+    }
+
+    fn clear_camera(&self, id: i32) -> Result<()> {
+
+        do_lock!(self.routes).cameras.remove(&id);
+
+        Ok(())
+    }
+
+    fn configure_stream(&self, hls_dir: &str, _templates: &Tera) -> Result<()> {
+
+        trace!("staging Caddy routes for HLS stream");
+        do_lock!(self.routes).stream = stream_routes(hls_dir);
+
+        Ok(())
+    }
+
+    fn clear_stream(&self) -> Result<()> {
+
+        do_lock!(self.routes).stream.clear();
+
+        Ok(())
+    }
+
+    fn reload(&self) -> Result<()> {
+
+        let synced = match self.push() {
+            Ok(()) => true,
+            Err(err) => {
+                warn!("failed to update Caddy routes, will retry: {}", err);
+                false
+            },
+        };
+        do_lock!(self.routes).synced = synced;
+
+        Ok(())
+    }
+
+    fn refresh(&self) -> Result<()> {
+
+        if do_lock!(self.routes).synced && self.has_route()? {
+            return Ok(());
+        }
+
+        debug!("pushing LunaCam routes to Caddy again");
+        self.push()?;
+        do_lock!(self.routes).synced = true;
+        info!("restored LunaCam routes in Caddy");
+
+        Ok(())
+    }
+}
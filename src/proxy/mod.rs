@@ -0,0 +1,223 @@
+//! Manages the server behind which the LunaCam application and HLS streams are
+//! reverse-proxied
+//!
+//! The server is chosen using the LC_PROXY environment variable:
+//!
+//! * *nginx* (default): configuration files are written to a runtime directory
+//!   included by Nginx, which is reloaded using sudo and systemctl.
+//! * *caddy*: routes are pushed to Caddy's admin API (see the `caddy` module),
+//!   and pushed again by the `Refresher` if Caddy loses them.
+//! * *builtin*: lcsvc serves HLS files and reverse-proxies the streams of
+//!   remote cameras itself, so that no other server or sudo is needed.
+
+
+use std::env;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use log::{debug, error, info, trace, warn};
+use tera::Tera;
+
+use crate::cameras::Camera;
+use crate::error::Result;
+
+
+pub mod builtin;
+pub mod caddy;
+pub mod nginx;
+
+
+/// Default address on which lcsvc listens for HTTP requests
+///
+/// May be overridden using the LC_LISTEN environment variable, e.g. to run
+/// several instances on one machine.
+const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:9351";
+
+/// Interval at which the proxy is refreshed
+const REFRESH_SECONDS: u64 = 30;
+
+
+/// Address on which lcsvc listens for HTTP requests, which the proxy forwards
+/// application requests to
+pub fn listen_addr() -> String {
+
+    env::var("LC_LISTEN")
+        .unwrap_or_else(|_| DEFAULT_LISTEN_ADDR.into())
+}
+
+
+/// Server which exposes camera streams to clients
+///
+/// Changes made by the `configure_*` and `clear_*` methods need not take effect
+/// until `reload` is called.
+pub trait ProxyBackend: Send + Sync {
+
+    /// Exposes the specified camera's stream at */streams/{id}/*, along with
+    /// its key at */streams/{id}/stream.key*
+    fn configure_camera(&self, camera: &Camera, templates: &Tera) -> Result<()>;
+
+    /// Stops exposing the specified camera's stream
+    fn clear_camera(&self, id: i32) -> Result<()>;
+
+    /// Exposes this host's HLS output directory at */stream/*
+    fn configure_stream(&self, hls_dir: &str, templates: &Tera) -> Result<()>;
+
+    /// Stops exposing this host's HLS output directory
+    fn clear_stream(&self) -> Result<()>;
+
+    /// Applies configuration changes
+    fn reload(&self) -> Result<()>;
+
+    /// Applies the current configuration again if the server has lost it, e.g.
+    /// because it was restarted or was unreachable when last reloaded
+    fn refresh(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+
+/// Identifies which implementation of `ProxyBackend` is used
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BackendKind {
+    Nginx,
+    Caddy,
+    Builtin,
+}
+
+
+/// Determines which backend to use from the environment
+fn backend_kind() -> BackendKind {
+
+    match env::var("LC_PROXY").as_ref().map(String::as_str) {
+        Err(_) | Ok("nginx") => BackendKind::Nginx,
+        Ok("caddy") => BackendKind::Caddy,
+        Ok("builtin") => BackendKind::Builtin,
+        Ok(other) => {
+            warn!("unknown proxy backend {}, using nginx", other);
+            BackendKind::Nginx
+        },
+    }
+}
+
+
+lazy_static! {
+    static ref KIND: BackendKind = backend_kind();
+
+    static ref BACKEND: Box<dyn ProxyBackend> = {
+        debug!("using {:?} proxy backend", *KIND);
+        match *KIND {
+            BackendKind::Nginx => Box::new(nginx::Nginx),
+            BackendKind::Caddy => Box::new(caddy::Caddy::new()),
+            BackendKind::Builtin => Box::new(builtin::Builtin),
+        }
+    };
+}
+
+
+/// Identifies the proxy backend in use
+pub fn kind() -> BackendKind {
+    *KIND
+}
+
+
+/// Exposes the specified camera's stream
+pub fn configure_camera(camera: &Camera, templates: &Tera) -> Result<()> {
+    BACKEND.configure_camera(camera, templates)
+}
+
+
+/// Stops exposing the specified camera's stream
+pub fn clear_camera(id: i32) -> Result<()> {
+    BACKEND.clear_camera(id)
+}
+
+
+/// Exposes this host's HLS output directory
+pub fn configure_stream(hls_dir: &str, templates: &Tera) -> Result<()> {
+    BACKEND.configure_stream(hls_dir, templates)
+}
+
+
+/// Stops exposing this host's HLS output directory
+pub fn clear_stream() -> Result<()> {
+    BACKEND.clear_stream()
+}
+
+
+/// Reloads server configuration
+pub fn reload() -> Result<()> {
+
+    debug!("reloading proxy");
+    BACKEND.reload()
+}
+
+
+/// Repeatedly refreshes the proxy until a message is sent over (or the sender
+/// of) `cancel` is dropped
+fn refresh_loop(cancel: &Receiver<()>) {
+
+    let interval = Duration::from_secs(REFRESH_SECONDS);
+    let mut failing = false;
+
+    while let Err(RecvTimeoutError::Timeout) = cancel.recv_timeout(interval) {
+        trace!("refreshing proxy");
+        match BACKEND.refresh() {
+            Ok(()) if failing => {
+                info!("proxy is reachable again");
+                failing = false;
+            },
+            Ok(()) => {},
+            // Only reported once, as the proxy may be down for a while
+            Err(err) if !failing => {
+                warn!("failed to refresh proxy: {}", err);
+                failing = true;
+            },
+            Err(err) => debug!("failed to refresh proxy: {}", err),
+        }
+    }
+
+    debug!("proxy refresher exiting");
+}
+
+
+/// Handle to the background task which keeps the proxy's configuration applied
+/// (see `ProxyBackend::refresh`)
+pub struct Refresher {
+    cancel: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Refresher {
+
+    /// Starts refreshing the proxy in the background
+    pub fn start() -> Self {
+
+        debug!("starting proxy refresher");
+
+        let (cancel, cancel_rx) = mpsc::channel();
+        let thread = thread::spawn(move || refresh_loop(&cancel_rx));
+
+        Self {
+            cancel: Some(cancel),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Refresher {
+    fn drop(&mut self) {
+
+        // Send only fails if the thread has already exited
+        if let Some(cancel) = self.cancel.take() {
+            let _ = cancel.send(());
+        }
+
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("proxy refresher thread panicked");
+            }
+        }
+    }
+}
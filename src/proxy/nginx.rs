@@ -0,0 +1,125 @@
+//! Nginx proxy backend
+//!
+//! Configuration files rendered from the *proxy.conf* and *hls.conf* templates
+//! are written to a runtime directory which Nginx includes, and Nginx is
+//! reloaded using sudo and systemctl.
+
+
+use std::env;
+use std::fs;
+use std::process::{Command, Stdio};
+
+use log::{debug, trace, warn};
+use tera::{Context, Tera};
+
+use crate::cameras::Camera;
+use crate::error::Result;
+
+use super::ProxyBackend;
+
+
+/// Retrieves the proxy configuration directory, creating it if it does not yet
+/// exist.
+pub fn config_dir() -> Result<String> {
+
+    trace!("identifying proxy config directory");
+
+    let rt_dir = env::var("RUNTIME_DIRECTORY")?;
+    let cfg_dir = format!("{}/nginx", rt_dir);
+
+    if fs::metadata(&cfg_dir).is_err() {
+        debug!("creating proxy config directory {}", cfg_dir);
+        fs::create_dir_all(&cfg_dir)?;
+    }
+
+    Ok(cfg_dir)
+}
+
+
+/// Gets path of the proxy configuration file for the specified camera
+fn camera_config_path(id: i32) -> Result<String> {
+
+    let cfg_dir = config_dir()?;
+    let path = format!("{}/proxy-{}.conf", cfg_dir, id);
+
+    Ok(path)
+}
+
+
+/// Gets the location of the HLS stream's proxy configuration file
+fn stream_config_path() -> Result<String> {
+
+    let cfg_dir = config_dir()?;
+    let path = format!("{}/hls.conf", cfg_dir);
+
+    Ok(path)
+}
+
+
+/// Removes a configuration file, if it exists
+fn remove_config(path: &str) -> Result<()> {
+
+    if fs::metadata(path).is_ok() {
+        debug!("removing proxy configuration file {}", path);
+        fs::remove_file(path)?;
+    }
+
+    Ok(())
+}
+
+
+/// Proxies streams using Nginx
+pub struct Nginx;
+
+impl ProxyBackend for Nginx {
+
+    fn configure_camera(&self, camera: &Camera, templates: &Tera) -> Result<()> {
+
+        let mut context = Context::new();
+        context.insert("camera", camera);
+        let config = templates.render("proxy.conf", &context)?;
+
+        debug!("writing proxy configuration for camera {}", camera.id);
+        fs::write(camera_config_path(camera.id)?, config)?;
+
+        Ok(())
+    }
+
+    fn clear_camera(&self, id: i32) -> Result<()> {
+
+        remove_config(&camera_config_path(id)?)
+    }
+
+    fn configure_stream(&self, hls_dir: &str, templates: &Tera) -> Result<()> {
+
+        let mut context = Context::new();
+        context.insert("hls_dir", hls_dir);
+        let config = templates.render("hls.conf", &context)?;
+
+        debug!("writing proxy configuration for HLS stream");
+        fs::write(stream_config_path()?, config)?;
+
+        Ok(())
+    }
+
+    fn clear_stream(&self) -> Result<()> {
+
+        remove_config(&stream_config_path()?)
+    }
+
+    fn reload(&self) -> Result<()> {
+
+        let status = Command::new("/usr/bin/sudo")
+            .args(&["-n", "/usr/bin/systemctl", "reload", "nginx.service"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()?;
+
+        if !status.success() {
+            warn!("failed to reload nginx");
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,973 @@
+//! Stream recording
+//!
+//! While recording is active, a background worker copies segments of the live
+//! HLS stream to persistent storage under STATE_DIRECTORY before the
+//! transcoder deletes them. Each contiguous period of recording is stored as a
+//! `Recording`, consisting of a directory of (still encrypted) segments and an
+//! index describing them.
+
+
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use actix_web::HttpResponse;
+use actix_web::http::{header, StatusCode};
+use bytes::Bytes;
+use chrono::{Local, NaiveDateTime, NaiveTime, Utc};
+use diesel::prelude::*;
+use futures::{Async, Poll, Stream};
+use log::{debug, error, info, trace, warn};
+use openssl::symm::{self, Cipher};
+use serde::{Deserialize, Serialize};
+
+use crate::db::{ConnectionPool, PooledConnection};
+use crate::db::schema::{motion_events, recordings};
+use crate::do_lock;
+use crate::error::{Error, Result};
+
+
+/// Interval at which the recorder checks for new segments
+const TICK_SECONDS: u64 = 1;
+
+/// Interval at which retention limits are enforced
+const RETENTION_INTERVAL_SECONDS: u64 = 60;
+
+/// Size of the chunks in which relayed recordings are read, in bytes
+const RELAY_CHUNK_BYTES: usize = 64 * 1024;
+
+
+//#region Configuration
+
+/// Determines when the stream is recorded
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RecordingMode {
+
+    /// Stream is never recorded
+    #[default]
+    Off,
+
+    /// Stream is always recorded
+    Continuous,
+
+    /// Stream is recorded while motion is detected
+    Motion,
+
+    /// Stream is recorded during the configured schedule windows
+    Schedule,
+}
+
+/// Daily period of time (in the host's local time zone) during which the
+/// stream is recorded
+///
+/// Windows for which `end` is earlier than `start` span midnight.
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Deserialize, Serialize)]
+pub struct ScheduleWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl ScheduleWindow {
+
+    /// Returns whether the given time falls within this window
+    pub fn contains(&self, time: NaiveTime) -> bool {
+
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+
+/// Recording settings
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RecordingConfig {
+
+    /// Determines when the stream is recorded
+    pub mode: RecordingMode,
+
+    /// Windows during which the stream is recorded in `Schedule` mode
+    pub schedule: Vec<ScheduleWindow>,
+
+    /// Time to keep recording after motion has ended, in seconds
+    pub post_roll_secs: u64,
+
+    /// Maximum length of a single recording, in minutes
+    pub max_clip_minutes: u64,
+
+    /// Recordings older than this many hours are deleted
+    pub max_age_hours: Option<u64>,
+
+    /// Oldest recordings are deleted once recordings use more than this many
+    /// megabytes of storage
+    pub max_disk_mb: Option<u64>,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        Self {
+            mode: Default::default(),
+            schedule: Vec::new(),
+            post_roll_secs: 10,
+            max_clip_minutes: 15,
+            max_age_hours: Some(24 * 7),
+            max_disk_mb: Some(4096),
+        }
+    }
+}
+
+impl RecordingConfig {
+
+    /// Checks that this configuration is usable
+    pub fn validate(&self) -> Result<()> {
+
+        if self.max_clip_minutes == 0 {
+            return Error::web(StatusCode::BAD_REQUEST, "maxClipMinutes must be positive");
+        }
+
+        if self.max_age_hours == Some(0) || self.max_disk_mb == Some(0) {
+            return Error::web(StatusCode::BAD_REQUEST, "retention limits must be positive");
+        }
+
+        Ok(())
+    }
+}
+
+
+/// Gets the directory under which recordings are stored
+fn recordings_dir() -> Result<String> {
+
+    let state_dir = env::var("STATE_DIRECTORY")?;
+
+    Ok(format!("{}/recordings", state_dir))
+}
+
+
+/// Gets the directory in which the specified recording is stored
+pub fn recording_dir(id: i32) -> Result<String> {
+
+    Ok(format!("{}/{}", recordings_dir()?, id))
+}
+
+//#endregion
+
+
+//#region Recordings
+
+/// Contiguous period of recorded video
+#[derive(Deserialize, Serialize)]
+#[derive(Identifiable, Queryable)]
+#[serde(rename_all = "camelCase")]
+#[table_name = "recordings"]
+pub struct Recording {
+    pub id: i32,
+    pub started_at: NaiveDateTime,
+    pub ended_at: Option<NaiveDateTime>,
+    pub size_bytes: i64,
+}
+
+
+#[derive(Insertable)]
+#[table_name = "recordings"]
+struct NewRecording {
+    started_at: NaiveDateTime,
+}
+
+
+/// Segment of a recording
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Segment {
+
+    /// Name of the segment's file within the recording directory
+    pub file: String,
+
+    /// Approximate time at which the segment starts
+    pub started_at: NaiveDateTime,
+
+    /// Duration of the segment, in seconds
+    pub duration: f64,
+
+    /// AES-128 initialization vector (as an integer), if the segment is encrypted
+    pub iv: Option<u64>,
+
+    /// Whether the segment is discontinuous with the previous segment
+    #[serde(default)]
+    pub discontinuity: bool,
+}
+
+impl Segment {
+
+    /// Gets the approximate time at which the segment ends
+    pub fn ended_at(&self) -> NaiveDateTime {
+
+        self.started_at + chrono::Duration::milliseconds((self.duration * 1000.0) as i64)
+    }
+}
+
+
+/// Reads the index of segments making up the specified recording
+pub fn read_segments(id: i32) -> Result<Vec<Segment>> {
+
+    let path = format!("{}/index.json", recording_dir(id)?);
+
+    match fs::read(&path) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+
+/// Reads a single (encrypted) segment of the specified recording
+pub fn read_segment(id: i32, file: &str) -> Result<Vec<u8>> {
+
+    // Only serve files listed in the index
+    if !read_segments(id)?.iter().any(|s| s.file == file) {
+        return Error::web(StatusCode::NOT_FOUND, "Segment not found");
+    }
+
+    let data = fs::read(format!("{}/{}", recording_dir(id)?, file))?;
+
+    Ok(data)
+}
+
+
+/// Writes the index of segments making up the specified recording
+fn write_segments(id: i32, segments: &[Segment]) -> Result<()> {
+
+    let dir = recording_dir(id)?;
+    let tmp_path = format!("{}/index.json.tmp", dir);
+
+    // Replace atomically so readers never observe a partial index
+    fs::write(&tmp_path, serde_json::to_vec(segments)?)?;
+    fs::rename(&tmp_path, format!("{}/index.json", dir))?;
+
+    Ok(())
+}
+
+
+/// Retrieves all recordings, newest first
+pub fn list(conn: &PooledConnection) -> Result<Vec<Recording>> {
+
+    let recordings = recordings::table
+        .order(recordings::started_at.desc())
+        .load(conn)?;
+
+    Ok(recordings)
+}
+
+
+/// Retrieves the specified recording
+pub fn find(id: i32, conn: &PooledConnection) -> Result<Recording> {
+
+    let recording = recordings::table.find(id)
+        .get_result(conn)?;
+
+    Ok(recording)
+}
+
+
+/// Deletes a finished recording along with its files
+pub fn delete(id: i32, conn: &PooledConnection) -> Result<()> {
+
+    let recording: Recording = recordings::table.find(id)
+        .get_result(conn)?;
+
+    if recording.ended_at.is_none() {
+        return Error::web(StatusCode::CONFLICT, "Recording is still in progress");
+    }
+
+    remove(&recording, conn)
+}
+
+
+/// Deletes a recording's files and database row
+fn remove(recording: &Recording, conn: &PooledConnection) -> Result<()> {
+
+    debug!("deleting recording {}", recording.id);
+
+    let dir = recording_dir(recording.id)?;
+    if fs::metadata(&dir).is_ok() {
+        fs::remove_dir_all(&dir)?;
+    }
+
+    diesel::delete(recording)
+        .execute(conn)?;
+
+    Ok(())
+}
+
+
+/// Recording read as a single unencrypted MPEG transport stream
+///
+/// Segments are read and decrypted one at a time as the stream is polled, so
+/// only a single segment is held in memory.
+pub struct Export {
+    dir: String,
+    key: [u8; 16],
+    segments: std::vec::IntoIter<Segment>,
+}
+
+impl Stream for Export {
+    type Item = Bytes;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, Error> {
+
+        let segment = match self.segments.next() {
+            Some(segment) => segment,
+            None => return Ok(Async::Ready(None)),
+        };
+
+        let data = fs::read(format!("{}/{}", self.dir, segment.file))?;
+        let data = match segment.iv {
+            Some(iv) => symm::decrypt(
+                Cipher::aes_128_cbc(),
+                &self.key,
+                Some(&u128::from(iv).to_be_bytes()),
+                &data,
+            )?,
+            None => data,
+        };
+
+        Ok(Async::Ready(Some(Bytes::from(data))))
+    }
+}
+
+
+/// Reads a recording as a single unencrypted MPEG transport stream
+pub fn export(id: i32, key: &[u8; 16]) -> Result<Export> {
+
+    Ok(Export {
+        dir: recording_dir(id)?,
+        key: *key,
+        segments: read_segments(id)?.into_iter(),
+    })
+}
+
+
+/// Exported recording relayed from another host
+///
+/// The recording is read from `reader` in fixed-size chunks as the stream is
+/// polled.
+pub struct Relay<R> {
+    reader: R,
+}
+
+impl<R: Read> Relay<R> {
+
+    /// Creates a new `Relay` reading from `reader`
+    pub fn new(reader: R) -> Self {
+
+        Self { reader }
+    }
+}
+
+impl<R: Read> Stream for Relay<R> {
+    type Item = Bytes;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, Error> {
+
+        let mut buf = vec![0; RELAY_CHUNK_BYTES];
+
+        let len = loop {
+            match self.reader.read(&mut buf) {
+                Ok(len) => break len,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            }
+        };
+
+        if len == 0 {
+            return Ok(Async::Ready(None));
+        }
+
+        buf.truncate(len);
+
+        Ok(Async::Ready(Some(Bytes::from(buf))))
+    }
+}
+
+/// Generates a VOD HLS playlist covering the given recorded segments
+///
+/// `segments` must be in chronological order and are identified by the ID of
+/// the recording to which they belong. `segment_uri` gives the URI at which
+/// each segment is served, and `key_uri` the URI of the decryption key.
+pub fn playlist<F>(segments: &[(i32, Segment)], segment_uri: F, key_uri: &str) -> String
+where F: Fn(i32, &Segment) -> String
+{
+    let target_duration = segments.iter()
+        .map(|(_, s)| s.duration.ceil() as u64)
+        .max()
+        .unwrap_or(1);
+
+    let mut playlist = String::new();
+    playlist.push_str("#EXTM3U\n");
+    playlist.push_str("#EXT-X-VERSION:3\n");
+    playlist.push_str("#EXT-X-PLAYLIST-TYPE:VOD\n");
+    playlist.push_str(&format!("#EXT-X-TARGETDURATION:{}\n", target_duration));
+    playlist.push_str("#EXT-X-MEDIA-SEQUENCE:0\n");
+
+    let mut prev: Option<&(i32, Segment)> = None;
+    for entry in segments {
+        let (recording_id, ref segment) = *entry;
+
+        // Separate recordings are never contiguous
+        let contiguous = match prev {
+            Some((prev_id, _)) => *prev_id == recording_id && !segment.discontinuity,
+            None => true,
+        };
+        if !contiguous {
+            playlist.push_str("#EXT-X-DISCONTINUITY\n");
+        }
+
+        playlist.push_str(&format!(
+            "#EXT-X-PROGRAM-DATE-TIME:{}Z\n",
+            segment.started_at.format("%Y-%m-%dT%H:%M:%S%.3f"),
+        ));
+
+        // FFmpeg derives each IV from the live media sequence number, so the
+        // IV must be given explicitly for every segment
+        match segment.iv {
+            Some(iv) => playlist.push_str(&format!(
+                "#EXT-X-KEY:METHOD=AES-128,URI=\"{}\",IV=0x{:032x}\n",
+                key_uri,
+                iv,
+            )),
+            None => if matches!(prev, Some((_, s)) if s.iv.is_some()) {
+                playlist.push_str("#EXT-X-KEY:METHOD=NONE\n");
+            },
+        }
+
+        playlist.push_str(&format!("#EXTINF:{:.3},\n", segment.duration));
+        playlist.push_str(&segment_uri(recording_id, segment));
+        playlist.push('\n');
+
+        prev = Some(entry);
+    }
+
+    playlist.push_str("#EXT-X-ENDLIST\n");
+
+    playlist
+}
+
+
+/// Builds a response which downloads an exported recording
+pub fn download_response<S>(id: i32, data: S) -> HttpResponse
+where S: Stream<Item = Bytes, Error = Error> + 'static
+{
+
+    HttpResponse::Ok()
+        .content_type("video/mp2t")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"recording-{}.ts\"", id),
+        )
+        .streaming(data)
+}
+
+//#endregion
+
+
+//#region Recorder
+
+/// Segment listed in the live HLS playlist
+pub(crate) struct LiveSegment {
+    pub sequence: u64,
+    pub file: String,
+    pub duration: f64,
+    pub encrypted: bool,
+}
+
+
+/// Parses the live HLS playlist written by the transcoder
+pub(crate) fn parse_playlist(playlist: &str) -> Vec<LiveSegment> {
+
+    let mut segments = Vec::new();
+    let mut sequence = 0;
+    let mut duration = None;
+    let mut encrypted = false;
+
+    for line in playlist.lines().map(str::trim) {
+        if let Some(value) = line.strip_prefix("#EXT-X-MEDIA-SEQUENCE:") {
+            sequence = value.parse().unwrap_or(0);
+        } else if let Some(value) = line.strip_prefix("#EXT-X-KEY:") {
+            encrypted = !value.contains("METHOD=NONE");
+        } else if let Some(value) = line.strip_prefix("#EXTINF:") {
+            duration = value.trim_end_matches(',')
+                .split(',')
+                .next()
+                .and_then(|d| d.parse().ok());
+        } else if !line.is_empty() && !line.starts_with('#') {
+            if let Some(duration) = duration.take() {
+                segments.push(LiveSegment {
+                    sequence,
+                    file: line.into(),
+                    duration,
+                    encrypted,
+                });
+            }
+            sequence += 1;
+        }
+    }
+
+    segments
+}
+
+
+/// Recording which has not yet finished
+struct OpenRecording {
+    id: i32,
+    started: Instant,
+    segments: Vec<Segment>,
+    size_bytes: i64,
+}
+
+
+/// Settings shared between a `Recorder` and its worker
+struct Shared {
+    config: RecordingConfig,
+    hls_dir: String,
+}
+
+
+/// State of the recorder worker
+struct Worker {
+    shared: Arc<Mutex<Shared>>,
+    pool: ConnectionPool,
+    last_sequence: Option<u64>,
+    recording: Option<OpenRecording>,
+    last_retention: Option<Instant>,
+}
+
+impl Worker {
+
+    /// Determines whether the stream should currently be recorded
+    fn should_record(&self, config: &RecordingConfig) -> Result<bool> {
+
+        match config.mode {
+            RecordingMode::Off => Ok(false),
+            RecordingMode::Continuous => Ok(true),
+            RecordingMode::Schedule => {
+                let now = Local::now().time();
+                Ok(config.schedule.iter().any(|w| w.contains(now)))
+            },
+            RecordingMode::Motion => {
+                let conn = self.pool.get()?;
+                let latest: Option<Option<NaiveDateTime>> = motion_events::table
+                    .select(motion_events::ended_at)
+                    .order(motion_events::id.desc())
+                    .first(&conn)
+                    .optional()?;
+                let post_roll = chrono::Duration::seconds(config.post_roll_secs as i64);
+                Ok(match latest {
+                    Some(None) => true,
+                    Some(Some(ended_at)) => ended_at + post_roll > Utc::now().naive_utc(),
+                    None => false,
+                })
+            },
+        }
+    }
+
+    /// Copies new segments to the current recording, starting or finishing
+    /// recordings as needed
+    fn tick(&mut self) -> Result<()> {
+
+        let (config, hls_dir) = {
+            let shared = do_lock!(self.shared);
+            (shared.config.clone(), shared.hls_dir.clone())
+        };
+
+        let active = self.should_record(&config)?;
+
+        // Split long recordings
+        let max_length = Duration::from_secs(config.max_clip_minutes * 60);
+        if let Some(ref recording) = self.recording {
+            if !active || recording.started.elapsed() >= max_length {
+                self.finish()?;
+            }
+        }
+
+        if active {
+            let playlist = fs::read_to_string(format!("{}/stream.m3u8", hls_dir))
+                .unwrap_or_default();
+            let live = parse_playlist(&playlist);
+            self.copy_segments(&hls_dir, &live)?;
+        }
+
+        let retention_due = match self.last_retention {
+            Some(last) => last.elapsed() >= Duration::from_secs(RETENTION_INTERVAL_SECONDS),
+            None => true,
+        };
+        if retention_due {
+            self.last_retention = Some(Instant::now());
+            enforce_retention(&config, &self.pool.get()?)?;
+        }
+
+        Ok(())
+    }
+
+    /// Copies any segments not yet recorded from the live stream
+    fn copy_segments(&mut self, hls_dir: &str, live: &[LiveSegment]) -> Result<()> {
+
+        // Media sequence starts over when the transcoder restarts
+        let mut discontinuity = false;
+        if let (Some(last), Some(newest)) = (self.last_sequence, live.last()) {
+            if newest.sequence < last {
+                trace!("live stream restarted");
+                self.last_sequence = None;
+                discontinuity = true;
+            }
+        }
+
+        let new_segments: Vec<&LiveSegment> = live.iter()
+            .filter(|s| !matches!(self.last_sequence, Some(last) if s.sequence <= last))
+            .collect();
+        if new_segments.is_empty() {
+            return Ok(());
+        }
+
+        if self.recording.is_none() {
+            self.start()?;
+        }
+        let recording = match self.recording {
+            Some(ref mut recording) => recording,
+            None => return Ok(()),
+        };
+        let dir = recording_dir(recording.id)?;
+
+        // Segments are listed once complete, so the newest segment ends now
+        let mut started_at = Utc::now().naive_utc();
+        let total: f64 = new_segments.iter().map(|s| s.duration).sum();
+        started_at -= chrono::Duration::milliseconds((total * 1000.0) as i64);
+
+        for live_segment in new_segments {
+            let file = format!("{}.ts", recording.segments.len());
+            let src = format!("{}/{}", hls_dir, live_segment.file);
+            match fs::copy(&src, format!("{}/{}", dir, file)) {
+                Ok(size) => {
+                    recording.size_bytes += size as i64;
+                    recording.segments.push(Segment {
+                        file,
+                        started_at,
+                        duration: live_segment.duration,
+                        // FFmpeg uses the media sequence number as the IV
+                        iv: if live_segment.encrypted {
+                            Some(live_segment.sequence)
+                        } else {
+                            None
+                        },
+                        discontinuity,
+                    });
+                    discontinuity = false;
+                },
+                Err(err) => {
+                    warn!("failed to copy segment {}: {}", src, err);
+                    discontinuity = true;
+                },
+            }
+            started_at += chrono::Duration::milliseconds((live_segment.duration * 1000.0) as i64);
+            self.last_sequence = Some(live_segment.sequence);
+        }
+
+        write_segments(recording.id, &recording.segments)?;
+
+        let conn = self.pool.get()?;
+        let first_started_at = recording.segments.first().map(|s| s.started_at);
+        diesel::update(recordings::table.find(recording.id))
+            .set(recordings::size_bytes.eq(recording.size_bytes))
+            .execute(&conn)?;
+        if let Some(first_started_at) = first_started_at {
+            diesel::update(recordings::table.find(recording.id))
+                .set(recordings::started_at.eq(first_started_at))
+                .execute(&conn)?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts a new recording
+    fn start(&mut self) -> Result<()> {
+
+        let conn = self.pool.get()?;
+
+        let new_recording = NewRecording {
+            started_at: Utc::now().naive_utc(),
+        };
+        diesel::insert_into(recordings::table)
+            .values(&new_recording)
+            .execute(&conn)?;
+
+        // Get the row we just inserted
+        let recording: Recording = recordings::table.order(recordings::id.desc())
+            .first(&conn)?;
+
+        fs::create_dir_all(recording_dir(recording.id)?)?;
+
+        info!("recording {} started", recording.id);
+
+        self.recording = Some(OpenRecording {
+            id: recording.id,
+            started: Instant::now(),
+            segments: Vec::new(),
+            size_bytes: 0,
+        });
+
+        Ok(())
+    }
+
+    /// Finishes the current recording, if any
+    fn finish(&mut self) -> Result<()> {
+
+        if let Some(recording) = self.recording.take() {
+            let ended_at = match recording.segments.last() {
+                Some(s) => s.ended_at(),
+                None => Utc::now().naive_utc(),
+            };
+
+            let conn = self.pool.get()?;
+            diesel::update(recordings::table.find(recording.id))
+                .set(recordings::ended_at.eq(ended_at))
+                .execute(&conn)?;
+
+            info!("recording {} finished", recording.id);
+        }
+
+        Ok(())
+    }
+}
+
+
+/// Deletes recordings which exceed the configured retention limits
+fn enforce_retention(config: &RecordingConfig, conn: &PooledConnection) -> Result<()> {
+
+    trace!("enforcing recording retention limits");
+
+    // Oldest first; recordings in progress are never deleted
+    let finished: Vec<Recording> = recordings::table
+        .filter(recordings::ended_at.is_not_null())
+        .order(recordings::started_at.asc())
+        .load(conn)?;
+
+    let mut total: i64 = recordings::table
+        .select(recordings::size_bytes)
+        .load::<i64>(conn)?
+        .iter()
+        .sum();
+
+    let min_started_at = config.max_age_hours
+        .map(|hours| Utc::now().naive_utc() - chrono::Duration::hours(hours as i64));
+    let max_bytes = config.max_disk_mb
+        .map(|mb| mb as i64 * 1024 * 1024);
+
+    for recording in finished {
+        let too_old = matches!(min_started_at, Some(min) if recording.started_at < min);
+        let too_large = matches!(max_bytes, Some(max) if total > max);
+        if !too_old && !too_large {
+            break;
+        }
+
+        remove(&recording, conn)?;
+        total -= recording.size_bytes;
+    }
+
+    Ok(())
+}
+
+
+/// Repeatedly records the stream until a message is sent over (or the sender
+/// of) `cancel` is dropped
+fn record(mut worker: Worker, cancel: &Receiver<()>) {
+
+    let interval = Duration::from_secs(TICK_SECONDS);
+
+    while let Err(RecvTimeoutError::Timeout) = cancel.recv_timeout(interval) {
+        if let Err(err) = worker.tick() {
+            error!("failed to record stream: {}", err);
+        }
+    }
+
+    if let Err(err) = worker.finish() {
+        error!("failed to finish recording: {}", err);
+    }
+
+    debug!("recorder exiting");
+}
+
+
+/// Handle to a running recorder thread
+struct WorkerHandle {
+    cancel: Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl WorkerHandle {
+
+    /// Signals the worker thread to exit and waits for it to do so
+    fn join(self) {
+
+        // Send only fails if the thread has already exited
+        let _ = self.cancel.send(());
+
+        if self.thread.join().is_err() {
+            error!("recorder thread panicked");
+        }
+    }
+}
+
+
+/// Records the current host's video stream
+///
+/// A background worker runs for as long as recording is enabled.
+pub struct Recorder {
+    shared: Arc<Mutex<Shared>>,
+    pool: ConnectionPool,
+    worker: Option<WorkerHandle>,
+}
+
+impl Recorder {
+
+    /// Creates a new `Recorder`, starting it if `config` enables recording
+    ///
+    /// Recordings left unfinished by a previous run of LunaCam are finished at
+    /// the end of their last segment.
+    pub fn new(config: RecordingConfig, hls_dir: &str, pool: ConnectionPool) -> Result<Self> {
+
+        let conn = pool.get()?;
+        let unfinished: Vec<Recording> = recordings::table
+            .filter(recordings::ended_at.is_null())
+            .load(&conn)?;
+
+        for recording in unfinished {
+            let ended_at = match read_segments(recording.id) {
+                Ok(segments) => segments.last()
+                    .map(Segment::ended_at)
+                    .unwrap_or(recording.started_at),
+                Err(err) => {
+                    warn!("failed to read segments of recording {}: {}", recording.id, err);
+                    recording.started_at
+                },
+            };
+
+            debug!("finishing interrupted recording {}", recording.id);
+            diesel::update(&recording)
+                .set(recordings::ended_at.eq(ended_at))
+                .execute(&conn)?;
+        }
+
+        let mut recorder = Self {
+            shared: Arc::new(Mutex::new(Shared {
+                config: RecordingConfig::default(),
+                hls_dir: hls_dir.into(),
+            })),
+            pool,
+            worker: None,
+        };
+        recorder.set_config(config, hls_dir);
+
+        Ok(recorder)
+    }
+
+    /// Gets the current recording settings
+    pub fn config(&self) -> RecordingConfig {
+
+        do_lock!(self.shared).config.clone()
+    }
+
+    /// Replaces the current recording settings
+    ///
+    /// `hls_dir` is the directory containing the live HLS stream. The worker
+    /// is started or stopped as needed.
+    pub fn set_config(&mut self, config: RecordingConfig, hls_dir: &str) {
+
+        let enabled = config.mode != RecordingMode::Off;
+        {
+            let mut shared = do_lock!(self.shared);
+            shared.config = config;
+            shared.hls_dir = hls_dir.into();
+        }
+
+        if enabled && self.worker.is_none() {
+            debug!("starting recorder");
+            let worker = Worker {
+                shared: self.shared.clone(),
+                pool: self.pool.clone(),
+                last_sequence: None,
+                recording: None,
+                last_retention: None,
+            };
+            let (cancel, cancel_rx) = mpsc::channel();
+            let thread = thread::spawn(move || record(worker, &cancel_rx));
+            self.worker = Some(WorkerHandle { cancel, thread });
+        } else if !enabled {
+            if let Some(worker) = self.worker.take() {
+                debug!("stopping recorder");
+                worker.join();
+            }
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            worker.join();
+        }
+    }
+}
+
+//#endregion
+
+
+#[cfg(test)]
+mod tests {
+
+    use chrono::NaiveDate;
+
+    use super::*;
+
+
+    #[test]
+    fn segments_end_after_their_duration() {
+
+        let segment = Segment {
+            file: "0.ts".into(),
+            started_at: NaiveDate::from_ymd(2019, 12, 2).and_hms(23, 59, 58),
+            duration: 2.5,
+            iv: None,
+            discontinuity: false,
+        };
+
+        let ended_at = NaiveDate::from_ymd(2019, 12, 3).and_hms_milli(0, 0, 0, 500);
+        assert_eq!(segment.ended_at(), ended_at);
+    }
+
+    #[test]
+    fn relay_reads_in_chunks() {
+
+        let data: Vec<u8> = (0..RELAY_CHUNK_BYTES * 2 + 10).map(|i| i as u8).collect();
+        let mut relay = Relay::new(data.as_slice());
+
+        let mut chunks = Vec::new();
+        while let Async::Ready(Some(chunk)) = relay.poll().unwrap() {
+            chunks.push(chunk);
+        }
+
+        let lengths: Vec<usize> = chunks.iter().map(Bytes::len).collect();
+        assert_eq!(lengths, vec![RELAY_CHUNK_BYTES, RELAY_CHUNK_BYTES, 10]);
+        assert_eq!(chunks.concat(), data);
+    }
+}
@@ -0,0 +1,618 @@
+//! Scheduled camera control
+//!
+//! Cameras may be given a weekly schedule of windows during which they are
+//! enabled, along with one-off overrides which force a camera on or off for a
+//! period of time. A background task evaluates schedules and enables or
+//! disables cameras accordingly.
+
+
+use std::collections::HashMap;
+#[cfg(feature = "stream")]
+use std::sync::RwLock;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use actix_web::http::StatusCode;
+use actix_web::web::{self, Data, Json, ServiceConfig};
+use chrono::{Datelike, Local, NaiveDateTime, NaiveTime, Utc};
+use diesel::prelude::*;
+use log::{debug, error, info, trace};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tera::Tera;
+
+use crate::cameras::{self, PatchCameraBody};
+use crate::db::{ConnectionPool, PooledConnection};
+use crate::db::schema::{camera_schedules, cameras as cameras_table, schedule_overrides};
+use crate::error::{Error, Result};
+#[cfg(feature = "stream")]
+use crate::stream::Stream;
+use crate::users::{AuthenticatedUser, AuthenticationMiddleware, Role};
+
+
+/// Interval at which schedules are evaluated
+const TICK_SECONDS: u64 = 30;
+
+
+//#region Schedules
+
+/// Weekly period of time (in the host's local time zone) during which a camera
+/// is enabled
+///
+/// Windows for which `end_time` is earlier than `start_time` span midnight and
+/// end on the following day.
+#[derive(Clone, Deserialize, Serialize)]
+#[derive(Identifiable, Queryable)]
+#[serde(rename_all = "camelCase")]
+#[table_name = "camera_schedules"]
+pub struct ScheduleWindow {
+    #[serde(skip_deserializing)]
+    pub id: i32,
+    #[serde(skip)]
+    pub camera_id: i32,
+
+    /// Day of the week, starting with 0 for Monday
+    pub weekday: i32,
+    pub start_time: NaiveTime,
+    pub end_time: NaiveTime,
+}
+
+impl ScheduleWindow {
+
+    /// Returns whether the given local time falls within this window
+    fn contains(&self, time: NaiveDateTime) -> bool {
+
+        let weekday = time.weekday().num_days_from_monday() as i32;
+        let time = time.time();
+
+        if self.start_time <= self.end_time {
+            weekday == self.weekday && self.start_time <= time && time < self.end_time
+        } else {
+            (weekday == self.weekday && self.start_time <= time)
+                || (weekday == (self.weekday + 1) % 7 && time < self.end_time)
+        }
+    }
+}
+
+
+/// Period of time during which a camera is forced on or off, regardless of its
+/// weekly schedule
+#[derive(Clone, Deserialize, Serialize)]
+#[derive(Identifiable, Queryable)]
+#[serde(rename_all = "camelCase")]
+#[table_name = "schedule_overrides"]
+pub struct ScheduleOverride {
+    #[serde(skip_deserializing)]
+    pub id: i32,
+    #[serde(skip)]
+    pub camera_id: i32,
+    pub enabled: bool,
+
+    /// Start of the override (UTC)
+    pub starts_at: NaiveDateTime,
+
+    /// End of the override (UTC)
+    pub ends_at: NaiveDateTime,
+}
+
+
+#[derive(Insertable)]
+#[table_name = "camera_schedules"]
+struct NewScheduleWindow {
+    camera_id: i32,
+    weekday: i32,
+    start_time: NaiveTime,
+    end_time: NaiveTime,
+}
+
+
+#[derive(Insertable)]
+#[table_name = "schedule_overrides"]
+struct NewScheduleOverride {
+    camera_id: i32,
+    enabled: bool,
+    starts_at: NaiveDateTime,
+    ends_at: NaiveDateTime,
+}
+
+
+/// Complete schedule of a camera
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Schedule {
+    pub windows: Vec<ScheduleWindow>,
+    pub overrides: Vec<ScheduleOverride>,
+}
+
+impl Schedule {
+
+    /// Checks that this schedule is usable
+    fn validate(&self) -> Result<()> {
+
+        for window in &self.windows {
+            if window.weekday < 0 || window.weekday > 6 {
+                return Error::web(StatusCode::BAD_REQUEST, "weekday must be between 0 and 6");
+            }
+            if window.start_time == window.end_time {
+                return Error::web(StatusCode::BAD_REQUEST, "window must not be empty");
+            }
+        }
+
+        for over in &self.overrides {
+            if over.starts_at >= over.ends_at {
+                return Error::web(StatusCode::BAD_REQUEST, "override must end after it starts");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Determines whether the camera should be enabled at the given time
+    ///
+    /// Returns `None` if this schedule does not control the camera at that time
+    /// (i.e. it has no windows and no override is in effect).
+    fn desired_state(&self, now: NaiveDateTime, local_now: NaiveDateTime) -> Option<bool> {
+
+        let over = self.overrides.iter()
+            .find(|o| o.starts_at <= now && now < o.ends_at);
+        if let Some(over) = over {
+            return Some(over.enabled);
+        }
+
+        if self.windows.is_empty() {
+            None
+        } else {
+            Some(self.windows.iter().any(|w| w.contains(local_now)))
+        }
+    }
+}
+
+
+/// Retrieves the schedule of the specified camera
+pub fn get(camera_id: i32, conn: &PooledConnection) -> Result<Schedule> {
+
+    let windows = camera_schedules::table
+        .filter(camera_schedules::camera_id.eq(camera_id))
+        .order((camera_schedules::weekday, camera_schedules::start_time))
+        .load(conn)?;
+
+    let overrides = schedule_overrides::table
+        .filter(schedule_overrides::camera_id.eq(camera_id))
+        .order(schedule_overrides::starts_at)
+        .load(conn)?;
+
+    Ok(Schedule { windows, overrides })
+}
+
+
+/// Removes the schedule of the specified camera
+pub fn clear(camera_id: i32, conn: &PooledConnection) -> Result<()> {
+
+    diesel::delete(camera_schedules::table.filter(camera_schedules::camera_id.eq(camera_id)))
+        .execute(conn)?;
+    diesel::delete(schedule_overrides::table.filter(schedule_overrides::camera_id.eq(camera_id)))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+
+/// Replaces the schedule of the specified camera
+fn set(camera_id: i32, schedule: &Schedule, conn: &PooledConnection) -> Result<()> {
+
+    conn.transaction(|| {
+
+        clear(camera_id, conn)?;
+
+        // SQLite does not support batch inserts
+        for w in &schedule.windows {
+            let window = NewScheduleWindow {
+                camera_id,
+                weekday: w.weekday,
+                start_time: w.start_time,
+                end_time: w.end_time,
+            };
+            diesel::insert_into(camera_schedules::table)
+                .values(&window)
+                .execute(conn)?;
+        }
+
+        for o in &schedule.overrides {
+            let over = NewScheduleOverride {
+                camera_id,
+                enabled: o.enabled,
+                starts_at: o.starts_at,
+                ends_at: o.ends_at,
+            };
+            diesel::insert_into(schedule_overrides::table)
+                .values(&over)
+                .execute(conn)?;
+        }
+
+        Ok(())
+    })
+}
+
+//#endregion
+
+
+//#region Scheduler
+
+/// State of the scheduler worker
+struct Worker {
+    pool: Data<ConnectionPool>,
+    client: Data<Client>,
+    templates: Data<Tera>,
+    #[cfg(feature = "stream")]
+    stream: Data<RwLock<Stream>>,
+
+    /// State each camera was last scheduled to be in
+    last_desired: HashMap<i32, bool>,
+}
+
+impl Worker {
+
+    /// Enables or disables cameras according to their schedules
+    ///
+    /// A camera is only changed when its scheduled state changes, so manual
+    /// changes made through the API persist until the next scheduled change.
+    fn tick(&mut self) -> Result<()> {
+
+        trace!("evaluating camera schedules");
+
+        let conn = self.pool.get()?;
+        let now = Utc::now().naive_utc();
+        let local_now = Local::now().naive_local();
+
+        // Expired overrides are no longer needed
+        diesel::delete(schedule_overrides::table.filter(schedule_overrides::ends_at.le(now)))
+            .execute(&conn)?;
+
+        let camera_ids: Vec<i32> = cameras_table::table
+            .select(cameras_table::id)
+            .load(&conn)?;
+        self.last_desired.retain(|id, _| camera_ids.contains(id));
+
+        for id in camera_ids {
+
+            let desired = match get(id, &conn)?.desired_state(now, local_now) {
+                Some(desired) => desired,
+                None => {
+                    self.last_desired.remove(&id);
+                    continue;
+                },
+            };
+
+            if self.last_desired.insert(id, desired) == Some(desired) {
+                continue;
+            }
+
+            let camera = cameras::get(id, &conn)?;
+            if camera.enabled == desired {
+                continue;
+            }
+
+            info!("{} camera {} on schedule", if desired { "enabling" } else { "disabling" }, id);
+            let body = PatchCameraBody {
+                enabled: Some(desired),
+                ..Default::default()
+            };
+            let result = cameras::update(
+                id,
+                body,
+                &conn,
+                &self.client,
+                &self.templates,
+                #[cfg(feature = "stream")]
+                &self.stream,
+            );
+
+            // Try again on the next tick
+            if let Err(err) = result {
+                error!("failed to apply schedule to camera {}: {}", id, err);
+                self.last_desired.remove(&id);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+/// Repeatedly evaluates schedules until a message is sent over (or the sender
+/// of) `cancel` is dropped
+fn schedule(mut worker: Worker, cancel: &Receiver<()>) {
+
+    let interval = Duration::from_secs(TICK_SECONDS);
+
+    loop {
+        if let Err(err) = worker.tick() {
+            error!("failed to evaluate camera schedules: {}", err);
+        }
+
+        if cancel.recv_timeout(interval) != Err(RecvTimeoutError::Timeout) {
+            break;
+        }
+    }
+
+    debug!("scheduler exiting");
+}
+
+
+/// Handle to the background task which applies camera schedules
+pub struct Scheduler {
+    cancel: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Scheduler {
+
+    /// Starts applying camera schedules in the background
+    pub fn start(
+        pool: Data<ConnectionPool>,
+        client: Data<Client>,
+        templates: Data<Tera>,
+        #[cfg(feature = "stream")]
+        stream: Data<RwLock<Stream>>,
+    ) -> Self {
+
+        debug!("starting scheduler");
+        let worker = Worker {
+            pool,
+            client,
+            templates,
+            #[cfg(feature = "stream")]
+            stream,
+            last_desired: HashMap::new(),
+        };
+        let (cancel, cancel_rx) = mpsc::channel();
+        let thread = thread::spawn(move || schedule(worker, &cancel_rx));
+
+        Self {
+            cancel: Some(cancel),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+
+        // Send only fails if the thread has already exited
+        if let Some(cancel) = self.cancel.take() {
+            let _ = cancel.send(());
+        }
+
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("scheduler thread panicked");
+            }
+        }
+    }
+}
+
+//#endregion
+
+
+//#region API
+
+/// Retrieves the schedule of the specified camera
+fn get_schedule(
+    pool: Data<ConnectionPool>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32,)>,
+) -> Result<Json<Schedule>> {
+
+    let conn = pool.get()?;
+    user.require_view(path.0, &conn)?;
+
+    // Ensure the camera exists
+    cameras::get(path.0, &conn)?;
+
+    Ok(Json(get(path.0, &conn)?))
+}
+
+
+/// Replaces the schedule of the specified camera
+fn put_schedule(
+    pool: Data<ConnectionPool>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32,)>,
+    body: Json<Schedule>,
+) -> Result<Json<Schedule>> {
+
+    let id = path.0;
+    user.require(Role::Operator)?;
+    let conn = pool.get()?;
+
+    cameras::get(id, &conn)?;
+    body.validate()?;
+
+    debug!("updating schedule for camera {}", id);
+    set(id, &body, &conn)?;
+
+    info!("updated schedule for camera {}", id);
+
+    Ok(Json(get(id, &conn)?))
+}
+
+
+/// Configures the */cameras/{id}/schedule* API resource
+pub fn configure_api(service: &mut ServiceConfig) {
+
+    service.service(
+        web::resource("/cameras/{id}/schedule")
+            .route(web::get().to(get_schedule))
+            .route(web::put().to(put_schedule))
+            .wrap(AuthenticationMiddleware::reject())
+    );
+}
+
+//#endregion
+
+
+#[cfg(test)]
+mod tests {
+
+    use chrono::NaiveDate;
+
+    use super::*;
+
+
+    /// Returns the given time on the given day of the first week of December
+    /// 2019, which starts on Monday the 2nd
+    fn at(weekday: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(2019, 12, 2 + weekday).and_hms(hour, minute, 0)
+    }
+
+    fn window(weekday: i32, start: (u32, u32), end: (u32, u32)) -> ScheduleWindow {
+        ScheduleWindow {
+            id: 0,
+            camera_id: 0,
+            weekday,
+            start_time: NaiveTime::from_hms(start.0, start.1, 0),
+            end_time: NaiveTime::from_hms(end.0, end.1, 0),
+        }
+    }
+
+    fn over(enabled: bool, starts_at: NaiveDateTime, ends_at: NaiveDateTime) -> ScheduleOverride {
+        ScheduleOverride {
+            id: 0,
+            camera_id: 0,
+            enabled,
+            starts_at,
+            ends_at,
+        }
+    }
+
+
+    #[test]
+    fn windows_contain_times_on_their_day() {
+
+        let w = window(1, (9, 0), (17, 0));
+
+        assert!(w.contains(at(1, 9, 0)));
+        assert!(w.contains(at(1, 16, 59)));
+        assert!(!w.contains(at(1, 8, 59)));
+        assert!(!w.contains(at(1, 17, 0)));
+        assert!(!w.contains(at(0, 12, 0)));
+        assert!(!w.contains(at(2, 12, 0)));
+    }
+
+    #[test]
+    fn windows_span_midnight() {
+
+        let w = window(0, (22, 0), (6, 0));
+
+        assert!(w.contains(at(0, 22, 0)));
+        assert!(w.contains(at(0, 23, 59)));
+        assert!(w.contains(at(1, 0, 0)));
+        assert!(w.contains(at(1, 5, 59)));
+        assert!(!w.contains(at(1, 6, 0)));
+        assert!(!w.contains(at(0, 21, 59)));
+        assert!(!w.contains(at(0, 5, 0)));
+        assert!(!w.contains(at(1, 22, 0)));
+
+        // Sunday night ends on Monday morning
+        let w = window(6, (22, 0), (6, 0));
+        assert!(w.contains(at(6, 23, 0)));
+        assert!(w.contains(at(0, 1, 0)));
+        assert!(!w.contains(at(5, 1, 0)));
+    }
+
+    #[test]
+    fn empty_windows_are_rejected() {
+
+        let schedule = Schedule {
+            windows: vec![window(0, (22, 0), (6, 0)), window(1, (9, 0), (9, 0))],
+            overrides: vec![],
+        };
+        assert!(schedule.validate().is_err());
+
+        let schedule = Schedule {
+            windows: vec![window(0, (22, 0), (6, 0))],
+            overrides: vec![],
+        };
+        assert!(schedule.validate().is_ok());
+    }
+
+    #[test]
+    fn schedule_without_windows_does_not_control_camera() {
+
+        let schedule = Schedule {
+            windows: vec![],
+            overrides: vec![],
+        };
+
+        assert_eq!(schedule.desired_state(at(0, 12, 0), at(0, 12, 0)), None);
+    }
+
+    #[test]
+    fn camera_is_enabled_during_windows() {
+
+        let schedule = Schedule {
+            windows: vec![window(0, (9, 0), (17, 0)), window(4, (22, 0), (6, 0))],
+            overrides: vec![],
+        };
+
+        assert_eq!(schedule.desired_state(at(0, 12, 0), at(0, 12, 0)), Some(true));
+        assert_eq!(schedule.desired_state(at(0, 18, 0), at(0, 18, 0)), Some(false));
+        assert_eq!(schedule.desired_state(at(5, 3, 0), at(5, 3, 0)), Some(true));
+        assert_eq!(schedule.desired_state(at(5, 7, 0), at(5, 7, 0)), Some(false));
+    }
+
+    #[test]
+    fn windows_use_local_time() {
+
+        let schedule = Schedule {
+            windows: vec![window(0, (9, 0), (17, 0))],
+            overrides: vec![],
+        };
+
+        // UTC time is outside the window, but local time is inside it
+        assert_eq!(schedule.desired_state(at(0, 8, 0), at(0, 10, 0)), Some(true));
+        assert_eq!(schedule.desired_state(at(0, 10, 0), at(0, 8, 0)), Some(false));
+    }
+
+    #[test]
+    fn overrides_take_precedence() {
+
+        let schedule = Schedule {
+            windows: vec![window(0, (9, 0), (17, 0))],
+            overrides: vec![
+                over(false, at(0, 10, 0), at(0, 11, 0)),
+                over(true, at(0, 20, 0), at(0, 21, 0)),
+            ],
+        };
+
+        assert_eq!(schedule.desired_state(at(0, 9, 30), at(0, 9, 30)), Some(true));
+        assert_eq!(schedule.desired_state(at(0, 10, 0), at(0, 10, 0)), Some(false));
+        assert_eq!(schedule.desired_state(at(0, 11, 0), at(0, 11, 0)), Some(true));
+        assert_eq!(schedule.desired_state(at(0, 20, 30), at(0, 20, 30)), Some(true));
+        assert_eq!(schedule.desired_state(at(0, 21, 0), at(0, 21, 0)), Some(false));
+    }
+
+    #[test]
+    fn overrides_control_camera_without_windows() {
+
+        let schedule = Schedule {
+            windows: vec![],
+            overrides: vec![over(true, at(2, 0, 0), at(3, 0, 0))],
+        };
+
+        assert_eq!(schedule.desired_state(at(2, 12, 0), at(2, 12, 0)), Some(true));
+        assert_eq!(schedule.desired_state(at(3, 12, 0), at(3, 12, 0)), None);
+    }
+
+    #[test]
+    fn empty_overrides_are_rejected() {
+
+        let schedule = Schedule {
+            windows: vec![],
+            overrides: vec![over(true, at(2, 0, 0), at(2, 0, 0))],
+        };
+
+        assert!(schedule.validate().is_err());
+    }
+}
@@ -9,24 +9,37 @@ use std::env;
 use std::fs;
 use std::io::Write;
 use std::process::{Command, Stdio};
-use std::sync::RwLock;
+use std::sync::{Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use actix_web::HttpResponse;
+use actix_web::http::{header, StatusCode};
 use actix_web::web::{self, Data, Json, ServiceConfig};
 use diesel::backend::Backend;
 use diesel::deserialize::{self, FromSql};
 use diesel::serialize::{self, Output, ToSql};
 use diesel::sql_types::Integer;
-use log::{debug, trace};
+use log::{debug, trace, warn};
+use openssl::symm::{self, Cipher};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
-use tera::{Context, Tera};
+use tera::Tera;
 
-use crate::{do_read, do_write};
-use crate::error::Result;
+use crate::{do_lock, do_read, do_write};
+use crate::error::{Error, Result};
 use crate::db::{ConnectionPool, PooledConnection};
-use crate::prochost::ProcHost;
+use crate::motion::{self, MotionConfig, MotionDetector, MotionEvent};
+use crate::pairing::{self, SignatureMiddleware};
+use crate::prochost::{HostDiagnostics, HostStatus, ProcHost, RestartPolicy};
 use crate::proxy;
+use crate::recording::{self, Recorder, Recording, RecordingConfig, Segment};
 use crate::settings;
+use crate::transcoder::{self, TranscoderConfig};
+
+
+/// Time for which a snapshot is reused before a new one is taken
+pub const SNAPSHOT_MAX_AGE: Duration = Duration::from_secs(5);
 
 
 //#region Orientation
@@ -86,95 +99,19 @@ where
 //#endregion
 
 
-/// Creates a `Command` for starting the transcoder
-fn make_command(_orientation: Orientation) -> Result<Command> {
-
-    // In debug mode, start a dummy process
-    let mut cmd = if cfg!(debug_assertions) {
-
-        let mut cmd = Command::new("sh");
-        let state_dir = env::var("STATE_DIRECTORY")?;
-        cmd.arg("-c");
-        cmd.arg(format!("while : ; do date > {}/time.txt; sleep 1; done", state_dir));
-        cmd
-
-    // In release mode, start the actual transcoder process
-    } else {
-
-
-        let hls_key_dir = env::var("STATE_DIRECTORY")?;
-        let hls_key_info_path = format!("{}/stream.keyinfo", hls_key_dir);
-
-        // TODO: parameterize orientation, output path, ...
-        let mut cmd = Command::new("ffmpeg");
-        cmd.args(&[
-            // General configuration
-            "-hide_banner",
-            "-loglevel", "error",
-
-            // Input stream
-            "-f", "v4l2",
-            "-input_format", "h264",
-            "-video_size", "1280x720",
-            "-i", "/dev/video0",
-
-            // Operation
-            "-c:v", "copy",
-            "-c:a", "aac",
-
-            // Output stream
-            "-f", "hls",
-            "-hls_flags", "delete_segments",
-            "-hls_key_info_file", &hls_key_info_path,
-            "/dev/shm/lunacam/hls/stream.m3u8",
-        ]);
-        cmd
-    };
-
-    cmd.stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null());
-
-    Ok(cmd)
-}
-
-
-/// Gets the location of the HLS stream's proxy configuration file
-fn get_proxy_config_path() -> Result<String> {
-
-    let cfg_dir = proxy::config_dir()?;
-    let path = format!("{}/hls.conf", cfg_dir);
-
-    Ok(path)
-}
-
-
-/// Writes proxy configuration for the HLS stream
-fn write_proxy_config(templates: &Tera) -> Result<()> {
+/// Exposes the HLS stream through the proxy
+fn write_proxy_config(templates: &Tera, config: &TranscoderConfig) -> Result<()> {
 
     debug!("writing proxy configuration for HLS stream");
-
-    let config = templates.render("hls.conf", &Context::new())?;
-
-    let config_path = get_proxy_config_path()?;
-
-    fs::write(&config_path, config)?;
-
-    Ok(())
+    proxy::configure_stream(&config.output_dir, templates)
 }
 
 
 /// Removes proxy configuration for the HLS stream
 fn clear_proxy_config() -> Result<()> {
 
-    let config_path = get_proxy_config_path()?;
-
-    if fs::metadata(&config_path).is_ok() {
-        debug!("clearing proxy configuration for HLS stream");
-        fs::remove_file(&config_path)?;
-    }
-
-    Ok(())
+    debug!("clearing proxy configuration for HLS stream");
+    proxy::clear_stream()
 }
 
 
@@ -183,10 +120,14 @@ const STREAM_STATE_SETTING: &str = "streamState";
 
 
 /// Describes an update to the state of a video stream
-#[derive(Deserialize, Serialize)]
+#[derive(Default, Deserialize, Serialize)]
 pub struct StreamUpdate {
     pub enabled: Option<bool>,
     pub orientation: Option<Orientation>,
+    pub transcoder: Option<TranscoderConfig>,
+    pub restart_policy: Option<RestartPolicy>,
+    pub motion: Option<MotionConfig>,
+    pub recording: Option<RecordingConfig>,
 }
 
 
@@ -197,8 +138,15 @@ pub struct StreamUpdate {
 /// `StreamState` from an instance of `Stream`
 pub struct Stream {
     pub(crate) orientation: Orientation,
+    pub(crate) config: TranscoderConfig,
     pub(crate) transcoder: ProcHost,
+    pub(crate) motion: MotionDetector,
+    pub(crate) recorder: Recorder,
+    pub(crate) snapshot: Mutex<Option<(Instant, Vec<u8>)>>,
     pub(crate) key: [u8; 16],
+
+    /// Path of the FFmpeg key info file used to encrypt the stream
+    pub(crate) key_info_path: String,
 }
 
 impl Stream {
@@ -208,11 +156,136 @@ impl Stream {
 
         StreamState {
             enabled: self.transcoder.running(),
+            status: self.transcoder.status(),
             orientation: self.orientation,
+            transcoder: self.config.clone(),
+            restart_policy: self.transcoder.policy(),
+            motion: self.motion.config(),
+            recording: self.recorder.config(),
             key: self.key,
         }
     }
 
+    /// Retrieves diagnostic information about this stream's transcoder
+    pub fn diagnostics(&self) -> HostDiagnostics {
+
+        self.transcoder.diagnostics()
+    }
+
+    /// Restarts this stream's transcoder if it has failed
+    pub fn reset(&mut self) -> Result<()> {
+
+        self.transcoder.reset()
+    }
+
+    /// Gets a JPEG image of the most recent keyframe of this stream
+    ///
+    /// Snapshots are cached for `SNAPSHOT_MAX_AGE`.
+    pub fn snapshot(&self) -> Result<Vec<u8>> {
+
+        let mut cache = do_lock!(self.snapshot);
+        if let Some((taken, ref image)) = *cache {
+            if taken.elapsed() < SNAPSHOT_MAX_AGE {
+                return Ok(image.clone());
+            }
+        }
+
+        if !self.transcoder.running() {
+            return Error::web(StatusCode::SERVICE_UNAVAILABLE, "Stream is not running");
+        }
+
+        let image = self.take_snapshot()?;
+        *cache = Some((Instant::now(), image.clone()));
+
+        Ok(image)
+    }
+
+    /// Decodes the first frame of the newest HLS segment as a JPEG image
+    ///
+    /// Segments always begin with a keyframe, so no other segments are needed.
+    fn take_snapshot(&self) -> Result<Vec<u8>> {
+
+        trace!("taking stream snapshot");
+
+        let playlist = fs::read_to_string(format!("{}/stream.m3u8", self.config.output_dir))
+            .unwrap_or_default();
+        let segment = match recording::parse_playlist(&playlist).pop() {
+            Some(segment) => segment,
+            None => return Error::web(StatusCode::SERVICE_UNAVAILABLE, "Stream is not ready"),
+        };
+
+        let data = fs::read(format!("{}/{}", self.config.output_dir, segment.file))?;
+        let data = if segment.encrypted {
+            symm::decrypt(
+                Cipher::aes_128_cbc(),
+                &self.key,
+                Some(&u128::from(segment.sequence).to_be_bytes()),
+                &data,
+            )?
+        } else {
+            data
+        };
+
+        let mut child = Command::new("ffmpeg")
+            .args(["-hide_banner", "-loglevel", "error"])
+            .args(["-f", "mpegts", "-i", "pipe:0"])
+            .args(["-frames:v", "1", "-f", "image2", "-c:v", "mjpeg", "pipe:1"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        // FFmpeg stops reading once it has a frame, so write from another
+        // thread and ignore the resulting broken pipe
+        let mut stdin = child.stdin.take();
+        let writer = thread::spawn(move || {
+            if let Some(ref mut stdin) = stdin {
+                let _ = stdin.write_all(&data);
+            }
+        });
+
+        let output = child.wait_with_output()?;
+        let _ = writer.join();
+
+        if !output.status.success() || output.stdout.is_empty() {
+            warn!("failed to decode snapshot: {}", output.status);
+            return Error::web(StatusCode::SERVICE_UNAVAILABLE, "Failed to take snapshot");
+        }
+
+        Ok(output.stdout)
+    }
+
+    /// Checks every changed part of an update, so that an invalid update is
+    /// rejected before any of it takes effect
+    fn validate_update(&self, update: &StreamUpdate) -> Result<()> {
+
+        if let Some(ref config) = update.transcoder {
+            if self.config != *config {
+                config.validate()?;
+            }
+        }
+
+        if let Some(ref policy) = update.restart_policy {
+            if self.transcoder.policy() != *policy {
+                policy.validate()?;
+            }
+        }
+
+        if let Some(ref config) = update.motion {
+            if self.motion.config() != *config {
+                self.motion.validate_config(config)?;
+            }
+        }
+
+        if let Some(ref config) = update.recording {
+            if self.recorder.config() != *config {
+                config.validate()?;
+            }
+        }
+
+        Ok(())
+    }
+
     /// Updates this stream's settings
     pub fn update(
         &mut self,
@@ -221,28 +294,79 @@ impl Stream {
         templates: &Tera,
     ) -> Result<()> {
 
+        self.validate_update(update)?;
+
         let mut do_stop = false;
         let mut do_reconfig = false;
         let mut do_start = false;
+        let mut do_flush = false;
 
-        if let Some(enabled) = update.enabled {
-            if self.transcoder.running() != enabled {
-                trace!("updating stream enabled state");
-                do_stop = !enabled;
-                do_start = enabled;
-            }
+        let running = self.transcoder.running();
+        let enabled = update.enabled.unwrap_or(running);
+
+        if running != enabled {
+            trace!("updating stream enabled state");
+            do_stop = !enabled;
+            do_start = enabled;
         }
 
         if let Some(orientation) = update.orientation {
             if self.orientation != orientation {
                 trace!("updating stream orientation");
                 self.orientation = orientation;
-                do_stop = true;
                 do_reconfig = true;
-                do_start = true;
             }
         }
 
+        if let Some(ref config) = update.transcoder {
+            if self.config != *config {
+                trace!("updating transcoder configuration");
+                self.config = config.clone();
+                do_reconfig = true;
+            }
+        }
+
+        if let Some(ref policy) = update.restart_policy {
+            if self.transcoder.policy() != *policy {
+                trace!("updating transcoder restart policy");
+                self.transcoder.set_policy(policy.clone());
+                do_flush = true;
+            }
+        }
+
+        if let Some(ref config) = update.motion {
+            let current = self.motion.config();
+            if current != *config {
+                trace!("updating motion detection settings");
+                // Transcoder only produces sampled frames while motion
+                // detection is enabled
+                do_reconfig |= current.enabled != config.enabled;
+                self.motion.set_config(config.clone())?;
+                do_flush = true;
+            }
+        }
+
+        if let Some(ref config) = update.recording {
+            if self.recorder.config() != *config {
+                trace!("updating recording settings");
+                do_flush = true;
+            }
+        }
+
+        // Recorder follows the transcoder's output directory
+        if do_flush || do_reconfig {
+            let config = update.recording.clone()
+                .unwrap_or_else(|| self.recorder.config());
+            self.recorder.set_config(config, &self.config.output_dir);
+        }
+
+        // Reconfiguring requires restarting the transcoder, but only if the
+        // stream is (or is about to be) enabled
+        if do_reconfig {
+            do_stop = running;
+            do_start = enabled;
+        }
+
         if do_stop {
             debug!("stopping transcoder");
             self.transcoder.stop()?;
@@ -252,17 +376,25 @@ impl Stream {
 
         if do_reconfig {
             trace!("reconfiguring transcoder host");
-            self.transcoder = ProcHost::new(make_command(self.orientation)?);
+            let cmd = transcoder::make_command(
+                self.orientation,
+                &self.config,
+                &self.key_info_path,
+                self.motion.config().enabled,
+            )?;
+            let shutdown = transcoder::make_shutdown_policy(&self.config)?;
+            self.transcoder.reconfigure(cmd, shutdown);
         }
 
         if do_start {
             debug!("starting transcoder");
+            fs::create_dir_all(&self.config.output_dir)?;
             self.transcoder.start()?;
-            write_proxy_config(templates)?;
+            write_proxy_config(templates, &self.config)?;
             proxy::reload()?;
         }
 
-        if do_stop || do_reconfig || do_start {
+        if do_stop || do_reconfig || do_start || do_flush {
             trace!("flushing stream settings");
             settings::set(STREAM_STATE_SETTING, &self.state(), conn)?;
         }
@@ -281,7 +413,17 @@ impl Stream {
 #[derive(Deserialize, Serialize)]
 pub struct StreamState {
     pub enabled: bool,
+    #[serde(default)]
+    pub status: HostStatus,
     pub orientation: Orientation,
+    #[serde(default)]
+    pub transcoder: TranscoderConfig,
+    #[serde(default)]
+    pub restart_policy: RestartPolicy,
+    #[serde(default)]
+    pub motion: MotionConfig,
+    #[serde(default)]
+    pub recording: RecordingConfig,
     pub key: [u8; 16],
 }
 
@@ -289,7 +431,12 @@ impl Default for StreamState {
     fn default() -> Self {
         Self {
             enabled: Default::default(),
+            status: Default::default(),
             orientation: Default::default(),
+            transcoder: Default::default(),
+            restart_policy: Default::default(),
+            motion: Default::default(),
+            recording: Default::default(),
             key: rand::thread_rng().gen(),
         }
     }
@@ -307,6 +454,139 @@ fn get_stream(
 }
 
 
+/// Retrieves diagnostic information about the video stream's transcoder
+fn get_stream_diagnostics(
+    stream: Data<RwLock<Stream>>,
+) -> Result<Json<HostDiagnostics>> {
+
+    let stream = do_read!(stream);
+
+    Ok(Json(stream.diagnostics()))
+}
+
+
+/// Restarts the video stream's transcoder if it has failed
+fn post_stream_reset(
+    stream: Data<RwLock<Stream>>,
+) -> Result<Json<StreamState>> {
+
+    let mut stream = do_write!(stream);
+
+    stream.reset()?;
+
+    Ok(Json(stream.state()))
+}
+
+
+/// Retrieves recent motion events for the video stream
+fn get_stream_events(
+    pool: Data<ConnectionPool>,
+) -> Result<Json<Vec<MotionEvent>>> {
+
+    let conn = pool.get()?;
+
+    Ok(Json(motion::recent_events(&conn)?))
+}
+
+
+/// Retrieves a JPEG image of the most recent keyframe of the video stream
+fn get_stream_snapshot(
+    stream: Data<RwLock<Stream>>,
+) -> Result<HttpResponse> {
+
+    let image = do_read!(stream).snapshot()?;
+
+    Ok(snapshot_response(image))
+}
+
+
+/// Builds a response containing a snapshot image
+pub fn snapshot_response(image: Vec<u8>) -> HttpResponse {
+
+    HttpResponse::Ok()
+        .content_type("image/jpeg")
+        .header(
+            header::CACHE_CONTROL,
+            format!("private, max-age={}", SNAPSHOT_MAX_AGE.as_secs()),
+        )
+        .body(image)
+}
+
+
+/// Retrieves recordings of the video stream
+fn get_stream_recordings(
+    pool: Data<ConnectionPool>,
+) -> Result<Json<Vec<Recording>>> {
+
+    let conn = pool.get()?;
+
+    Ok(Json(recording::list(&conn)?))
+}
+
+
+/// Retrieves the segments making up a recording of the video stream
+fn get_stream_recording_segments(
+    pool: Data<ConnectionPool>,
+    path: web::Path<(i32,)>,
+) -> Result<Json<Vec<Segment>>> {
+
+    let id = path.0;
+
+    // Ensure the recording exists
+    let conn = pool.get()?;
+    recording::find(id, &conn)?;
+
+    Ok(Json(recording::read_segments(id)?))
+}
+
+
+/// Retrieves a single (encrypted) segment of a recording of the video stream
+fn get_stream_recording_segment(
+    path: web::Path<(i32, String)>,
+) -> Result<HttpResponse> {
+
+    let data = recording::read_segment(path.0, &path.1)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("video/mp2t")
+        .body(data))
+}
+
+
+/// Downloads a recording of the video stream as an MPEG transport stream
+fn get_stream_recording_download(
+    pool: Data<ConnectionPool>,
+    stream: Data<RwLock<Stream>>,
+    path: web::Path<(i32,)>,
+) -> Result<HttpResponse> {
+
+    let id = path.0;
+
+    // Ensure the recording exists
+    let conn = pool.get()?;
+    recording::find(id, &conn)?;
+
+    let key = do_read!(stream).key;
+    let export = recording::export(id, &key)?;
+
+    Ok(recording::download_response(id, export))
+}
+
+
+/// Deletes a recording of the video stream
+fn delete_stream_recording(
+    pool: Data<ConnectionPool>,
+    path: web::Path<(i32,)>,
+) -> Result<HttpResponse> {
+
+    let conn = pool.get()?;
+
+    recording::delete(path.0, &conn)?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+
 /// Updates video stream settings
 fn patch_stream(
     pool: Data<ConnectionPool>,
@@ -328,7 +608,9 @@ fn patch_stream(
 ///
 /// This function must be called exactly once over the lifetime of the current
 /// process.
-pub fn initialize(conn: &PooledConnection, templates: &Tera) -> Result<Stream> {
+pub fn initialize(pool: &ConnectionPool, templates: &Tera) -> Result<Stream> {
+
+    let conn = &pool.get()?;
 
     trace!("loading stream settings");
     let state: StreamState = match settings::get(STREAM_STATE_SETTING, conn)? {
@@ -348,34 +630,112 @@ pub fn initialize(conn: &PooledConnection, templates: &Tera) -> Result<Stream> {
     fs::write(&hls_key_path, state.key)?;
     let hls_key_info = format!("stream.key\n{}\n", hls_key_path);
     let hls_key_info_path = format!("{}/stream.keyinfo", hls_key_dir);
-    fs::write(hls_key_info_path, hls_key_info)?;
+    fs::write(&hls_key_info_path, hls_key_info)?;
 
     trace!("initializing stream");
-    let mut transcoder = ProcHost::new(make_command(state.orientation)?);
+    let cmd = transcoder::make_command(
+        state.orientation,
+        &state.transcoder,
+        &hls_key_info_path,
+        state.motion.enabled,
+    )?;
+    let shutdown = transcoder::make_shutdown_policy(&state.transcoder)?;
+    let mut transcoder = ProcHost::new(cmd, state.restart_policy, shutdown);
     if state.enabled {
         debug!("starting transcoder");
+        fs::create_dir_all(&state.transcoder.output_dir)?;
         transcoder.start()?;
-        write_proxy_config(templates)?;
+        write_proxy_config(templates, &state.transcoder)?;
     } else {
         clear_proxy_config()?;
     }
 
     proxy::reload()?;
 
+    trace!("initializing motion detector");
+    let motion = MotionDetector::new(state.motion, pool.clone())?;
+
+    trace!("initializing recorder");
+    let recorder = Recorder::new(state.recording, &state.transcoder.output_dir, pool.clone())?;
+
     Ok(Stream {
         orientation: state.orientation,
+        config: state.transcoder,
         transcoder,
+        motion,
+        recorder,
+        snapshot: Mutex::new(None),
         key: state.key,
+        key_info_path: hls_key_info_path,
     })
 }
 
 
-/// Configures the */stream* API resource
+/// Configures the */stream* API resources
+///
+/// Only the paired portal may use these resources, so requests must be signed.
 pub fn configure_api(service: &mut ServiceConfig) {
 
+    pairing::configure_api(service);
+
     service.service(
         web::resource("/stream")
             .route(web::get().to(get_stream))
             .route(web::patch().to(patch_stream))
+            .wrap(SignatureMiddleware)
+    );
+
+    service.service(
+        web::resource("/stream/diagnostics")
+            .route(web::get().to(get_stream_diagnostics))
+            .wrap(SignatureMiddleware)
+    );
+
+    service.service(
+        web::resource("/stream/events")
+            .route(web::get().to(get_stream_events))
+            .wrap(SignatureMiddleware)
+    );
+
+    service.service(
+        web::resource("/stream/snapshot.jpg")
+            .route(web::get().to(get_stream_snapshot))
+            .wrap(SignatureMiddleware)
+    );
+
+    service.service(
+        web::resource("/stream/recordings")
+            .route(web::get().to(get_stream_recordings))
+            .wrap(SignatureMiddleware)
+    );
+
+    service.service(
+        web::resource("/stream/recordings/{id}")
+            .route(web::delete().to(delete_stream_recording))
+            .wrap(SignatureMiddleware)
+    );
+
+    service.service(
+        web::resource("/stream/recordings/{id}/segments")
+            .route(web::get().to(get_stream_recording_segments))
+            .wrap(SignatureMiddleware)
+    );
+
+    service.service(
+        web::resource("/stream/recordings/{id}/segments/{file}")
+            .route(web::get().to(get_stream_recording_segment))
+            .wrap(SignatureMiddleware)
+    );
+
+    service.service(
+        web::resource("/stream/recordings/{id}/download")
+            .route(web::get().to(get_stream_recording_download))
+            .wrap(SignatureMiddleware)
+    );
+
+    service.service(
+        web::resource("/stream/reset")
+            .route(web::post().to(post_stream_reset))
+            .wrap(SignatureMiddleware)
     );
 }
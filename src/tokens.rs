@@ -0,0 +1,321 @@
+//! API tokens
+//!
+//! Machine clients such as home automation scripts authenticate using long
+//! lived tokens passed in an `Authorization: Bearer` header, rather than
+//! logging in to obtain a session cookie. Tokens are random, so they are stored
+//! as a fast SHA-256 hash rather than with Argon2 like passwords.
+
+
+use std::io::Write;
+
+use actix_web::HttpResponse;
+use actix_web::http::StatusCode;
+use actix_web::web::{self, Data, Json, ServiceConfig};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::backend::Backend;
+use diesel::deserialize::{self, FromSql};
+use diesel::prelude::*;
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::Integer;
+use log::{debug, info, trace};
+use openssl::sha::sha256;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::db::{ConnectionPool, PooledConnection};
+use crate::db::schema::{api_tokens, users};
+use crate::error::{Error, Result};
+use crate::users::{AuthenticatedUser, AuthenticationMiddleware, Role};
+
+
+/// Tokens are only marked as used once per interval, to avoid writing to the
+/// database on every request
+const LAST_USED_RESOLUTION_MINUTES: i64 = 1;
+
+
+/// Determines what a token may be used for
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[derive(AsExpression, FromSqlRow)]
+#[sql_type = "Integer"]
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TokenScope {
+
+    /// Anything the token's owner may do
+    #[default]
+    Full,
+
+    /// Only requests which do not change anything (e.g. viewing cameras)
+    ReadOnly,
+}
+
+impl<B> FromSql<Integer, B> for TokenScope
+where
+    B: Backend,
+    i32: FromSql<Integer, B>,
+{
+    fn from_sql(bytes: Option<&B::RawValue>) -> deserialize::Result<Self> {
+        match i32::from_sql(bytes)? {
+            0 => Ok(Self::Full),
+            1 => Ok(Self::ReadOnly),
+            other => Err(format!("Unrecognized value \"{}\"", other).into()),
+        }
+    }
+}
+
+impl<B> ToSql<Integer, B> for TokenScope
+where
+    B: Backend,
+    i32: ToSql<Integer, B>,
+{
+    fn to_sql<W: Write>(&self, out: &mut Output<W, B>) -> serialize::Result {
+        let val = match *self {
+            Self::Full => 0,
+            Self::ReadOnly => 1,
+        };
+
+        val.to_sql(out)
+    }
+}
+
+
+/// Representation of an API token
+#[derive(Serialize)]
+#[derive(Identifiable, Queryable)]
+#[serde(rename_all = "camelCase")]
+#[table_name = "api_tokens"]
+pub struct ApiToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    #[serde(skip)]
+    pub token_hash: String,
+    pub scope: TokenScope,
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+}
+
+
+#[derive(Insertable)]
+#[table_name = "api_tokens"]
+struct NewApiToken<'a> {
+    user_id: i32,
+    name: &'a str,
+    token_hash: &'a str,
+    scope: TokenScope,
+    created_at: NaiveDateTime,
+    expires_at: Option<NaiveDateTime>,
+}
+
+
+/// Computes the hash under which a token is stored
+fn hash_token(token: &str) -> String {
+
+    base64::encode(&sha256(token.as_bytes()))
+}
+
+
+/// Looks up the (unexpired) token presented by a client
+///
+/// Returns the token's record, or `None` if the token is not valid.
+pub fn authenticate(token: &str, conn: &PooledConnection) -> Result<Option<ApiToken>> {
+
+    let token: Option<ApiToken> = api_tokens::table
+        .filter(api_tokens::token_hash.eq(hash_token(token)))
+        .first(conn)
+        .optional()?;
+    let token = match token {
+        Some(token) => token,
+        None => return Ok(None),
+    };
+
+    let now = Utc::now().naive_utc();
+    if matches!(token.expires_at, Some(expires_at) if expires_at <= now) {
+        debug!("token {} has expired", token.id);
+        return Ok(None);
+    }
+
+    let is_stale = token.last_used_at
+        .map(|used| now - used > Duration::minutes(LAST_USED_RESOLUTION_MINUTES))
+        .unwrap_or(true);
+    if is_stale {
+        trace!("updating last used time of token {}", token.id);
+        diesel::update(&token)
+            .set(api_tokens::last_used_at.eq(now))
+            .execute(conn)?;
+    }
+
+    Ok(Some(token))
+}
+
+
+/// Revokes all tokens belonging to the specified user
+pub fn revoke_all(user_id: i32, conn: &PooledConnection) -> Result<()> {
+
+    diesel::delete(api_tokens::table.filter(api_tokens::user_id.eq(user_id)))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+
+/// Token representation including the owner's username
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenListing {
+    #[serde(flatten)]
+    token: ApiToken,
+    username: String,
+}
+
+
+/// Retrieves tokens belonging to the given user, or all tokens if `user` is
+/// an admin
+pub fn visible(user: &AuthenticatedUser, conn: &PooledConnection) -> Result<Vec<TokenListing>> {
+
+    let mut query = api_tokens::table
+        .inner_join(users::table)
+        .select((api_tokens::all_columns, users::username))
+        .order(api_tokens::created_at.desc())
+        .into_boxed();
+
+    if !user.role.includes(Role::Admin) {
+        query = query.filter(api_tokens::user_id.eq(user.id));
+    }
+
+    let tokens: Vec<(ApiToken, String)> = query.load(conn)?;
+    let tokens = tokens.into_iter()
+        .map(|(token, username)| TokenListing { token, username })
+        .collect();
+
+    Ok(tokens)
+}
+
+
+//#region API
+
+/// Retrieves the current user's tokens (or every user's tokens, for admins)
+fn get_tokens(
+    pool: Data<ConnectionPool>,
+    current_user: AuthenticatedUser,
+) -> Result<Json<Vec<TokenListing>>>
+{
+    let conn = pool.get()?;
+
+    Ok(Json(visible(&current_user, &conn)?))
+}
+
+
+/// Token representation required by PUT requests
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PutTokenBody {
+    name: String,
+    #[serde(default)]
+    scope: TokenScope,
+    expires_at: Option<NaiveDateTime>,
+}
+
+
+/// Response returned after successful token creation
+///
+/// This is the only time the token itself is revealed.
+#[derive(Serialize)]
+struct PutTokenResponse {
+    #[serde(flatten)]
+    token: ApiToken,
+    secret: String,
+}
+
+
+/// Creates a new token for the current user
+fn put_token(
+    pool: Data<ConnectionPool>,
+    current_user: AuthenticatedUser,
+    body: Json<PutTokenBody>,
+) -> Result<Json<PutTokenResponse>>
+{
+    let conn = pool.get()?;
+    let now = Utc::now().naive_utc();
+
+    if body.name.is_empty() {
+        return Error::web(StatusCode::BAD_REQUEST, "token name must not be empty");
+    }
+    if matches!(body.expires_at, Some(expires_at) if expires_at <= now) {
+        return Error::web(StatusCode::BAD_REQUEST, "token must expire in the future");
+    }
+
+    let secret: [u8; 32] = rand::thread_rng().gen();
+    let secret = base64::encode(&secret);
+    let token_hash = hash_token(&secret);
+
+    debug!("adding new token for user {} to database", current_user.id);
+    let new_token = NewApiToken {
+        user_id: current_user.id,
+        name: &body.name,
+        token_hash: &token_hash,
+        scope: body.scope,
+        created_at: now,
+        expires_at: body.expires_at,
+    };
+    diesel::insert_into(api_tokens::table)
+        .values(&new_token)
+        .execute(&conn)?;
+
+    let token: ApiToken = api_tokens::table
+        .filter(api_tokens::token_hash.eq(&token_hash))
+        .first(&conn)?;
+
+    info!("created new token {} for user {}", token.id, current_user.id);
+
+    Ok(Json(PutTokenResponse { token, secret }))
+}
+
+
+/// Revokes the specified token
+///
+/// Users may revoke their own tokens, and admins may revoke any token.
+fn delete_token(
+    pool: Data<ConnectionPool>,
+    current_user: AuthenticatedUser,
+    path: web::Path<(i32,)>,
+) -> Result<HttpResponse>
+{
+    let id = path.0;
+    let conn = pool.get()?;
+
+    let token: ApiToken = api_tokens::table.find(id)
+        .get_result(&conn)?;
+    if token.user_id != current_user.id {
+        current_user.require(Role::Admin)?;
+    }
+
+    debug!("deleting token {} from database", id);
+    diesel::delete(&token)
+        .execute(&conn)?;
+
+    info!("revoked token {} of user {}", id, token.user_id);
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+
+/// Configures the */tokens* API resource
+pub fn configure_api(service: &mut ServiceConfig) {
+
+    service.service(
+        web::resource("/tokens")
+            .route(web::get().to(get_tokens))
+            .route(web::put().to(put_token))
+            .wrap(AuthenticationMiddleware::reject())
+    );
+
+    service.service(
+        web::resource("/tokens/{id}")
+            .route(web::delete().to(delete_token))
+            .wrap(AuthenticationMiddleware::reject())
+    );
+}
+
+//#endregion
@@ -0,0 +1,859 @@
+//! Two-factor authentication using time-based one-time passwords
+//!
+//! Users may enroll an authenticator app using the RFC 6238 TOTP algorithm, after
+//! which logging in requires a code from the app in addition to the password.
+//! TOTP secrets must be available in plain form to verify codes, so they are
+//! stored encrypted with a key kept in a file in the state directory, outside
+//! of the database. Each user also gets a set of single-use recovery codes, in
+//! case the authenticator app is lost.
+
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::{HttpRequest, HttpResponse};
+use actix_web::http::StatusCode;
+use actix_web::web::{self, Data, Json, ServiceConfig};
+use diesel::prelude::*;
+use futures::{future, Future};
+use lazy_static::lazy_static;
+use log::{debug, info, trace};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sha::sha256;
+use openssl::sign::Signer;
+use openssl::symm::{self, Cipher};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::audit::{self, AuthEventKind};
+use crate::db::{ConnectionPool, PooledConnection};
+use crate::db::schema::{totp_credentials, totp_recovery_codes, users};
+use crate::do_lock;
+use crate::error::{Error, Result};
+use crate::settings;
+use crate::users::{self as user_api, AuthenticatedUser, AuthenticationMiddleware, HashingFuture, Role};
+
+
+const ISSUER: &str = "LunaCam";
+
+/// Length of each time step
+const STEP_SECS: u64 = 30;
+
+/// Number of digits in a code
+const DIGITS: u32 = 6;
+
+/// Codes from this many steps before or after the current one are accepted, to
+/// allow for clock drift between the server and the authenticator app
+const ALLOWED_DRIFT_STEPS: i64 = 1;
+
+const SECRET_LENGTH: usize = 20;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_CHARS: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+
+//#region Secret encryption
+
+lazy_static! {
+    static ref ENCRYPTION_KEY: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+}
+
+
+/// Setting in which earlier versions kept the encryption key
+const LEGACY_KEY_SETTING: &str = "totpEncryptionKey";
+
+const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+
+
+/// Gets the path of the file containing the key with which TOTP secrets are
+/// encrypted
+///
+/// The key is kept out of the database, so that a copy of the database alone
+/// (e.g. a backup) does not reveal anyone's TOTP secret.
+fn key_path() -> Result<String> {
+
+    let state_dir = env::var("STATE_DIRECTORY")?;
+
+    Ok(format!("{}/totp.key", state_dir))
+}
+
+
+/// Writes a new key file, readable only by this user
+fn write_key(path: &str, key: &[u8]) -> Result<()> {
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(key)?;
+
+    Ok(())
+}
+
+
+fn get_encryption_key(conn: &PooledConnection) -> Result<Vec<u8>> {
+
+    let mut key = do_lock!(ENCRYPTION_KEY);
+
+    if let Some(key) = key.as_ref() {
+        trace!("retrieving cached TOTP encryption key");
+        return Ok(key.clone());
+    }
+
+    let path = key_path()?;
+    debug!("loading TOTP encryption key from {}", path);
+    match fs::read(&path) {
+        Ok(new_key) if new_key.len() == KEY_LENGTH => {
+            key.replace(new_key.clone());
+            return Ok(new_key);
+        },
+        Ok(_) => return Error::web(StatusCode::INTERNAL_SERVER_ERROR, "TOTP encryption key is malformed"),
+        Err(ref err) if err.kind() == ErrorKind::NotFound => (),
+        Err(err) => return Err(err.into()),
+    }
+
+    // Keys of earlier versions are moved out of the database, so that secrets
+    // encrypted with them can still be read
+    let legacy_key = settings::get::<Option<String>>(LEGACY_KEY_SETTING, conn)?.flatten();
+    let new_key = match legacy_key {
+        Some(ref key_b64) => {
+            info!("moving TOTP encryption key from database to {}", path);
+            base64::decode(key_b64)?
+        },
+        None => {
+            debug!("TOTP encryption key not found, generating a new one");
+            rand::thread_rng().gen::<[u8; KEY_LENGTH]>().to_vec()
+        },
+    };
+    write_key(&path, &new_key)?;
+    if legacy_key.is_some() {
+        settings::set(LEGACY_KEY_SETTING, &None::<String>, conn)?;
+    }
+    key.replace(new_key.clone());
+
+    Ok(new_key)
+}
+
+
+/// Encrypts a TOTP secret for storage
+///
+/// The result consists of the nonce, then the authentication tag, then the
+/// ciphertext.
+fn encrypt_secret(secret: &[u8], conn: &PooledConnection) -> Result<Vec<u8>> {
+
+    let key = get_encryption_key(conn)?;
+    let nonce: [u8; NONCE_LENGTH] = rand::thread_rng().gen();
+    let mut tag = [0; TAG_LENGTH];
+
+    let ciphertext = symm::encrypt_aead(
+        Cipher::aes_256_gcm(),
+        &key,
+        Some(&nonce),
+        &[],
+        secret,
+        &mut tag,
+    )?;
+
+    let mut encrypted = Vec::with_capacity(NONCE_LENGTH + TAG_LENGTH + ciphertext.len());
+    encrypted.extend_from_slice(&nonce);
+    encrypted.extend_from_slice(&tag);
+    encrypted.extend_from_slice(&ciphertext);
+
+    Ok(encrypted)
+}
+
+
+/// Decrypts a TOTP secret produced by `encrypt_secret`
+fn decrypt_secret(encrypted: &[u8], conn: &PooledConnection) -> Result<Vec<u8>> {
+
+    if encrypted.len() < NONCE_LENGTH + TAG_LENGTH {
+        return Error::web(StatusCode::INTERNAL_SERVER_ERROR, "stored TOTP secret is malformed");
+    }
+
+    let key = get_encryption_key(conn)?;
+    let (nonce, rest) = encrypted.split_at(NONCE_LENGTH);
+    let (tag, ciphertext) = rest.split_at(TAG_LENGTH);
+
+    let secret = symm::decrypt_aead(
+        Cipher::aes_256_gcm(),
+        &key,
+        Some(nonce),
+        &[],
+        ciphertext,
+        tag,
+    )?;
+
+    Ok(secret)
+}
+
+//#endregion
+
+
+//#region Codes
+
+fn current_step() -> i64 {
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time is before the Unix epoch")
+        .as_secs();
+
+    (now / STEP_SECS) as i64
+}
+
+
+/// Computes an RFC 4226 HOTP code (with SHA-1) of the given length
+fn hotp(secret: &[u8], counter: i64, digits: u32) -> Result<u32> {
+
+    let key = PKey::hmac(secret)?;
+    let mut signer = Signer::new(MessageDigest::sha1(), &key)?;
+    signer.update(&counter.to_be_bytes())?;
+    let hmac = signer.sign_to_vec()?;
+
+    // Dynamic truncation
+    let offset = (hmac[hmac.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([
+        hmac[offset] & 0x7f,
+        hmac[offset + 1],
+        hmac[offset + 2],
+        hmac[offset + 3],
+    ]);
+
+    Ok(value % 10u32.pow(digits))
+}
+
+
+/// Computes the code for the given time step
+fn code_at(secret: &[u8], step: i64) -> Result<u32> {
+
+    hotp(secret, step, DIGITS)
+}
+
+
+/// Checks a code against the secret
+///
+/// Returns the time step matched by the code, or `None` if the code is not
+/// valid. Steps up to and including `last_step` are rejected, so that a code
+/// cannot be used more than once.
+fn verify_code(secret: &[u8], code: &str, last_step: Option<i64>) -> Result<Option<i64>> {
+
+    let code = match code.trim().parse::<u32>() {
+        Ok(code) => code,
+        Err(_) => return Ok(None),
+    };
+
+    let now = current_step();
+    for step in (now - ALLOWED_DRIFT_STEPS)..=(now + ALLOWED_DRIFT_STEPS) {
+        if matches!(last_step, Some(last) if step <= last) {
+            continue;
+        }
+        if code_at(secret, step)? == code {
+            return Ok(Some(step));
+        }
+    }
+
+    Ok(None)
+}
+
+
+/// Encodes data as unpadded RFC 4648 base32, as expected by authenticator apps
+fn base32_encode(data: &[u8]) -> String {
+
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+    let mut encoded = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for &byte in data {
+        buffer = (buffer << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+
+    encoded
+}
+
+
+/// Percent-encodes a value for use in a provisioning URI
+fn uri_encode(value: &str) -> String {
+
+    value.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' =>
+                (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+
+/// Builds the *otpauth://* URI used to enroll an authenticator app, usually by
+/// scanning it as a QR code
+fn provisioning_uri(username: &str, secret: &str) -> String {
+
+    format!(
+        "otpauth://totp/{issuer}:{user}?secret={secret}&issuer={issuer}&digits={digits}&period={period}",
+        issuer = ISSUER,
+        user = uri_encode(username),
+        secret = secret,
+        digits = DIGITS,
+        period = STEP_SECS,
+    )
+}
+
+//#endregion
+
+
+//#region Recovery codes
+
+/// Normalizes and hashes a recovery code for storage
+///
+/// Recovery codes are random, so like API tokens they are stored as a fast
+/// SHA-256 hash.
+fn hash_recovery_code(code: &str) -> String {
+
+    let code: String = code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    base64::encode(&sha256(code.as_bytes()))
+}
+
+
+#[derive(Insertable)]
+#[table_name = "totp_recovery_codes"]
+struct NewRecoveryCode<'a> {
+    user_id: i32,
+    code_hash: &'a str,
+}
+
+
+/// Replaces the specified user's recovery codes with new ones
+///
+/// The new codes are returned, and cannot be retrieved again afterwards.
+fn generate_recovery_codes(user_id: i32, conn: &PooledConnection) -> Result<Vec<String>> {
+
+    let mut rng = rand::thread_rng();
+    let mut random_chars = |count: usize| -> String {
+        (0..count)
+            .map(|_| RECOVERY_CODE_CHARS[rng.gen_range(0, RECOVERY_CODE_CHARS.len())] as char)
+            .collect()
+    };
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| format!("{}-{}", random_chars(5), random_chars(5)))
+        .collect();
+
+    conn.transaction::<_, Error, _>(|| {
+        diesel::delete(totp_recovery_codes::table.filter(totp_recovery_codes::user_id.eq(user_id)))
+            .execute(conn)?;
+
+        // SQLite does not support batch inserts
+        for code in &codes {
+            let code_hash = hash_recovery_code(code);
+            diesel::insert_into(totp_recovery_codes::table)
+                .values(&NewRecoveryCode { user_id, code_hash: &code_hash })
+                .execute(conn)?;
+        }
+
+        Ok(())
+    })?;
+
+    Ok(codes)
+}
+
+
+/// Consumes one of the specified user's recovery codes
+///
+/// Returns whether the code was valid.
+fn use_recovery_code(user_id: i32, code: &str, conn: &PooledConnection) -> Result<bool> {
+
+    let count = diesel::delete(
+        totp_recovery_codes::table
+            .filter(totp_recovery_codes::user_id.eq(user_id))
+            .filter(totp_recovery_codes::code_hash.eq(hash_recovery_code(code)))
+    )
+        .execute(conn)?;
+
+    if count > 0 {
+        info!("user {} used a recovery code", user_id);
+    }
+
+    Ok(count > 0)
+}
+
+//#endregion
+
+
+//#region Login
+
+/// TOTP enrollment of a user
+#[derive(AsChangeset, Identifiable, Insertable, Queryable)]
+#[table_name = "totp_credentials"]
+#[primary_key(user_id)]
+#[changeset_options(treat_none_as_null = "true")]
+struct TotpCredential {
+    user_id: i32,
+
+    /// Encrypted TOTP secret
+    secret: Vec<u8>,
+
+    /// Whether enrollment has been confirmed with a valid code. Until it is,
+    /// the credential is not required to log in.
+    enabled: bool,
+
+    /// Time step of the most recently used code
+    last_step: Option<i64>,
+}
+
+
+fn get_credential(user_id: i32, conn: &PooledConnection) -> Result<Option<TotpCredential>> {
+
+    let credential = totp_credentials::table.find(user_id)
+        .first(conn)
+        .optional()?;
+
+    Ok(credential)
+}
+
+
+/// Outcome of checking the second factor of a login
+#[derive(Debug, PartialEq)]
+pub enum SecondFactor {
+
+    /// User has not enabled two-factor authentication
+    NotEnrolled,
+
+    /// User has enabled two-factor authentication, but no code was provided
+    Missing,
+
+    /// Code is neither a valid TOTP code nor an unused recovery code
+    Invalid,
+
+    /// Code is valid
+    Valid,
+}
+
+
+/// Checks the second factor presented by a user who is logging in
+///
+/// `code` may be either a TOTP code or a recovery code. Either kind of code is
+/// used up by a successful check.
+pub fn check(user_id: i32, code: Option<&str>, conn: &PooledConnection) -> Result<SecondFactor> {
+
+    let mut credential = match get_credential(user_id, conn)? {
+        Some(credential) if credential.enabled => credential,
+        _ => return Ok(SecondFactor::NotEnrolled),
+    };
+
+    let code = match code.map(str::trim).filter(|code| !code.is_empty()) {
+        Some(code) => code,
+        None => return Ok(SecondFactor::Missing),
+    };
+
+    // TOTP codes are all digits, whereas recovery codes always contain a dash
+    if !code.contains('-') {
+        let secret = decrypt_secret(&credential.secret, conn)?;
+        if let Some(step) = verify_code(&secret, code, credential.last_step)? {
+            credential.last_step = Some(step);
+            diesel::update(&credential)
+                .set(&credential)
+                .execute(conn)?;
+            return Ok(SecondFactor::Valid);
+        }
+    } else if use_recovery_code(user_id, code, conn)? {
+        return Ok(SecondFactor::Valid);
+    }
+
+    Ok(SecondFactor::Invalid)
+}
+
+
+/// Two-factor authentication status of a user
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TotpStatus {
+    enabled: bool,
+    recovery_codes_remaining: i64,
+}
+
+
+/// Retrieves the two-factor authentication status of the specified user
+pub fn status(user_id: i32, conn: &PooledConnection) -> Result<TotpStatus> {
+
+    let enabled = get_credential(user_id, conn)?
+        .map(|credential| credential.enabled)
+        .unwrap_or(false);
+    let recovery_codes_remaining = totp_recovery_codes::table
+        .filter(totp_recovery_codes::user_id.eq(user_id))
+        .count()
+        .get_result(conn)?;
+
+    Ok(TotpStatus { enabled, recovery_codes_remaining })
+}
+
+
+/// Removes the specified user's TOTP enrollment and recovery codes
+pub fn clear(user_id: i32, conn: &PooledConnection) -> Result<()> {
+
+    diesel::delete(totp_recovery_codes::table.filter(totp_recovery_codes::user_id.eq(user_id)))
+        .execute(conn)?;
+    diesel::delete(totp_credentials::table.filter(totp_credentials::user_id.eq(user_id)))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+//#endregion
+
+
+//#region API
+
+/// Fails with *403 Forbidden* unless the current user is the specified user
+fn require_self(current_user: &AuthenticatedUser, user_id: i32) -> Result<()> {
+
+    if current_user.id == user_id {
+        Ok(())
+    } else {
+        Error::web(StatusCode::FORBIDDEN, "two-factor authentication may only be set up by its user")
+    }
+}
+
+
+fn get_username(user_id: i32, conn: &PooledConnection) -> Result<String> {
+
+    let username = users::table.find(user_id)
+        .select(users::username)
+        .first(conn)?;
+
+    Ok(username)
+}
+
+
+/// Retrieves the two-factor authentication status of the specified user
+///
+/// Users other than admins may only retrieve their own status.
+fn get_totp(
+    pool: Data<ConnectionPool>,
+    current_user: AuthenticatedUser,
+    path: web::Path<(i32,)>,
+) -> Result<Json<TotpStatus>>
+{
+    let id = path.0;
+    if id != current_user.id {
+        current_user.require(Role::Admin)?;
+    }
+
+    let conn = pool.get()?;
+
+    Ok(Json(status(id, &conn)?))
+}
+
+
+/// Response returned when beginning enrollment
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PutTotpResponse {
+
+    /// Base32-encoded secret, for entering into an authenticator app by hand
+    secret: String,
+
+    provisioning_uri: String,
+}
+
+
+/// Begins enrolling the current user in two-factor authentication
+///
+/// A new secret is generated, but is not required to log in until enrollment is
+/// confirmed with a valid code.
+fn put_totp(
+    pool: Data<ConnectionPool>,
+    current_user: AuthenticatedUser,
+    path: web::Path<(i32,)>,
+) -> Result<Json<PutTotpResponse>>
+{
+    let id = path.0;
+    require_self(&current_user, id)?;
+
+    let conn = pool.get()?;
+    if matches!(get_credential(id, &conn)?, Some(ref credential) if credential.enabled) {
+        return Error::web(StatusCode::CONFLICT, "two-factor authentication is already enabled");
+    }
+
+    let secret: [u8; SECRET_LENGTH] = rand::thread_rng().gen();
+    let credential = TotpCredential {
+        user_id: id,
+        secret: encrypt_secret(&secret, &conn)?,
+        enabled: false,
+        last_step: None,
+    };
+
+    debug!("storing pending TOTP secret for user {}", id);
+    clear(id, &conn)?;
+    diesel::insert_into(totp_credentials::table)
+        .values(&credential)
+        .execute(&conn)?;
+
+    let secret = base32_encode(&secret);
+    let provisioning_uri = provisioning_uri(&current_user.username, &secret);
+
+    Ok(Json(PutTotpResponse { secret, provisioning_uri }))
+}
+
+
+/// Code confirming that an authenticator app was set up correctly
+#[derive(Deserialize)]
+struct PostConfirmationBody {
+    code: String,
+}
+
+
+/// Recovery codes generated when enrollment is confirmed
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PostConfirmationResponse {
+    recovery_codes: Vec<String>,
+}
+
+
+/// Completes enrollment of the current user in two-factor authentication
+fn post_totp_confirmation(
+    pool: Data<ConnectionPool>,
+    current_user: AuthenticatedUser,
+    req: HttpRequest,
+    path: web::Path<(i32,)>,
+    body: Json<PostConfirmationBody>,
+) -> Result<Json<PostConfirmationResponse>>
+{
+    let id = path.0;
+    require_self(&current_user, id)?;
+
+    let conn = pool.get()?;
+    let mut credential = match get_credential(id, &conn)? {
+        Some(credential) if !credential.enabled => credential,
+        Some(_) => return Error::web(
+            StatusCode::CONFLICT,
+            "two-factor authentication is already enabled",
+        ),
+        None => return Error::web(StatusCode::NOT_FOUND, "two-factor enrollment was not started"),
+    };
+
+    let secret = decrypt_secret(&credential.secret, &conn)?;
+    let step = match verify_code(&secret, &body.code, None)? {
+        Some(step) => step,
+        None => return Error::web(StatusCode::BAD_REQUEST, "invalid two-factor code"),
+    };
+
+    credential.enabled = true;
+    credential.last_step = Some(step);
+    diesel::update(&credential)
+        .set(&credential)
+        .execute(&conn)?;
+    let recovery_codes = generate_recovery_codes(id, &conn)?;
+
+    audit::record(AuthEventKind::TotpEnabled, &current_user.username, None, &req, &conn);
+    info!("enabled two-factor authentication for user {}", id);
+
+    Ok(Json(PostConfirmationResponse { recovery_codes }))
+}
+
+
+/// Proof of identity required to disable two-factor authentication
+#[derive(Deserialize)]
+struct DeleteTotpBody {
+
+    /// Current TOTP code or unused recovery code of the user
+    code: Option<String>,
+
+    /// Password of the current user
+    password: Option<String>,
+}
+
+
+/// Checks the proof presented to disable the specified user's two-factor
+/// authentication
+///
+/// Users disabling their own may present a code from their authenticator app
+/// (or a recovery code) instead of their password, whereas admins resetting
+/// another user's must present their own password. Pending enrollments are not
+/// yet required to log in, so they may be cancelled without proof.
+fn check_delete_proof(
+    pool: &ConnectionPool,
+    current_user: &AuthenticatedUser,
+    user_id: i32,
+    body: &DeleteTotpBody,
+) -> Result<HashingFuture<bool>>
+{
+    if user_id != current_user.id {
+        current_user.require(Role::Admin)?;
+    }
+
+    let conn = pool.get()?;
+    if !matches!(get_credential(user_id, &conn)?, Some(ref credential) if credential.enabled) {
+        return Ok(Box::new(future::ok(true)));
+    }
+
+    if user_id == current_user.id {
+        if let Some(ref code) = body.code {
+            let valid = check(user_id, Some(code), &conn)? == SecondFactor::Valid;
+            return Ok(Box::new(future::ok(valid)));
+        }
+    }
+
+    let password = match body.password {
+        Some(ref password) => password.clone(),
+        None => return Error::web(StatusCode::BAD_REQUEST, "two-factor code or password is required"),
+    };
+    let pwhash: String = users::table.find(current_user.id)
+        .select(users::pwhash)
+        .first(&conn)?;
+
+    Ok(user_api::verify_password(pwhash, password, pool))
+}
+
+
+/// Disables two-factor authentication for the specified user
+///
+/// Users may disable their own two-factor authentication, and admins may reset
+/// it for users who have lost their authenticator app and recovery codes. Either
+/// way, proof of identity is required (see `check_delete_proof`).
+fn delete_totp(
+    pool: Data<ConnectionPool>,
+    current_user: AuthenticatedUser,
+    req: HttpRequest,
+    path: web::Path<(i32,)>,
+    body: Json<DeleteTotpBody>,
+) -> HashingFuture<HttpResponse>
+{
+    let id = path.0;
+
+    let proven = match check_delete_proof(&pool, &current_user, id, &body) {
+        Ok(proven) => proven,
+        Err(err) => return Box::new(future::err(err)),
+    };
+
+    let future = proven.and_then(move |proven| {
+
+        if !proven {
+            return Error::web(StatusCode::FORBIDDEN, "two-factor code or password is incorrect");
+        }
+
+        let conn = pool.get()?;
+        let username = get_username(id, &conn)?;
+
+        debug!("removing TOTP enrollment of user {}", id);
+        clear(id, &conn)?;
+
+        audit::record(
+            AuthEventKind::TotpDisabled,
+            &username,
+            Some(&current_user.username),
+            &req,
+            &conn,
+        );
+        info!("disabled two-factor authentication for user {}", id);
+
+        Ok(HttpResponse::NoContent().finish())
+    });
+
+    Box::new(future)
+}
+
+
+/// Configures the */users/{id}/totp* API resources
+pub fn configure_api(service: &mut ServiceConfig) {
+
+    // Users may manage their own enrollment, so privileges are checked by the
+    // handlers
+    service.service(
+        web::resource("/users/{id}/totp")
+            .route(web::get().to(get_totp))
+            .route(web::put().to(put_totp))
+            .route(web::delete().to_async(delete_totp))
+            .wrap(AuthenticationMiddleware::reject())
+    );
+
+    service.service(
+        web::resource("/users/{id}/totp/confirmation")
+            .route(web::post().to(post_totp_confirmation))
+            .wrap(AuthenticationMiddleware::reject())
+    );
+}
+
+//#endregion
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+
+    /// Test vectors from RFC 6238 Appendix B, for the SHA-1 key
+    const RFC_6238_SECRET: &[u8] = b"12345678901234567890";
+    const RFC_6238_VECTORS: &[(u64, u32)] = &[
+        (59, 94287082),
+        (1111111109, 7081804),
+        (1111111111, 14050471),
+        (1234567890, 89005924),
+        (2000000000, 69279037),
+        (20000000000, 65353130),
+    ];
+
+    #[test]
+    fn rfc_6238_test_vectors() {
+
+        for &(time, expected) in RFC_6238_VECTORS {
+            let step = (time / STEP_SECS) as i64;
+            assert_eq!(hotp(RFC_6238_SECRET, step, 8).unwrap(), expected, "time {}", time);
+        }
+    }
+
+    #[test]
+    fn six_digit_codes_are_truncated_vectors() {
+
+        for &(time, expected) in RFC_6238_VECTORS {
+            let step = (time / STEP_SECS) as i64;
+            assert_eq!(code_at(RFC_6238_SECRET, step).unwrap(), expected % 1_000_000, "time {}", time);
+        }
+    }
+
+    #[test]
+    fn used_steps_are_rejected() {
+
+        let step = current_step();
+        let code = format!("{:06}", code_at(RFC_6238_SECRET, step).unwrap());
+
+        assert_eq!(verify_code(RFC_6238_SECRET, &code, None).unwrap(), Some(step));
+        assert_eq!(verify_code(RFC_6238_SECRET, &code, Some(step)).unwrap(), None);
+        assert_eq!(verify_code(RFC_6238_SECRET, "not a code", None).unwrap(), None);
+    }
+
+    #[test]
+    fn base32_matches_rfc_4648() {
+
+        // RFC 4648 section 10, without padding
+        assert_eq!(base32_encode(b""), "");
+        assert_eq!(base32_encode(b"f"), "MY");
+        assert_eq!(base32_encode(b"fo"), "MZXQ");
+        assert_eq!(base32_encode(b"foo"), "MZXW6");
+        assert_eq!(base32_encode(b"foob"), "MZXW6YQ");
+        assert_eq!(base32_encode(b"fooba"), "MZXW6YTB");
+        assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+    }
+}
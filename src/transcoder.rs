@@ -0,0 +1,664 @@
+//! Transcoder backends
+//!
+//! The transcoder is the child process which reads video from a capture device
+//! and writes it out as an HLS stream. Several interchangeable backends are
+//! available, each of which knows how to construct a `Command` that runs the
+//! transcoder for a given `TranscoderConfig`.
+
+
+use std::env;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use actix_web::http::StatusCode;
+use libc::c_int;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::motion;
+use crate::prochost::ShutdownPolicy;
+use crate::stream::Orientation;
+
+
+//#region Backend selection
+
+/// Identifies which implementation of `Transcoder` is used
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TranscoderBackend {
+    Ffmpeg,
+    Gstreamer,
+    TestPattern,
+}
+
+/// Debug builds generate a test pattern by default, so that a playable stream
+/// is available on workstations without a camera
+impl Default for TranscoderBackend {
+    fn default() -> Self {
+        if cfg!(debug_assertions) {
+            Self::TestPattern
+        } else {
+            Self::Ffmpeg
+        }
+    }
+}
+
+impl TranscoderBackend {
+
+    /// Gets the `Transcoder` implementing this backend
+    pub fn transcoder(self) -> &'static dyn Transcoder {
+        match self {
+            Self::Ffmpeg => &Ffmpeg,
+            Self::Gstreamer => &Gstreamer,
+            Self::TestPattern => &TestPattern,
+        }
+    }
+}
+
+//#endregion
+
+
+//#region Transcoder configuration
+
+/// Transcoder pipeline configuration
+///
+/// Describes where the transcoder reads video from, how that video is
+/// interpreted, and where the resulting HLS stream is written.
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct TranscoderConfig {
+
+    /// Path of the V4L2 capture device
+    pub device: String,
+
+    /// Width of captured video, in pixels
+    pub width: u32,
+
+    /// Height of captured video, in pixels
+    pub height: u32,
+
+    /// Capture framerate, in frames per second
+    pub framerate: u32,
+
+    /// Backend used to run the transcoder
+    pub backend: TranscoderBackend,
+
+    /// Pixel or compression format requested from the capture device
+    pub input_format: String,
+
+    /// FFmpeg encoder used when video must be re-encoded (e.g. to apply orientation)
+    pub encoder: String,
+
+    /// Directory to which the HLS playlist and segments are written
+    pub output_dir: String,
+
+    /// Whether HLS segments are encrypted with the stream's key
+    pub encrypt: bool,
+
+    /// Target duration of each HLS segment, in seconds
+    pub segment_length: u32,
+
+    /// Maximum number of segments listed in the HLS playlist
+    pub list_size: u32,
+
+    /// Time allowed for the transcoder to finalize its output after being
+    /// asked to stop, in milliseconds
+    pub grace_period_ms: u64,
+}
+
+impl Default for TranscoderConfig {
+    fn default() -> Self {
+        Self {
+            backend: Default::default(),
+            device: "/dev/video0".into(),
+            width: 1280,
+            height: 720,
+            framerate: 30,
+            input_format: "h264".into(),
+            encoder: "h264_omx".into(),
+            output_dir: "/dev/shm/lunacam/hls".into(),
+            encrypt: true,
+            segment_length: 2,
+            list_size: 5,
+            grace_period_ms: 5000,
+        }
+    }
+}
+
+impl TranscoderConfig {
+
+    /// Checks that this configuration describes a usable pipeline
+    pub fn validate(&self) -> Result<()> {
+
+        if self.device.is_empty() {
+            return Error::web(StatusCode::BAD_REQUEST, "device must not be empty");
+        }
+        if self.width == 0 || self.height == 0 {
+            return Error::web(StatusCode::BAD_REQUEST, "resolution must be nonzero");
+        }
+        if self.framerate == 0 {
+            return Error::web(StatusCode::BAD_REQUEST, "framerate must be nonzero");
+        }
+        if self.input_format.is_empty() || self.encoder.is_empty() {
+            return Error::web(StatusCode::BAD_REQUEST, "input format and encoder must not be empty");
+        }
+        if !Path::new(&self.output_dir).is_absolute() {
+            return Error::web(StatusCode::BAD_REQUEST, "output directory must be an absolute path");
+        }
+        if !is_safe_path(&self.output_dir) {
+            return Error::web(StatusCode::BAD_REQUEST, "output directory contains invalid characters");
+        }
+        if self.segment_length == 0 || self.list_size == 0 {
+            return Error::web(StatusCode::BAD_REQUEST, "segment length and list size must be nonzero");
+        }
+        if self.encrypt && self.backend == TranscoderBackend::Gstreamer {
+            return Error::web(StatusCode::BAD_REQUEST, "GStreamer transcoder cannot encrypt the stream");
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether video must be re-encoded to satisfy the given orientation
+    ///
+    /// Video captured in H.264 format can be copied directly into the HLS
+    /// stream. Any other input format, or any rotation of the video, requires
+    /// decoding and re-encoding each frame.
+    fn needs_reencode(&self, orientation: Orientation) -> bool {
+        self.input_format != "h264" || orientation != Orientation::Landscape
+    }
+}
+
+/// Checks that a path can be substituted into proxy configuration (e.g.
+/// *hls.conf*) and transcoder arguments verbatim
+///
+/// Only ASCII letters, digits, `/`, `_`, `.` and `-` are allowed, so that
+/// nothing in the path needs escaping, and parent directory components are
+/// rejected.
+fn is_safe_path(path: &str) -> bool {
+
+    let valid_chars = path.chars()
+        .all(|c| c.is_ascii_alphanumeric() || "/_.-".contains(c));
+
+    valid_chars && !path.split('/').any(|component| component == "..")
+}
+
+/// Gets the FFmpeg video filter which applies the given orientation
+fn orientation_filter(orientation: Orientation) -> Option<&'static str> {
+    match orientation {
+        Orientation::Landscape => None,
+        Orientation::Portrait => Some("transpose=clock"),
+        Orientation::InvertedLandscape => Some("hflip,vflip"),
+        Orientation::InvertedPortrait => Some("transpose=cclock"),
+    }
+}
+
+//#endregion
+
+
+/// Produces the child process which transcodes the video stream
+pub trait Transcoder: Send + Sync {
+
+    /// Creates a `Command` which runs this transcoder
+    ///
+    /// `key_info` is the path of the FFmpeg key info file used to encrypt the
+    /// stream (see `config.encrypt`).
+    fn command(&self, orientation: Orientation, config: &TranscoderConfig, key_info: &str) -> Result<Command>;
+
+    /// Gets the signal which asks this transcoder to finalize its output and exit
+    fn stop_signal(&self) -> c_int {
+        libc::SIGTERM
+    }
+
+    /// Appends an output which writes sampled frames to `path` for motion detection
+    ///
+    /// Frames are oriented the same way as the stream, so that motion regions
+    /// drawn over the stream line up with them. Returns `false` if this
+    /// transcoder does not support motion detection.
+    fn add_motion_output(&self, _cmd: &mut Command, _orientation: Orientation, _path: &str) -> bool {
+        false
+    }
+}
+
+
+/// Appends FFmpeg arguments describing the HLS output stream
+fn add_ffmpeg_hls_output(cmd: &mut Command, config: &TranscoderConfig, key_info: &str) {
+
+    cmd.args(["-f", "hls"])
+        .args(["-hls_time", &config.segment_length.to_string()])
+        .args(["-hls_list_size", &config.list_size.to_string()])
+        .args(["-hls_flags", "delete_segments"]);
+
+    if config.encrypt {
+        cmd.args(["-hls_key_info_file", key_info]);
+    }
+
+    cmd.arg(format!("{}/stream.m3u8", config.output_dir));
+}
+
+
+/// Appends FFmpeg arguments describing the sampled frames used for motion detection
+fn add_ffmpeg_motion_output(cmd: &mut Command, orientation: Orientation, path: &str) {
+
+    let sample = format!(
+        "fps={},scale={}:{},format=gray",
+        motion::FRAME_RATE,
+        motion::FRAME_WIDTH,
+        motion::FRAME_HEIGHT,
+    );
+    let filter = match orientation_filter(orientation) {
+        Some(orient) => format!("{},{}", orient, sample),
+        None => sample,
+    };
+
+    cmd.args(["-map", "0:v", "-an"])
+        .args(["-vf", &filter])
+        .args(["-f", "image2", "-update", "1"])
+        .arg(path);
+}
+
+
+/// Transcodes video from a V4L2 device using FFmpeg
+pub struct Ffmpeg;
+
+impl Transcoder for Ffmpeg {
+
+    fn command(&self, orientation: Orientation, config: &TranscoderConfig, key_info: &str) -> Result<Command> {
+
+        let mut cmd = Command::new("ffmpeg");
+
+        // General configuration
+        cmd.args(["-hide_banner", "-loglevel", "error"]);
+
+        // Input stream
+        cmd.args(["-f", "v4l2"])
+            .args(["-input_format", &config.input_format])
+            .args(["-framerate", &config.framerate.to_string()])
+            .args(["-video_size", &format!("{}x{}", config.width, config.height)])
+            .args(["-i", &config.device]);
+
+        // Operation
+        if config.needs_reencode(orientation) {
+            if let Some(filter) = orientation_filter(orientation) {
+                cmd.args(["-vf", filter]);
+            }
+            cmd.args(["-c:v", &config.encoder]);
+        } else {
+            cmd.args(["-c:v", "copy"]);
+        }
+        cmd.args(["-c:a", "aac"]);
+
+        // Output stream
+        add_ffmpeg_hls_output(&mut cmd, config, key_info);
+
+        Ok(cmd)
+    }
+
+    fn add_motion_output(&self, cmd: &mut Command, orientation: Orientation, path: &str) -> bool {
+        add_ffmpeg_motion_output(cmd, orientation, path);
+        true
+    }
+}
+
+
+/// Transcodes video from a V4L2 device using GStreamer
+///
+/// GStreamer's HLS sink does not support encryption, so this backend may only
+/// be used when `config.encrypt` is disabled. When re-encoding is required, the V4L2
+/// memory-to-memory H.264 encoder is used regardless of `config.encoder`.
+pub struct Gstreamer;
+
+impl Transcoder for Gstreamer {
+
+    fn command(&self, orientation: Orientation, config: &TranscoderConfig, _key_info: &str) -> Result<Command> {
+
+        // The backend may have been chosen by LC_TRANSCODER, bypassing validation
+        if config.encrypt {
+            return Error::web(StatusCode::BAD_REQUEST, "GStreamer transcoder cannot encrypt the stream");
+        }
+
+        let mut cmd = Command::new("gst-launch-1.0");
+        cmd.args(["-q", "-e"]);
+
+        // Input stream
+        let caps = format!(
+            "width={},height={},framerate={}/1",
+            config.width,
+            config.height,
+            config.framerate,
+        );
+        cmd.args(["v4l2src", &format!("device={}", config.device), "!"]);
+        match config.input_format.as_str() {
+            "h264" => cmd.arg(format!("video/x-h264,{}", caps)),
+            "mjpeg" => cmd.arg(format!("image/jpeg,{}", caps)),
+            _ => cmd.arg(format!("video/x-raw,{}", caps)),
+        };
+        cmd.arg("!");
+
+        // Operation
+        if config.needs_reencode(orientation) {
+            match config.input_format.as_str() {
+                "h264" => cmd.args(["h264parse", "!", "avdec_h264", "!"]),
+                "mjpeg" => cmd.args(["jpegdec", "!"]),
+                _ => &mut cmd,
+            };
+            cmd.args(["videoconvert", "!"]);
+            if let Some(method) = videoflip_method(orientation) {
+                cmd.args(["videoflip", &format!("method={}", method), "!"]);
+            }
+            cmd.args(["v4l2h264enc", "!", "video/x-h264,level=(string)4", "!"]);
+        }
+        cmd.args(["h264parse", "!"]);
+
+        // Output stream
+        cmd.args([
+            "hlssink2",
+            &format!("location={}/segment%05d.ts", config.output_dir),
+            &format!("playlist-location={}/stream.m3u8", config.output_dir),
+            &format!("target-duration={}", config.segment_length),
+            &format!("playlist-length={}", config.list_size),
+            &format!("max-files={}", config.list_size + 1),
+        ]);
+
+        Ok(cmd)
+    }
+
+    /// gst-launch only sends end-of-stream (finalizing the playlist) when
+    /// interrupted
+    fn stop_signal(&self) -> c_int {
+        libc::SIGINT
+    }
+}
+
+
+/// Generates a synthetic test pattern using FFmpeg
+///
+/// No capture device is required, making this backend useful for development
+/// and testing. Output is otherwise identical to the `Ffmpeg` backend,
+/// including orientation and encryption.
+pub struct TestPattern;
+
+impl Transcoder for TestPattern {
+
+    fn command(&self, orientation: Orientation, config: &TranscoderConfig, key_info: &str) -> Result<Command> {
+
+        let mut cmd = Command::new("ffmpeg");
+
+        // General configuration
+        cmd.args(["-hide_banner", "-loglevel", "error"]);
+
+        // Input stream
+        let source = format!(
+            "testsrc=size={}x{}:rate={}",
+            config.width,
+            config.height,
+            config.framerate,
+        );
+        cmd.args(["-re", "-f", "lavfi", "-i", &source]);
+
+        // Operation
+        if let Some(filter) = orientation_filter(orientation) {
+            cmd.args(["-vf", filter]);
+        }
+        let gop_size = config.framerate * config.segment_length;
+        cmd.args(["-c:v", "libx264"])
+            .args(["-preset", "ultrafast", "-tune", "zerolatency"])
+            .args(["-pix_fmt", "yuv420p"])
+            .args(["-g", &gop_size.to_string()]);
+
+        // Output stream
+        add_ffmpeg_hls_output(&mut cmd, config, key_info);
+
+        Ok(cmd)
+    }
+
+    fn add_motion_output(&self, cmd: &mut Command, orientation: Orientation, path: &str) -> bool {
+        add_ffmpeg_motion_output(cmd, orientation, path);
+        true
+    }
+}
+
+
+/// Gets the GStreamer `videoflip` method which applies the given orientation
+fn videoflip_method(orientation: Orientation) -> Option<&'static str> {
+    match orientation {
+        Orientation::Landscape => None,
+        Orientation::Portrait => Some("clockwise"),
+        Orientation::InvertedLandscape => Some("rotate-180"),
+        Orientation::InvertedPortrait => Some("counterclockwise"),
+    }
+}
+
+
+/// Identifies the backend used to run the transcoder
+///
+/// The backend is chosen by `config.backend`, unless overridden by the
+/// LC_TRANSCODER environment variable (e.g. `LC_TRANSCODER=testPattern`).
+fn select_backend(config: &TranscoderConfig) -> Result<TranscoderBackend> {
+
+    match env::var("LC_TRANSCODER") {
+        Ok(name) => Ok(serde_json::from_value(serde_json::Value::String(name))?),
+        Err(_) => Ok(config.backend),
+    }
+}
+
+
+/// Creates a `Command` for starting the transcoder
+///
+/// `key_info` is the path of the FFmpeg key info file used to encrypt the
+/// stream. If `motion` is set, the transcoder also writes sampled frames for
+/// motion detection (see `motion::frame_path`).
+pub fn make_command(
+    orientation: Orientation,
+    config: &TranscoderConfig,
+    key_info: &str,
+    motion: bool,
+) -> Result<Command> {
+
+    let backend = select_backend(config)?;
+
+    debug!("creating {:?} transcoder command", backend);
+    let transcoder = backend.transcoder();
+    let mut cmd = transcoder.command(orientation, config, key_info)?;
+
+    if motion && !transcoder.add_motion_output(&mut cmd, orientation, &motion::frame_path()?) {
+        warn!("motion detection is not supported by the {:?} transcoder", backend);
+    }
+
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::null());
+
+    Ok(cmd)
+}
+
+
+/// Creates a `ShutdownPolicy` for stopping the transcoder
+pub fn make_shutdown_policy(config: &TranscoderConfig) -> Result<ShutdownPolicy> {
+
+    let backend = select_backend(config)?;
+
+    Ok(ShutdownPolicy {
+        signal: backend.transcoder().stop_signal(),
+        grace_period: Duration::from_millis(config.grace_period_ms),
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::thread;
+    use std::time::Instant;
+
+    use super::*;
+
+
+    fn config_with_output_dir(output_dir: &str) -> TranscoderConfig {
+        TranscoderConfig {
+            output_dir: output_dir.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn motion_frames_follow_stream_orientation() {
+
+        let filter_of = |orientation| {
+            let mut cmd = Command::new("ffmpeg");
+            add_ffmpeg_motion_output(&mut cmd, orientation, "/tmp/motion.pgm");
+
+            let args: Vec<_> = cmd.get_args().map(|arg| arg.to_str().unwrap().to_owned()).collect();
+            let pos = args.iter().position(|arg| arg == "-vf").unwrap();
+            args[pos + 1].clone()
+        };
+
+        assert_eq!(filter_of(Orientation::Landscape), "fps=2,scale=64:48,format=gray");
+        assert_eq!(filter_of(Orientation::Portrait), "transpose=clock,fps=2,scale=64:48,format=gray");
+    }
+
+    #[test]
+    fn gstreamer_requires_encryption_disabled() {
+
+        let mut config = TranscoderConfig {
+            backend: TranscoderBackend::Gstreamer,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        assert!(Gstreamer.command(Orientation::Landscape, &config, "/tmp/stream.keyinfo").is_err());
+
+        config.encrypt = false;
+        assert!(config.validate().is_ok());
+        assert!(Gstreamer.command(Orientation::Landscape, &config, "/tmp/stream.keyinfo").is_ok());
+    }
+
+    /// Temporary directory which is removed when dropped, even if a test fails
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("lunacam-{}-{}", name, process::id()));
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Returns whether FFmpeg is installed with the encoder used by the test
+    /// pattern transcoder
+    fn has_libx264() -> bool {
+
+        Command::new("ffmpeg")
+            .args(["-hide_banner", "-encoders"])
+            .output()
+            .map(|output| String::from_utf8_lossy(&output.stdout).contains("libx264"))
+            .unwrap_or(false)
+    }
+
+    /// Runs the test pattern transcoder until its playlist lists a segment,
+    /// returning the playlist
+    fn run_test_pattern(config: &TranscoderConfig, orientation: Orientation, key_info: &str) -> String {
+
+        fs::create_dir_all(&config.output_dir).unwrap();
+        let mut child = TestPattern.command(orientation, config, key_info).unwrap()
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("failed to run ffmpeg");
+
+        let playlist_path = format!("{}/stream.m3u8", config.output_dir);
+        let deadline = Instant::now() + Duration::from_secs(30);
+        let playlist = loop {
+            let playlist = fs::read_to_string(&playlist_path).unwrap_or_default();
+            if playlist.contains(".ts") {
+                break Some(playlist);
+            }
+            if Instant::now() > deadline || child.try_wait().unwrap().is_some() {
+                break None;
+            }
+            thread::sleep(Duration::from_millis(100));
+        };
+
+        let _ = child.kill();
+        let _ = child.wait();
+
+        playlist.expect("transcoder did not produce a segment")
+    }
+
+    #[test]
+    fn test_pattern_produces_segments() {
+
+        if !has_libx264() {
+            eprintln!("skipping test, FFmpeg with libx264 is not installed");
+            return;
+        }
+
+        let dir = TempDir::new("transcoder");
+        let config = TranscoderConfig {
+            backend: TranscoderBackend::TestPattern,
+            width: 160,
+            height: 120,
+            framerate: 10,
+            output_dir: dir.0.join("hls").to_str().unwrap().into(),
+            segment_length: 1,
+            ..Default::default()
+        };
+        config.validate().unwrap();
+
+        let key_path = dir.0.join("stream.key");
+        let key_info_path = dir.0.join("stream.keyinfo");
+        let key_info = key_info_path.to_str().unwrap();
+
+        // Unencrypted
+        let plain = TranscoderConfig { encrypt: false, ..config.clone() };
+        let playlist = run_test_pattern(&plain, Orientation::Portrait, key_info);
+        assert!(!playlist.contains("#EXT-X-KEY"));
+
+        // Encrypted, using a key info file like the one written by the stream
+        // on startup
+        fs::write(&key_path, [0x42u8; 16]).unwrap();
+        fs::write(&key_info_path, format!("stream.key\n{}\n", key_path.display())).unwrap();
+
+        let playlist = run_test_pattern(&config, Orientation::Landscape, key_info);
+        assert!(playlist.contains("#EXT-X-KEY:METHOD=AES-128"));
+    }
+
+    #[test]
+    fn output_dir_accepts_plain_paths() {
+
+        for dir in &["/dev/shm/lunacam/hls", "/var/lib/luna-cam/stream_1.0"] {
+            assert!(config_with_output_dir(dir).validate().is_ok(), "{} was rejected", dir);
+        }
+    }
+
+    #[test]
+    fn output_dir_rejects_unsafe_paths() {
+
+        let dirs = [
+            "relative/hls",
+            "/dev/shm/../etc",
+            "/dev/shm/..",
+            "/tmp/hls; }",
+            "/tmp/hls\n",
+            "/tmp/h ls",
+            "/tmp/{{hls}}",
+            "/tmp/$hls",
+            "/tmp/\"hls\"",
+            "/tmp/hlš",
+        ];
+
+        for dir in &dirs {
+            assert!(config_with_output_dir(dir).validate().is_err(), "{:?} was accepted", dir);
+        }
+    }
+}
@@ -1,22 +1,37 @@
 //! User interface
 
 use actix_web::HttpResponse;
-use actix_web::web::{self, Data, Path, ServiceConfig};
+use actix_web::http::{header, StatusCode};
+use actix_web::web::{self, Data, Path, Query, ServiceConfig};
 use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use reqwest::Client;
+use serde::Deserialize;
 use tera::{Context, Tera};
 
+use crate::audit;
 use crate::cameras;
 use crate::db::{ConnectionPool};
-use crate::error::Result;
-use crate::users::{self, AuthenticationMiddleware};
+use crate::error::{Error, Result};
+use crate::recording;
+use crate::tokens;
+use crate::totp;
+use crate::users::{self, AuthenticatedUser, AuthenticationMiddleware, Role};
 
 
 fn render_template_response(
     templates: &Tera,
     name: &str,
-    context: Context,
+    user: Option<&AuthenticatedUser>,
+    mut context: Context,
 ) -> Result<HttpResponse> {
 
+    // Allows templates to hide controls which the user is not allowed to use
+    if let Some(user) = user {
+        context.insert("current_user", user);
+        context.insert("is_admin", &user.role.includes(Role::Admin));
+    }
+
     let body = templates.render(name, &context)?;
 
     let response = HttpResponse::Ok()
@@ -27,47 +42,79 @@ fn render_template_response(
 }
 
 
-fn index(pool: Data<ConnectionPool>, templates: Data<Tera>) -> Result<HttpResponse> {
-
+fn index(
+    pool: Data<ConnectionPool>,
+    templates: Data<Tera>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse>
+{
     let conn = pool.get()?;
-    let cameras = cameras::all(&conn)?;
+    let cameras = cameras::visible(&user, &conn)?;
 
     let mut context = Context::new();
     context.insert("cameras", &cameras);
 
-    render_template_response(&templates, "index.html", context)
+    render_template_response(&templates, "index.html", Some(&user), context)
 }
 
 
 fn login(templates: Data<Tera>) -> Result<HttpResponse> {
 
     let context = Context::new();
-    render_template_response(&templates, "login.html", context)
+    render_template_response(&templates, "login.html", None, context)
+}
+
+
+fn setup(templates: Data<Tera>, user: AuthenticatedUser) -> Result<HttpResponse> {
+
+    let context = Context::new();
+    render_template_response(&templates, "setup.html", Some(&user), context)
+}
+
+
+fn account(
+    pool: Data<ConnectionPool>,
+    templates: Data<Tera>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse>
+{
+    let conn = pool.get()?;
+    let totp = totp::status(user.id, &conn)?;
+
+    let mut context = Context::new();
+    context.insert("totp", &totp);
+
+    render_template_response(&templates, "account.html", Some(&user), context)
 }
 
 
 fn camera(
     pool: Data<ConnectionPool>,
     templates: Data<Tera>,
+    user: AuthenticatedUser,
     path: Path<(i32,)>,
 ) -> Result<HttpResponse>
 {
     let conn = pool.get()?;
+    user.require_view(path.0, &conn)?;
     let camera = cameras::get(path.0, &conn)?;
 
     let mut context = Context::new();
     context.insert("camera", &camera);
 
-    render_template_response(&templates, "camera.html", context)
+    render_template_response(&templates, "camera.html", Some(&user), context)
 }
 
 
-fn camera_key(
+/// Serves the key with which a camera's stream is encrypted
+pub(crate) fn camera_key(
     pool: Data<ConnectionPool>,
+    user: AuthenticatedUser,
     path: Path<(i32,)>,
 ) -> Result<HttpResponse>
 {
     let conn = pool.get()?;
+    user.require_view(path.0, &conn)?;
 
     let camera = cameras::get(path.0, &conn)?;
     let response = HttpResponse::Ok()
@@ -77,27 +124,168 @@ fn camera_key(
 }
 
 
-fn camera_admin(pool: Data<ConnectionPool>, templates: Data<Tera>) -> Result<HttpResponse> {
+/// Time range requested for playback
+#[derive(Deserialize)]
+struct PlaybackQuery {
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+}
+
+
+/// Generates a VOD playlist of a camera's recordings within a time range
+///
+/// Segments are encrypted with the camera's key, so the playlist refers to the
+/// same key as the live stream.
+fn camera_playback(
+    pool: Data<ConnectionPool>,
+    client: Data<Client>,
+    user: AuthenticatedUser,
+    path: Path<(i32,)>,
+    query: Query<PlaybackQuery>,
+) -> Result<HttpResponse>
+{
+    let conn = pool.get()?;
+    user.require_view(path.0, &conn)?;
+    let camera = cameras::get(path.0, &conn)?;
+
+    let start = query.start.naive_utc();
+    let end = query.end.naive_utc();
+    let now = Utc::now().naive_utc();
+
+    let mut recordings = cameras::recordings(&camera, &conn, &client)?;
+    recordings.retain(|r| r.started_at < end && r.ended_at.unwrap_or(now) > start);
+    recordings.sort_by_key(|r| r.started_at);
+
+    let mut segments = Vec::new();
+    for rec in recordings {
+        for segment in cameras::recording_segments(&camera, rec.id, &client)? {
+            let segment_end = segment.started_at
+                + chrono::Duration::milliseconds((segment.duration * 1000.0) as i64);
+            if segment.started_at < end && segment_end > start {
+                segments.push((rec.id, segment));
+            }
+        }
+    }
+
+    if segments.is_empty() {
+        return Error::web(StatusCode::NOT_FOUND, "No recordings in the requested range");
+    }
+
+    let playlist = recording::playlist(
+        &segments,
+        |id, segment| format!("/cameras/{}/recordings/{}/{}", camera.id, id, segment.file),
+        &format!("/cameras/{}/key", camera.id),
+    );
+
+    let response = HttpResponse::Ok()
+        .content_type("application/vnd.apple.mpegurl")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(playlist);
+
+    Ok(response)
+}
+
+
+/// Serves a single (encrypted) segment of a camera's recording
+fn camera_recording_segment(
+    pool: Data<ConnectionPool>,
+    client: Data<Client>,
+    user: AuthenticatedUser,
+    path: Path<(i32, i32, String)>,
+) -> Result<HttpResponse>
+{
+    let conn = pool.get()?;
+    user.require_view(path.0, &conn)?;
+    let camera = cameras::get(path.0, &conn)?;
+
+    let data = cameras::recording_segment(&camera, path.1, &path.2, &client)?;
+    let response = HttpResponse::Ok()
+        .content_type("video/mp2t")
+        .body(data);
+
+    Ok(response)
+}
+
 
+fn camera_admin(
+    pool: Data<ConnectionPool>,
+    templates: Data<Tera>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse>
+{
+    user.require(Role::Admin)?;
     let conn = pool.get()?;
     let cameras = cameras::all(&conn)?;
 
     let mut context = Context::new();
     context.insert("cameras", &cameras);
 
-    render_template_response(&templates, "admin/cameras.html", context)
+    render_template_response(&templates, "admin/cameras.html", Some(&user), context)
 }
 
 
-fn user_admin(pool: Data<ConnectionPool>, templates: Data<Tera>) -> Result<HttpResponse> {
-
+fn user_admin(
+    pool: Data<ConnectionPool>,
+    templates: Data<Tera>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse>
+{
+    user.require(Role::Admin)?;
     let conn = pool.get()?;
     let users = users::all(&conn)?;
+    let cameras = cameras::all(&conn)?;
 
     let mut context = Context::new();
     context.insert("users", &users);
+    context.insert("cameras", &cameras);
+
+    render_template_response(&templates, "admin/users.html", Some(&user), context)
+}
+
+
+fn token_admin(
+    pool: Data<ConnectionPool>,
+    templates: Data<Tera>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse>
+{
+    let conn = pool.get()?;
+    let tokens = tokens::visible(&user, &conn)?;
+
+    let mut context = Context::new();
+    context.insert("tokens", &tokens);
+
+    render_template_response(&templates, "admin/tokens.html", Some(&user), context)
+}
+
 
-    render_template_response(&templates, "admin/users.html", context)
+/// Filters accepted by the audit log page
+#[derive(Deserialize)]
+struct AuditQuery {
+    username: Option<String>,
+}
+
+
+fn audit_admin(
+    pool: Data<ConnectionPool>,
+    templates: Data<Tera>,
+    user: AuthenticatedUser,
+    query: Query<AuditQuery>,
+) -> Result<HttpResponse>
+{
+    user.require(Role::Admin)?;
+    let conn = pool.get()?;
+
+    // An empty filter comes from submitting the form without a username
+    let username = query.username.as_deref()
+        .filter(|username| !username.is_empty());
+    let events = audit::recent(username, &conn)?;
+
+    let mut context = Context::new();
+    context.insert("events", &events);
+    context.insert("username", &username);
+
+    render_template_response(&templates, "admin/audit.html", Some(&user), context)
 }
 
 
@@ -106,13 +294,27 @@ pub fn configure(service: &mut ServiceConfig) {
 
     service.route("/login", web::get().to(login));
 
+    service.service(
+        web::resource(users::SETUP_PAGE)
+            .route(web::get().to(setup))
+            .wrap(AuthenticationMiddleware::redirect("/login").allow_setup())
+    );
+
     service.service(
         web::scope("")
             .route("/",                  web::get().to(index))
+            .route("/account",           web::get().to(account))
             .route("/cameras/{id}",      web::get().to(camera))
             .route("/cameras/{id}/key",  web::get().to(camera_key))
+            .route("/cameras/{id}/playback.m3u8", web::get().to(camera_playback))
+            .route(
+                "/cameras/{id}/recordings/{recording_id}/{file}",
+                web::get().to(camera_recording_segment),
+            )
             .route("/admin/cameras",     web::get().to(camera_admin))
             .route("/admin/users",       web::get().to(user_admin))
+            .route("/admin/tokens",      web::get().to(token_admin))
+            .route("/admin/audit",       web::get().to(audit_admin))
             .wrap(AuthenticationMiddleware::redirect("/login"))
     );
 }
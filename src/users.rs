@@ -1,28 +1,42 @@
 //! User management
 
 
-use std::sync::Mutex;
-
-use actix_web::{Error as ActixError, HttpMessage, HttpResponse};
-use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
-use actix_web::http::{Cookie, StatusCode};
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration as StdDuration, Instant};
+
+use actix_web::{Error as ActixError, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use actix_web::dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{header, Cookie, Method, StatusCode};
 use actix_web::web::{self, Data, Json, ServiceConfig};
-use argonautica::{Hasher, Verifier};
+use argonautica::{Error as ArgonError, Hasher, Verifier};
 use argonautica::input::SecretKey;
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::backend::Backend;
+use diesel::deserialize::{self, FromSql};
 use diesel::prelude::*;
 use diesel::result::{Error as DieselError, QueryResult};
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::Integer;
 use futures::{Future, Poll};
 use futures::future::{self, FutureResult};
+use futures::sync::oneshot;
 use lazy_static::lazy_static;
 use log::{debug, error, info, trace, warn};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 
+use crate::audit::{self, AuthEventKind};
 use crate::db::{ConnectionPool, PooledConnection};
-use crate::db::schema::{sessions, users};
+use crate::db::schema::{camera_permissions, sessions, users};
 use crate::do_lock;
 use crate::error::{Error, Result};
 use crate::settings;
+use crate::tokens::{self, TokenScope};
+use crate::totp::{self, SecondFactor};
 
 
 //#region Password hashing
@@ -63,14 +77,80 @@ fn get_secret_key(conn: &PooledConnection) -> Result<SecretKey<'static>> {
 }
 
 
-// These values are chosen such that hashing takes approximately one second on a
-// Raspberry Pi Zero W
-const ARGON2_ITERATIONS: u32 = 24;
-const ARGON2_MEMORY_SIZE: u32 = 1024;
+const ARGON2_PARAMS_SETTING: &str = "argon2Params";
+
+/// Cost parameters used when hashing passwords
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Argon2Params {
+    pub iterations: u32,
+
+    /// Memory used by each hash, in kibibytes
+    pub memory_size: u32,
+}
+
+impl Default for Argon2Params {
+
+    // These values are chosen such that hashing takes approximately one second on a
+    // Raspberry Pi Zero W
+    fn default() -> Self {
+        Self {
+            iterations: 24,
+            memory_size: 1024,
+        }
+    }
+}
+
+impl Argon2Params {
+
+    /// Loads the configured parameters, falling back to the defaults
+    pub fn load(conn: &PooledConnection) -> Result<Self> {
+
+        let params = settings::get(ARGON2_PARAMS_SETTING, conn)?
+            .unwrap_or_default();
+
+        Ok(params)
+    }
+
+    /// Stores these parameters, to be used for all new password hashes
+    pub fn store(&self, conn: &PooledConnection) -> Result<()> {
+
+        settings::set(ARGON2_PARAMS_SETTING, self, conn)
+    }
+
+    /// Extracts the parameters used to produce an encoded hash, e.g.
+    /// *$argon2id$v=19$m=1024,t=24,p=1$...*
+    fn of_hash(hash: &str) -> Option<Self> {
+
+        let encoded = hash.split('$')
+            .find(|part| part.starts_with("m="))?;
+
+        let mut iterations = None;
+        let mut memory_size = None;
+        for param in encoded.split(',') {
+            let mut kv = param.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some("m"), Some(value)) => memory_size = value.parse().ok(),
+                (Some("t"), Some(value)) => iterations = value.parse().ok(),
+                _ => (),
+            }
+        }
 
+        Some(Self {
+            iterations: iterations?,
+            memory_size: memory_size?,
+        })
+    }
+}
 
-fn hash_password(password: &str, conn: &PooledConnection) -> Result<String> {
 
+fn hash_with(
+    password: &str,
+    params: Argon2Params,
+    key: SecretKey<'static>,
+) -> std::result::Result<String, ArgonError>
+{
     // Don't really understand why, but declaring hasher with a separate statement forces the 'a in
     // Hasher<'a> to be compatible with the lifetime of password. If we don't do this, the compiler
     // tries to infer hasher as Hasher<'static>, probably because we're passing a SecretKey<'static>
@@ -78,27 +158,423 @@ fn hash_password(password: &str, conn: &PooledConnection) -> Result<String> {
     // because password is not 'static.
     let mut hasher = Hasher::new();
 
-    let hash = hasher
-        .configure_iterations(ARGON2_ITERATIONS)
-        .configure_memory_size(ARGON2_MEMORY_SIZE)
-        .with_secret_key(get_secret_key(conn)?)
+    hasher
+        .configure_iterations(params.iterations)
+        .configure_memory_size(params.memory_size)
+        .with_secret_key(key)
         .with_password(password)
         .hash()
-        .expect("failed to hash password");
-
-    Ok(hash)
 }
 
-fn verify_password(hash: &str, password: &str, conn: &PooledConnection) -> Result<bool> {
-
-    let res = Verifier::new()
-        .with_secret_key(get_secret_key(conn)?)
+fn verify_with(
+    hash: &str,
+    password: &str,
+    key: SecretKey<'static>,
+) -> std::result::Result<bool, ArgonError>
+{
+    Verifier::new()
+        .with_secret_key(key)
         .with_hash(hash)
         .with_password(password)
         .verify()
-        .expect("failed to verify password");
+}
+
+// argonautica's errors do not implement std::error::Error, so they are logged
+// and replaced rather than being propagated
+
+fn hashing_error(err: ArgonError) -> Error {
+
+    error!("failed to hash password: {}", err);
+    Error::Web(StatusCode::INTERNAL_SERVER_ERROR, "failed to hash password")
+}
+
+fn verification_error(err: ArgonError) -> Error {
+
+    error!("failed to verify password: {}", err);
+    Error::Web(StatusCode::INTERNAL_SERVER_ERROR, "failed to verify password")
+}
+
+/// Hashes a password on the current thread
+///
+/// Only suitable outside of request handlers, which should use `hash_password`.
+fn hash_password_with(
+    password: &str,
+    params: Argon2Params,
+    conn: &PooledConnection,
+) -> Result<String>
+{
+    hash_with(password, params, get_secret_key(conn)?)
+        .map_err(hashing_error)
+}
+
+/// Verifies a password on the current thread
+///
+/// Only suitable outside of request handlers, which should use
+/// `verify_password`.
+fn verify_password_now(hash: &str, password: &str, conn: &PooledConnection) -> Result<bool> {
+
+    verify_with(hash, password, get_secret_key(conn)?)
+        .map_err(verification_error)
+}
+
+
+// Each hash takes about a second and a good deal of memory, so only a few run
+// at once. Requests beyond the queue limit are turned away rather than left to
+// pile up behind a login storm.
+const HASHING_THREADS: usize = 2;
+const HASHING_QUEUE_LIMIT: usize = 16;
+
+type HashingJob = Box<dyn FnOnce() + Send>;
+
+lazy_static! {
+    static ref HASHING_QUEUE: Mutex<SyncSender<HashingJob>> = Mutex::new(start_hashing_threads());
+}
+
+/// Starts the threads which run password hashing jobs
+fn start_hashing_threads() -> SyncSender<HashingJob> {
+
+    let (sender, receiver) = mpsc::sync_channel::<HashingJob>(HASHING_QUEUE_LIMIT);
+    let receiver = Arc::new(Mutex::new(receiver));
+
+    for i in 0..HASHING_THREADS {
+        let receiver = receiver.clone();
+        thread::Builder::new()
+            .name(format!("pwhash-{}", i))
+            .spawn(move || loop {
+                let job = match do_lock!(receiver).recv() {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+                job();
+            })
+            .expect("failed to spawn password hashing thread");
+    }
+
+    sender
+}
+
+/// Queues a job on the password hashing threads
+///
+/// Fails with *503 Service Unavailable* if too many jobs are already queued.
+fn submit_hashing_job(job: HashingJob) -> Result<()> {
+
+    match do_lock!(HASHING_QUEUE).try_send(job) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full(_)) => {
+            warn!("password hashing queue is full");
+            Error::web(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "server is busy, try again later",
+            )
+        },
+        Err(TrySendError::Disconnected(_)) => Error::web(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "password hashing is unavailable",
+        ),
+    }
+}
+
+/// Future resolved by a request handler which waits on password hashing
+pub(crate) type HashingFuture<T> = Box<dyn Future<Item = T, Error = Error>>;
+
+/// Runs a password hashing operation on the hashing threads, so that request
+/// handlers are not blocked while it runs
+fn run_hashing<T, F>(f: F, on_error: fn(ArgonError) -> Error) -> HashingFuture<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> std::result::Result<T, ArgonError> + Send + 'static,
+{
+    let (sender, receiver) = oneshot::channel();
+
+    // The request may have been dropped by the time the job finishes, in which
+    // case nobody needs the result
+    let job = Box::new(move || {
+        let _ = sender.send(f());
+    });
+    if let Err(err) = submit_hashing_job(job) {
+        return Box::new(future::err(err));
+    }
+
+    let future = receiver.then(move |res| match res {
+        Ok(res) => res.map_err(on_error),
+        Err(_) => Error::web(StatusCode::INTERNAL_SERVER_ERROR, "password hashing was interrupted"),
+    });
 
-    Ok(res)
+    Box::new(future)
+}
+
+/// Hashes a password using the configured parameters
+fn hash_password(password: String, pool: &ConnectionPool) -> HashingFuture<String> {
+
+    let prepare = || -> Result<_> {
+        let conn = pool.get()?;
+        Ok((Argon2Params::load(&conn)?, get_secret_key(&conn)?))
+    };
+    let (params, key) = match prepare() {
+        Ok(prepared) => prepared,
+        Err(err) => return Box::new(future::err(err)),
+    };
+
+    run_hashing(move || hash_with(&password, params, key), hashing_error)
+}
+
+/// Checks a password against a hash
+pub(crate) fn verify_password(hash: String, password: String, pool: &ConnectionPool) -> HashingFuture<bool> {
+
+    let key = match pool.get().map_err(Error::from).and_then(|conn| get_secret_key(&conn)) {
+        Ok(key) => key,
+        Err(err) => return Box::new(future::err(err)),
+    };
+
+    run_hashing(move || verify_with(&hash, &password, key), verification_error)
+}
+
+lazy_static! {
+    /// Hash of a random password, against which logins as unknown users are
+    /// checked
+    static ref DUMMY_HASH: Mutex<Option<String>> = Mutex::new(None);
+}
+
+/// Checks a password against a hash which no password matches
+///
+/// Used when the username is not found, so that logging in as an unknown user
+/// takes as long as logging in with the wrong password, and does not reveal
+/// which usernames exist. Always resolves to `false`.
+fn verify_dummy_password(password: String, pool: &ConnectionPool) -> HashingFuture<bool> {
+
+    if let Some(hash) = do_lock!(DUMMY_HASH).clone() {
+        return Box::new(verify_password(hash, password, pool).map(|_| false));
+    }
+
+    // Hashing takes as long as verifying, so the first check can generate the
+    // dummy hash instead
+    let random: [u8; 32] = rand::thread_rng().gen();
+    let future = hash_password(base64::encode(&random), pool)
+        .map(|hash| {
+            do_lock!(DUMMY_HASH).replace(hash);
+            false
+        });
+
+    Box::new(future)
+}
+
+/// Rehashes a user's password in the background if it was hashed with
+/// parameters other than the configured ones
+///
+/// This is only possible while the plain password is known, i.e. right after it
+/// has been verified.
+fn maybe_rehash_password(user: &User, password: String, pool: &ConnectionPool) -> Result<()> {
+
+    let conn = pool.get()?;
+    let params = Argon2Params::load(&conn)?;
+    if Argon2Params::of_hash(&user.pwhash) == Some(params) {
+        return Ok(());
+    }
+
+    debug!("rehashing password of user {} with new parameters", user.id);
+    let key = get_secret_key(&conn)?;
+    let pool = pool.clone();
+    let id = user.id;
+    submit_hashing_job(Box::new(move || {
+        let res = hash_with(&password, params, key)
+            .map_err(|err| err.to_string())
+            .and_then(|pwhash| -> std::result::Result<_, String> {
+                let conn = pool.get()
+                    .map_err(|err| err.to_string())?;
+                diesel::update(users::table.find(id))
+                    .set(users::pwhash.eq(pwhash))
+                    .execute(&conn)
+                    .map_err(|err| err.to_string())
+            });
+        if let Err(err) = res {
+            error!("failed to rehash password of user {}: {}", id, err);
+        }
+    }))
+}
+
+
+/// Upper bound on iterations chosen by tuning, in case the target is unreasonably high
+const MAX_TUNING_ITERATIONS: u32 = 1024;
+
+/// Chooses the number of iterations such that hashing a password takes about
+/// `target` on this machine, and stores the resulting parameters
+///
+/// The configured memory size is kept.
+pub fn tune_argon2(target: StdDuration, conn: &PooledConnection) -> Result<Argon2Params> {
+
+    let mut params = Argon2Params::load(conn)?;
+    params.iterations = 1;
+
+    loop {
+        let start = Instant::now();
+        hash_password_with("benchmark", params, conn)?;
+        let elapsed = start.elapsed();
+        debug!("hashing with {} iterations took {:?}", params.iterations, elapsed);
+
+        if elapsed >= target || params.iterations >= MAX_TUNING_ITERATIONS {
+            break;
+        }
+
+        // Time scales roughly linearly with iterations, but the estimate is
+        // checked by another round since the first few iterations are noisy
+        let estimate = f64::from(params.iterations) * target.as_secs_f64()
+            / elapsed.as_secs_f64().max(0.001);
+        params.iterations = (estimate.ceil() as u32)
+            .max(params.iterations + 1)
+            .min(MAX_TUNING_ITERATIONS);
+    }
+
+    params.store(conn)?;
+    info!("tuned Argon2 parameters: {:?}", params);
+
+    Ok(params)
+}
+
+//#endregion
+
+
+//#region Roles
+
+/// Determines what a user is allowed to do
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(AsExpression, FromSqlRow)]
+#[sql_type = "Integer"]
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Role {
+
+    /// May do anything, including managing users and adding/removing cameras
+    Admin,
+
+    /// May view and control all cameras
+    Operator,
+
+    /// May only view cameras to which they have been granted access
+    Viewer,
+}
+
+impl Role {
+
+    /// Returns whether this role has at least the privileges of `other`
+    pub fn includes(self, other: Role) -> bool {
+
+        let level = |role| match role {
+            Role::Admin => 2,
+            Role::Operator => 1,
+            Role::Viewer => 0,
+        };
+
+        level(self) >= level(other)
+    }
+}
+
+impl<B> FromSql<Integer, B> for Role
+where
+    B: Backend,
+    i32: FromSql<Integer, B>,
+{
+    fn from_sql(bytes: Option<&B::RawValue>) -> deserialize::Result<Self> {
+        match i32::from_sql(bytes)? {
+            0 => Ok(Self::Admin),
+            1 => Ok(Self::Operator),
+            2 => Ok(Self::Viewer),
+            other => Err(format!("Unrecognized value \"{}\"", other).into()),
+        }
+    }
+}
+
+impl<B> ToSql<Integer, B> for Role
+where
+    B: Backend,
+    i32: ToSql<Integer, B>,
+{
+    fn to_sql<W: Write>(&self, out: &mut Output<W, B>) -> serialize::Result {
+        let val = match *self {
+            Self::Admin => 0,
+            Self::Operator => 1,
+            Self::Viewer => 2,
+        };
+
+        val.to_sql(out)
+    }
+}
+
+
+/// User on whose behalf a request is being made
+///
+/// An `AuthenticatedUser` is available as an extractor for any handler wrapped
+/// by `AuthenticationMiddleware`.
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatedUser {
+    pub id: i32,
+    pub username: String,
+    pub role: Role,
+
+    /// Whether the user must choose a new password before doing anything else
+    pub must_change_password: bool,
+
+    /// Session used to authenticate the request, if not authenticated with an
+    /// API token
+    #[serde(skip)]
+    pub session_id: Option<i32>,
+
+    /// Whether the request may only read, not change, anything
+    #[serde(skip)]
+    pub read_only: bool,
+}
+
+impl AuthenticatedUser {
+
+    /// Fails with *403 Forbidden* unless this user has at least the given role
+    pub fn require(&self, role: Role) -> Result<()> {
+
+        if self.role.includes(role) {
+            Ok(())
+        } else {
+            Error::web(StatusCode::FORBIDDEN, "insufficient privileges")
+        }
+    }
+
+    /// Returns whether this user may view the specified camera
+    pub fn can_view(&self, camera_id: i32, conn: &PooledConnection) -> Result<bool> {
+
+        if self.role.includes(Role::Operator) {
+            return Ok(true);
+        }
+
+        let permission = camera_permissions::table
+            .find((self.id, camera_id))
+            .select(camera_permissions::camera_id)
+            .first::<i32>(conn)
+            .optional()?;
+
+        Ok(permission.is_some())
+    }
+
+    /// Fails with *403 Forbidden* unless this user may view the specified camera
+    pub fn require_view(&self, camera_id: i32, conn: &PooledConnection) -> Result<()> {
+
+        if self.can_view(camera_id, conn)? {
+            Ok(())
+        } else {
+            Error::web(StatusCode::FORBIDDEN, "insufficient privileges")
+        }
+    }
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = Error;
+    type Future = Result<Self>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+
+        match req.extensions().get::<Self>() {
+            Some(user) => Ok(user.clone()),
+            None => Error::web(StatusCode::UNAUTHORIZED, "not authenticated"),
+        }
+    }
 }
 
 //#endregion
@@ -112,26 +588,40 @@ fn verify_password(hash: &str, password: &str, conn: &PooledConnection) -> Resul
 struct NewUser<'a> {
     username: &'a str,
     pwhash: &'a str,
+    role: Role,
+    must_change_password: bool,
 }
 
 /// Representation of a user account
 #[derive(Serialize)]
 #[derive(AsChangeset, Identifiable, Queryable)]
+#[serde(rename_all = "camelCase")]
 #[table_name = "users"]
 struct User {
     id: i32,
     username: String,
     #[serde(skip_serializing)]
     pwhash: String,
+    role: Role,
+    must_change_password: bool,
 }
 
 impl User {
 
     /// Creates a new user account using the given credentials
-    fn create(username: &str, password: &str, conn: &PooledConnection) -> Result<Self> {
-
-        let pwhash = hash_password(password, conn)?;
-        let user = NewUser { username, pwhash: &pwhash };
+    fn create(
+        username: &str,
+        pwhash: &str,
+        role: Role,
+        conn: &PooledConnection,
+    ) -> Result<Self> {
+
+        let user = NewUser {
+            username,
+            pwhash,
+            role,
+            must_change_password: false,
+        };
 
         debug!("adding new user to database");
         diesel::insert_into(users::table)
@@ -153,50 +643,112 @@ const DEFAULT_USERNAME: &str = "lunacam";
 const DEFAULT_PASSWORD: &str = "lunacam";
 
 /// Creates a default user account if no other users are present
+///
+/// The default account's password is publicly known, so the account is flagged
+/// such that its password must be changed before it can be used. Accounts
+/// created by earlier versions which still use the default password are
+/// flagged as well.
 pub fn maybe_create_default_user(conn: &PooledConnection) -> Result<()> {
 
     // Can't figure out any other way to reliably get the count of a SQLite
     // table using Diesel.
     let users: Vec<User> = users::table.load(conn)?;
-    let user_count = users.len();
 
-    if user_count == 0 {
+    if users.is_empty() {
         info!("creating default user account");
-        User::create(DEFAULT_USERNAME, DEFAULT_PASSWORD, conn)?;
+        let pwhash = hash_password_with(DEFAULT_PASSWORD, Argon2Params::load(conn)?, conn)?;
+        let mut user = User::create(DEFAULT_USERNAME, &pwhash, Role::Admin, conn)?;
+        user.must_change_password = true;
+        diesel::update(&user)
+            .set(&user)
+            .execute(conn)?;
+        return Ok(());
+    }
+
+    let default_user = users.into_iter()
+        .find(|u| u.username == DEFAULT_USERNAME && !u.must_change_password);
+    if let Some(mut user) = default_user {
+        if verify_password_now(&user.pwhash, DEFAULT_PASSWORD, conn)? {
+            warn!("user {} still has the default password", user.id);
+            user.must_change_password = true;
+            diesel::update(&user)
+                .set(&user)
+                .execute(conn)?;
+        }
     }
 
     Ok(())
 }
 
+/// Returns whether the given user is the only remaining admin
+fn is_last_admin(user: &User, conn: &PooledConnection) -> Result<bool> {
+
+    if user.role != Role::Admin {
+        return Ok(false);
+    }
+
+    let admins: Vec<i32> = users::table
+        .filter(users::role.eq(Role::Admin))
+        .select(users::id)
+        .load(conn)?;
+
+    Ok(admins.len() == 1)
+}
+
 /// User representation required by PUT requests
 #[derive(Deserialize)]
 struct PutUserBody {
     password: String,
     username: String,
+    role: Option<Role>,
 }
 
 /// Creates a new user
 fn put_user(
     pool: Data<ConnectionPool>,
+    current_user: AuthenticatedUser,
+    req: HttpRequest,
     body: Json<PutUserBody>,
-) -> Result<Json<User>>
+) -> HashingFuture<Json<User>>
 {
-    let body = body.into_inner();
-    let conn = pool.get()?;
-
-    let user = User::create(&body.username, &body.password, &conn)?;
-
-    Ok(Json(user))
+    let PutUserBody { password, username, role } = body.into_inner();
+
+    // New users may only view cameras unless told otherwise
+    let role = role.unwrap_or(Role::Viewer);
+
+    let future = hash_password(password, &pool)
+        .and_then(move |pwhash| -> Result<_> {
+            let conn = pool.get()?;
+            let user = User::create(&username, &pwhash, role, &conn)?;
+            audit::record(
+                AuthEventKind::UserCreated,
+                &user.username,
+                Some(&current_user.username),
+                &req,
+                &conn,
+            );
+
+            Ok(Json(user))
+        });
+
+    Box::new(future)
 }
 
 /// Retrieves information about the specified user
+///
+/// Users other than admins may only retrieve their own account.
 fn get_user(
     pool: Data<ConnectionPool>,
+    current_user: AuthenticatedUser,
     path: web::Path<(i32,)>,
 ) -> Result<Json<User>>
 {
     let id = path.0;
 
+    if id != current_user.id {
+        current_user.require(Role::Admin)?;
+    }
+
     debug!("retrieving user {} from database", id);
     let conn = pool.get()?;
     let user = users::table.find(id)
@@ -222,67 +774,245 @@ fn get_users(
 struct PatchUserBody {
     password: Option<String>,
     username: Option<String>,
+    role: Option<Role>,
 }
 
 /// Updates information about the specified user
+///
+/// Users other than admins may only update their own account, and may not
+/// change their own role.
 fn patch_user(
     pool: Data<ConnectionPool>,
+    current_user: AuthenticatedUser,
+    req: HttpRequest,
     path: web::Path<(i32,)>,
     body: Json<PatchUserBody>,
-) -> Result<Json<User>>
+) -> HashingFuture<Json<User>>
 {
     let id = path.0;
-    let body = body.into_inner();
+    let mut body = body.into_inner();
 
-    debug!("retrieving user {} from database", id);
-    let conn = pool.get()?;
-    let mut user: User = users::table.find(id)
-        .get_result(&conn)?;
+    // Check privileges before doing any expensive work
+    let authorize = || -> Result<User> {
 
-    let mut do_save = false;
+        if id != current_user.id || body.role.is_some() {
+            current_user.require(Role::Admin)?;
+        }
 
-    if let Some(password) = body.password {
-        trace!("updating pwhash for user {}", id);
-        user.pwhash = hash_password(&password, &conn)?;
-        do_save = true;
-    }
+        // Users who must change their password may do only that
+        if current_user.must_change_password && (id != current_user.id || body.password.is_none()) {
+            return Error::web(StatusCode::FORBIDDEN, "password must be changed first");
+        }
+
+        debug!("retrieving user {} from database", id);
+        let conn = pool.get()?;
+        let user = users::table.find(id)
+            .get_result(&conn)?;
+
+        Ok(user)
+    };
+    let mut user = match authorize() {
+        Ok(user) => user,
+        Err(err) => return Box::new(future::err(err)),
+    };
+
+    let pwhash: HashingFuture<Option<String>> = match body.password.take() {
+        Some(password) => {
+
+            // Don't let a known password be "changed" to itself
+            let is_unchanged: HashingFuture<bool> = if user.must_change_password {
+                verify_password(user.pwhash.clone(), password.clone(), &pool)
+            } else {
+                Box::new(future::ok(false))
+            };
+
+            let pool = pool.clone();
+            Box::new(is_unchanged.and_then(move |is_unchanged| -> HashingFuture<_> {
+                if is_unchanged {
+                    return Box::new(future::err(Error::Web(
+                        StatusCode::BAD_REQUEST,
+                        "new password must differ from the old one",
+                    )));
+                }
+                Box::new(hash_password(password, &pool).map(Some))
+            }))
+        },
+        None => Box::new(future::ok(None)),
+    };
+
+    let future = pwhash.and_then(move |pwhash| -> Result<_> {
 
-    if let Some(username) = body.username {
-        if username != user.username {
-            trace!("updating username for user {}", id);
-            user.username = username;
+        let conn = pool.get()?;
+        let mut do_save = false;
+        let mut do_revoke = false;
+
+        if let Some(pwhash) = pwhash {
+            trace!("updating pwhash for user {}", id);
+            user.pwhash = pwhash;
+            user.must_change_password = false;
             do_save = true;
+            do_revoke = true;
         }
-    }
 
-    if do_save {
-        debug!("saving changes to user {}", id);
-        diesel::update(&user)
-            .set(&user)
-            .execute(&conn)?;
-    }
+        if let Some(username) = body.username {
+            if username != user.username {
+                trace!("updating username for user {}", id);
+                user.username = username;
+                do_save = true;
+            }
+        }
 
-    info!("successfully updated user {}", id);
-    Ok(Json(user))
+        if let Some(role) = body.role {
+            if role != user.role {
+                if is_last_admin(&user, &conn)? {
+                    return Error::web(StatusCode::CONFLICT, "cannot demote the last admin");
+                }
+                trace!("updating role for user {}", id);
+                user.role = role;
+                do_save = true;
+            }
+        }
+
+        if do_save {
+            debug!("saving changes to user {}", id);
+            diesel::update(&user)
+                .set(&user)
+                .execute(&conn)?;
+        }
+
+        // Anyone who knew the old password must log in again. If users are changing
+        // their own password, they keep the session used to make the change.
+        if do_revoke {
+            debug!("revoking sessions of user {}", id);
+            let keep = if id == current_user.id { current_user.session_id } else { None };
+            revoke_sessions(id, keep, &conn)?;
+            audit::record(
+                AuthEventKind::PasswordChanged,
+                &user.username,
+                Some(&current_user.username),
+                &req,
+                &conn,
+            );
+        }
+
+        info!("successfully updated user {}", id);
+        Ok(Json(user))
+    });
+
+    Box::new(future)
 }
 
 /// Deletes the specified user
 fn delete_user(
     pool: Data<ConnectionPool>,
+    current_user: AuthenticatedUser,
+    req: HttpRequest,
     path: web::Path<(i32,)>,
 ) -> Result<()>
 {
     let id = path.0;
 
-    debug!("deleting user {} from database", id);
+    current_user.require(Role::Admin)?;
+    if current_user.must_change_password {
+        return Error::web(StatusCode::FORBIDDEN, "password must be changed first");
+    }
+
     let conn = pool.get()?;
+    let user: User = users::table.find(id)
+        .get_result(&conn)?;
+
+    // Without an admin, nobody could manage users or cameras
+    if is_last_admin(&user, &conn)? {
+        return Error::web(StatusCode::CONFLICT, "cannot delete the last admin");
+    }
+
+    debug!("deleting user {} from database", id);
+    revoke_sessions(id, None, &conn)?;
+    tokens::revoke_all(id, &conn)?;
+    totp::clear(id, &conn)?;
+    diesel::delete(camera_permissions::table.filter(camera_permissions::user_id.eq(id)))
+        .execute(&conn)?;
     diesel::delete(users::table.filter(users::id.eq(id)))
         .execute(&conn)?;
 
+    audit::record(
+        AuthEventKind::UserDeleted,
+        &user.username,
+        Some(&current_user.username),
+        &req,
+        &conn,
+    );
+
     info!("deleted user {}", id);
 
-    // Can't reuse conn, because it will not reflect the recently deleted user
-    maybe_create_default_user(&pool.get()?)?;
+    Ok(())
+}
+
+#[derive(Insertable)]
+#[table_name = "camera_permissions"]
+struct CameraPermission {
+    user_id: i32,
+    camera_id: i32,
+}
+
+/// Retrieves the IDs of cameras which the specified user may view
+fn get_user_cameras(
+    pool: Data<ConnectionPool>,
+    path: web::Path<(i32,)>,
+) -> Result<Json<Vec<i32>>>
+{
+    let id = path.0;
+    let conn = pool.get()?;
+
+    let camera_ids = camera_permissions::table
+        .filter(camera_permissions::user_id.eq(id))
+        .select(camera_permissions::camera_id)
+        .load(&conn)?;
+
+    Ok(Json(camera_ids))
+}
+
+/// Replaces the set of cameras which the specified user may view
+///
+/// Admins and operators may view all cameras regardless of these permissions.
+fn put_user_cameras(
+    pool: Data<ConnectionPool>,
+    path: web::Path<(i32,)>,
+    body: Json<Vec<i32>>,
+) -> Result<Json<Vec<i32>>>
+{
+    let id = path.0;
+    let conn = pool.get()?;
+
+    // Ensure the user exists
+    users::table.find(id)
+        .get_result::<User>(&conn)?;
+
+    debug!("updating camera permissions for user {}", id);
+    conn.transaction::<_, Error, _>(|| {
+        diesel::delete(camera_permissions::table.filter(camera_permissions::user_id.eq(id)))
+            .execute(&conn)?;
+
+        // SQLite does not support batch inserts
+        for &camera_id in body.iter() {
+            diesel::insert_into(camera_permissions::table)
+                .values(&CameraPermission { user_id: id, camera_id })
+                .execute(&conn)?;
+        }
+
+        Ok(())
+    })?;
+
+    info!("updated camera permissions for user {}", id);
+
+    get_user_cameras(pool, path)
+}
+
+/// Removes all permissions granted for the specified camera
+pub fn clear_camera_permissions(camera_id: i32, conn: &PooledConnection) -> Result<()> {
+
+    diesel::delete(camera_permissions::table.filter(camera_permissions::camera_id.eq(camera_id)))
+        .execute(conn)?;
 
     Ok(())
 }
@@ -294,25 +1024,91 @@ fn delete_user(
 
 const SESSION_COOKIE: &str = "lcsession";
 
-/// Representation of a user account
+/// Representation of a login session
+#[derive(Serialize)]
 #[derive(AsChangeset, Identifiable, Queryable)]
+#[serde(rename_all = "camelCase")]
 #[table_name = "sessions"]
 struct Session {
     id: i32,
+    #[serde(skip)]
     key: String,
+    #[serde(skip)]
     user_id: i32,
+    created_at: NaiveDateTime,
+    last_seen_at: NaiveDateTime,
+}
+
+impl Session {
+
+    /// Returns whether this session has timed out
+    fn is_expired(&self, now: NaiveDateTime, timeouts: &SessionTimeouts) -> bool {
+
+        now - self.last_seen_at > Duration::minutes(timeouts.idle_minutes)
+            || now - self.created_at > Duration::hours(timeouts.max_age_hours)
+    }
+}
+
+
+const SESSION_TIMEOUTS_SETTING: &str = "sessionTimeouts";
+
+/// Determines how long sessions remain valid
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SessionTimeouts {
+
+    /// Time after which an unused session expires
+    idle_minutes: i64,
+
+    /// Time after which a session expires, regardless of use
+    max_age_hours: i64,
+}
+
+impl Default for SessionTimeouts {
+    fn default() -> Self {
+        Self {
+            idle_minutes: 7 * 24 * 60,
+            max_age_hours: 30 * 24,
+        }
+    }
 }
 
-fn authenticate_request(req: &ServiceRequest) -> Result<bool> {
+impl SessionTimeouts {
+
+    fn load(conn: &PooledConnection) -> Result<Self> {
+
+        let timeouts = settings::get(SESSION_TIMEOUTS_SETTING, conn)?
+            .unwrap_or_default();
+
+        Ok(timeouts)
+    }
+}
+
+
+/// Sessions are only marked as seen once per interval, to avoid writing to the
+/// database on every request
+const LAST_SEEN_RESOLUTION_MINUTES: i64 = 1;
+
+fn authenticate_request(req: &ServiceRequest) -> Result<Option<AuthenticatedUser>> {
+
+    let pool: Data<ConnectionPool> = req.app_data()
+        .expect("failed to retrieve connection pool");
+
+    // Machine clients present an API token instead of a session cookie
+    let bearer = req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if let Some(token) = bearer {
+        return authenticate_token(token.trim(), &pool.get()?);
+    }
 
     let key = if let Some(key) = req.cookie(SESSION_COOKIE) {
         key
     } else {
-        return Ok(false);
+        return Ok(None);
     };
 
-    let pool: Data<ConnectionPool> = req.app_data()
-        .expect("failed to retrieve connection pool");
     let conn = pool.get()?;
 
     // Search for a matching authenticated session
@@ -321,15 +1117,69 @@ fn authenticate_request(req: &ServiceRequest) -> Result<bool> {
         .filter(session_filter)
         .first(&conn);
 
-    match session_res {
-        Ok(_) => Ok(true),
-        Err(DieselError::NotFound) => Ok(false),
-        Err(e) => Err(e.into()),
+    let session = match session_res {
+        Ok(session) => session,
+        Err(DieselError::NotFound) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let now = Utc::now().naive_utc();
+    if session.is_expired(now, &SessionTimeouts::load(&conn)?) {
+        debug!("session {} has expired", session.id);
+        diesel::delete(&session)
+            .execute(&conn)?;
+        return Ok(None);
     }
+
+    if now - session.last_seen_at > Duration::minutes(LAST_SEEN_RESOLUTION_MINUTES) {
+        trace!("updating last seen time of session {}", session.id);
+        diesel::update(&session)
+            .set(sessions::last_seen_at.eq(now))
+            .execute(&conn)?;
+    }
+
+    let user: User = users::table.find(session.user_id)
+        .get_result(&conn)?;
+
+    Ok(Some(AuthenticatedUser {
+        id: user.id,
+        username: user.username,
+        role: user.role,
+        must_change_password: user.must_change_password,
+        session_id: Some(session.id),
+        read_only: false,
+    }))
+}
+
+/// Authenticates a request made with an API token
+fn authenticate_token(token: &str, conn: &PooledConnection) -> Result<Option<AuthenticatedUser>> {
+
+    let token = match tokens::authenticate(token, conn)? {
+        Some(token) => token,
+        None => return Ok(None),
+    };
+
+    let user: User = users::table.find(token.user_id)
+        .get_result(conn)?;
+
+    Ok(Some(AuthenticatedUser {
+        id: user.id,
+        username: user.username,
+        role: user.role,
+        must_change_password: user.must_change_password,
+        session_id: None,
+        read_only: token.scope == TokenScope::ReadOnly,
+    }))
 }
 
+/// Page where users choose a new password when required to
+pub const SETUP_PAGE: &str = "/setup";
+
 pub struct AuthenticationService<S> {
     dest: Option<String>,
+    role: Role,
+    required: bool,
+    allow_setup: bool,
     service: S,
 }
 
@@ -349,13 +1199,47 @@ where
 
     fn call(&mut self, req: ServiceRequest) -> Self::Future {
 
-        let is_authenticated = authenticate_request(&req)
+        let user = authenticate_request(&req)
             .unwrap_or_else(|e| {
                 error!("failed to authenticate request: {}", e);
-                false
+                None
             });
 
-        if is_authenticated {
+        if let Some(user) = user {
+
+            // Until the password is changed, only the resources needed to
+            // change it are available
+            if user.must_change_password && !self.allow_setup {
+                debug!("user {} must change password before accessing {}", user.id, req.path());
+                let response = if self.dest.is_some() {
+                    HttpResponse::Found()
+                        .header("Location", SETUP_PAGE)
+                        .finish()
+                } else {
+                    HttpResponse::Forbidden()
+                        .finish()
+                };
+                return Box::new(future::ok(req.into_response(response)));
+            }
+
+            if user.read_only && req.method() != Method::GET && req.method() != Method::HEAD {
+                debug!("read-only token used for {} {}", req.method(), req.path());
+                let response = HttpResponse::Forbidden()
+                    .finish();
+                return Box::new(future::ok(req.into_response(response)));
+            }
+
+            if !user.role.includes(self.role) {
+                debug!("user {} lacks privileges for {}", user.id, req.path());
+                let response = HttpResponse::Forbidden()
+                    .finish();
+                return Box::new(future::ok(req.into_response(response)));
+            }
+
+            req.extensions_mut().insert(user);
+            Box::new(self.service.call(req))
+
+        } else if !self.required {
 
             Box::new(self.service.call(req))
 
@@ -375,16 +1259,54 @@ where
     }
 }
 
-pub struct AuthenticationMiddleware(Option<String>);
+pub struct AuthenticationMiddleware {
+    dest: Option<String>,
+    role: Role,
+    required: bool,
+    allow_setup: bool,
+}
 
 impl AuthenticationMiddleware {
 
     pub fn redirect(destination: &str) -> Self {
-        Self(Some(destination.into()))
+        Self {
+            dest: Some(destination.into()),
+            role: Role::Viewer,
+            required: true,
+            allow_setup: false,
+        }
     }
 
     pub fn reject() -> Self {
-        Self(None)
+        Self {
+            dest: None,
+            role: Role::Viewer,
+            required: true,
+            allow_setup: false,
+        }
+    }
+
+    /// Allows unauthenticated requests through, leaving it to handlers to
+    /// check for an `AuthenticatedUser`
+    pub fn optional() -> Self {
+        Self {
+            dest: None,
+            role: Role::Viewer,
+            required: false,
+            allow_setup: true,
+        }
+    }
+
+    /// Rejects authenticated users who lack the given role with *403 Forbidden*
+    pub fn require(mut self, role: Role) -> Self {
+        self.role = role;
+        self
+    }
+
+    /// Allows through users who have yet to change their password
+    pub fn allow_setup(mut self) -> Self {
+        self.allow_setup = true;
+        self
     }
 }
 
@@ -402,7 +1324,10 @@ where
 
     fn new_transform(&self, service: S) -> Self::Future {
         future::ok(AuthenticationService {
-            dest: self.0.clone(),
+            dest: self.dest.clone(),
+            role: self.role,
+            required: self.required,
+            allow_setup: self.allow_setup,
             service
         })
     }
@@ -411,6 +1336,105 @@ where
 //#endregion
 
 
+//#region Login throttling
+
+// Each failed login costs an expensive password hash, so repeated failures
+// lock out both the targeted username and the address they came from
+const MAX_USERNAME_FAILURES: u32 = 5;
+const MAX_ADDRESS_FAILURES: u32 = 20;
+
+/// Period over which failed logins are counted
+const FAILURE_WINDOW: StdDuration = StdDuration::from_secs(15 * 60);
+
+/// Period for which logins are refused after too many failures
+const LOCKOUT_PERIOD: StdDuration = StdDuration::from_secs(15 * 60);
+
+
+/// Recent failed logins for a single username or address
+struct LoginFailures {
+    count: u32,
+    since: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl LoginFailures {
+
+    fn is_locked(&self, now: Instant) -> bool {
+        matches!(self.locked_until, Some(until) if now < until)
+    }
+
+    fn is_stale(&self, now: Instant) -> bool {
+        !self.is_locked(now) && now.duration_since(self.since) > FAILURE_WINDOW
+    }
+}
+
+
+lazy_static! {
+    /// Failed logins, keyed by username or address
+    static ref LOGIN_FAILURES: Mutex<HashMap<String, LoginFailures>> = Mutex::new(HashMap::new());
+}
+
+
+/// Gets the keys under which login failures are tracked, along with the number
+/// of failures allowed for each
+///
+/// `addr` must be the client's own address (see `audit::client_addr`) rather
+/// than that of a proxy, which would lock out everyone behind it.
+fn throttle_keys(username: &str, addr: Option<&str>) -> Vec<(String, u32)> {
+
+    let mut keys = vec![(format!("user:{}", username), MAX_USERNAME_FAILURES)];
+    if let Some(addr) = addr {
+        keys.push((format!("addr:{}", addr), MAX_ADDRESS_FAILURES));
+    }
+
+    keys
+}
+
+
+/// Returns whether logins are currently refused for any of the given keys
+fn is_login_locked(keys: &[(String, u32)]) -> bool {
+
+    let failures = do_lock!(LOGIN_FAILURES);
+    let now = Instant::now();
+
+    keys.iter()
+        .any(|(key, _)| failures.get(key).map(|f| f.is_locked(now)).unwrap_or(false))
+}
+
+
+/// Counts a failed login against each of the given keys, locking out any which
+/// have failed too often
+fn record_login_failure(keys: &[(String, u32)]) {
+
+    let mut failures = do_lock!(LOGIN_FAILURES);
+    let now = Instant::now();
+
+    // Prevent the map from growing without bound
+    failures.retain(|_, f| !f.is_stale(now));
+
+    for (key, max) in keys {
+        let entry = failures.entry(key.clone())
+            .or_insert(LoginFailures { count: 0, since: now, locked_until: None });
+        entry.count += 1;
+        if entry.count >= *max {
+            warn!("too many failed logins for {}, locking out", key);
+            entry.count = 0;
+            entry.since = now;
+            entry.locked_until = Some(now + LOCKOUT_PERIOD);
+        }
+    }
+}
+
+
+/// Forgets failed logins for the given username after it logs in successfully
+fn clear_login_failures(username: &str) {
+
+    do_lock!(LOGIN_FAILURES).remove(&format!("user:{}", username));
+}
+
+//#endregion
+
+
 //#region Session API
 
 /// Credentials required to create a session
@@ -418,6 +1442,10 @@ where
 struct PutSessionBody {
     username: String,
     password: String,
+
+    /// TOTP or recovery code, required if the user has enabled two-factor
+    /// authentication
+    code: Option<String>,
 }
 
 /// Used when creating a new session with Diesel
@@ -426,6 +1454,8 @@ struct PutSessionBody {
 struct NewSession<'a> {
     key: &'a str,
     user_id: i32,
+    created_at: NaiveDateTime,
+    last_seen_at: NaiveDateTime,
 }
 
 /// Response returned after successful session creation
@@ -434,38 +1464,125 @@ struct PutSessionResponse {
     key: String,
 }
 
+/// Response returned when the password is correct, but a two-factor code must
+/// also be provided
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CodeRequiredResponse {
+    message: &'static str,
+    code_required: bool,
+}
+
 /// Creates a new session
 fn put_session(
     pool: Data<ConnectionPool>,
+    req: HttpRequest,
     body: Json<PutSessionBody>
+) -> HashingFuture<HttpResponse>
+{
+    let body = body.into_inner();
+
+    // Refuse logins before doing any expensive work
+    let addr = audit::client_addr(&req);
+    let throttle_keys = throttle_keys(&body.username, addr.as_deref());
+    let find_user = || -> Result<Option<User>> {
+
+        let conn = pool.get()?;
+
+        if is_login_locked(&throttle_keys) {
+            audit::record(AuthEventKind::LoginLocked, &body.username, None, &req, &conn);
+            return Error::web(
+                StatusCode::TOO_MANY_REQUESTS,
+                "too many failed login attempts, try again later",
+            );
+        }
+
+        let user_filter = users::username.eq(&body.username);
+        let user = users::table.filter(user_filter)
+            .first(&conn)
+            .optional()?;
+
+        Ok(user)
+    };
+    let user = match find_user() {
+        Ok(user) => user,
+        Err(err) => return Box::new(future::err(err)),
+    };
+
+    // Validate password
+    let is_valid: HashingFuture<bool> = match user {
+        Some(ref user) => verify_password(user.pwhash.clone(), body.password.clone(), &pool),
+        None => verify_dummy_password(body.password.clone(), &pool),
+    };
+
+    let future = is_valid.and_then(move |is_valid| {
+        finish_login(&pool, &req, body, &throttle_keys, user.filter(|_| is_valid))
+    });
+
+    Box::new(future)
+}
+
+/// Completes a login once the password has been checked
+///
+/// `user` is `None` if the username or password was incorrect.
+fn finish_login(
+    pool: &ConnectionPool,
+    req: &HttpRequest,
+    body: PutSessionBody,
+    throttle_keys: &[(String, u32)],
+    user: Option<User>,
 ) -> Result<HttpResponse>
 {
     let conn = pool.get()?;
 
-    // Validate password
-    let user_filter = users::username.eq(&body.username);
-    let user_query = users::table.filter(user_filter);
-    let user: User = match user_query.first(&conn) {
-        Ok(user) => user,
-        Err(DieselError::NotFound) => {
+    let user = match user {
+        Some(user) => user,
+        None => {
+            record_login_failure(throttle_keys);
+            audit::record(AuthEventKind::LoginFailed, &body.username, None, req, &conn);
             return Error::web(StatusCode::UNAUTHORIZED, "invalid username or password");
         },
-        Err(err) => {
-            return Err(err.into());
-        }
     };
-    if !verify_password(&user.pwhash, &body.password, &conn)? {
-        return Error::web(StatusCode::UNAUTHORIZED, "invalid username or password");
+
+    // Wrong codes count as failed logins, which keeps codes from being guessed
+    match totp::check(user.id, body.code.as_deref(), &conn)? {
+        SecondFactor::NotEnrolled | SecondFactor::Valid => (),
+        SecondFactor::Missing => {
+            let response = HttpResponse::Unauthorized()
+                .json(CodeRequiredResponse {
+                    message: "two-factor code required",
+                    code_required: true,
+                });
+            return Ok(response);
+        },
+        SecondFactor::Invalid => {
+            record_login_failure(throttle_keys);
+            audit::record(AuthEventKind::LoginFailed, &user.username, None, req, &conn);
+            return Error::web(StatusCode::UNAUTHORIZED, "invalid two-factor code");
+        },
     }
 
+    clear_login_failures(&user.username);
+    audit::record(AuthEventKind::LoginSucceeded, &user.username, None, req, &conn);
+
+    // Failing to upgrade the hash should not prevent logging in
+    maybe_rehash_password(&user, body.password, pool)
+        .unwrap_or_else(|e| error!("failed to rehash password of user {}: {}", user.id, e));
+
     // Generate session key
     let key: [u8; 32] = rand::thread_rng().gen();
     let key = base64::encode(&key);
 
+    // Clean up after users who never logged out
+    purge_expired_sessions(&conn)?;
+
     // Create the session record
+    let now = Utc::now().naive_utc();
     let session = NewSession {
         key: &key,
         user_id: user.id,
+        created_at: now,
+        last_seen_at: now,
     };
     diesel::insert_into(sessions::table)
         .values(&session)
@@ -482,6 +1599,141 @@ fn put_session(
     Ok(response)
 }
 
+/// Retrieves the current user's sessions
+fn get_sessions(
+    pool: Data<ConnectionPool>,
+    current_user: AuthenticatedUser,
+) -> Result<Json<Vec<SessionResponse>>>
+{
+    let conn = pool.get()?;
+    purge_expired_sessions(&conn)?;
+
+    debug!("retrieving sessions of user {}", current_user.id);
+    let sessions: Vec<Session> = sessions::table
+        .filter(sessions::user_id.eq(current_user.id))
+        .order(sessions::last_seen_at.desc())
+        .load(&conn)?;
+
+    let sessions = sessions.into_iter()
+        .map(|session| SessionResponse {
+            current: Some(session.id) == current_user.session_id,
+            session,
+        })
+        .collect();
+
+    Ok(Json(sessions))
+}
+
+/// Session representation returned by the API
+#[derive(Serialize)]
+struct SessionResponse {
+    #[serde(flatten)]
+    session: Session,
+
+    /// Whether this is the session making the request
+    current: bool,
+}
+
+/// Revokes all of the current user's sessions, except for the session making
+/// the request
+fn delete_sessions(
+    pool: Data<ConnectionPool>,
+    current_user: AuthenticatedUser,
+) -> Result<HttpResponse>
+{
+    let conn = pool.get()?;
+
+    revoke_sessions(current_user.id, current_user.session_id, &conn)?;
+    info!("revoked other sessions of user {}", current_user.id);
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Revokes the specified session of the current user
+fn delete_session(
+    pool: Data<ConnectionPool>,
+    current_user: AuthenticatedUser,
+    path: web::Path<(i32,)>,
+) -> Result<HttpResponse>
+{
+    let id = path.0;
+    let conn = pool.get()?;
+
+    debug!("revoking session {}", id);
+    let count = diesel::delete(
+        sessions::table
+            .filter(sessions::id.eq(id))
+            .filter(sessions::user_id.eq(current_user.id))
+    )
+        .execute(&conn)?;
+    if count == 0 {
+        return Error::web(StatusCode::NOT_FOUND, "session not found");
+    }
+
+    info!("revoked session {} of user {}", id, current_user.id);
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Logs out by revoking the session making the request
+fn delete_current_session(
+    pool: Data<ConnectionPool>,
+    current_user: AuthenticatedUser,
+) -> Result<HttpResponse>
+{
+    let session_id = match current_user.session_id {
+        Some(id) => id,
+        None => return Error::web(StatusCode::BAD_REQUEST, "request was not made with a session"),
+    };
+
+    let conn = pool.get()?;
+
+    debug!("revoking session {}", session_id);
+    diesel::delete(sessions::table.find(session_id))
+        .execute(&conn)?;
+
+    info!("user {} logged out", current_user.id);
+
+    let cookie = Cookie::build(SESSION_COOKIE, "")
+        .path("/")
+        .finish();
+    let response = HttpResponse::NoContent()
+        .del_cookie(&cookie)
+        .finish();
+
+    Ok(response)
+}
+
+/// Revokes all sessions of the specified user, optionally keeping one
+fn revoke_sessions(user_id: i32, keep: Option<i32>, conn: &PooledConnection) -> Result<()> {
+
+    let query = sessions::table
+        .filter(sessions::user_id.eq(user_id))
+        .filter(sessions::id.ne(keep.unwrap_or(-1)));
+    diesel::delete(query)
+        .execute(conn)?;
+
+    Ok(())
+}
+
+/// Deletes all sessions which have timed out
+fn purge_expired_sessions(conn: &PooledConnection) -> Result<()> {
+
+    let timeouts = SessionTimeouts::load(conn)?;
+    let now = Utc::now().naive_utc();
+
+    let query = sessions::table
+        .filter(sessions::last_seen_at.lt(now - Duration::minutes(timeouts.idle_minutes)))
+        .or_filter(sessions::created_at.lt(now - Duration::hours(timeouts.max_age_hours)));
+    let count = diesel::delete(query)
+        .execute(conn)?;
+    if count > 0 {
+        debug!("purged {} expired sessions", count);
+    }
+
+    Ok(())
+}
+
 //#endregion
 
 
@@ -491,22 +1743,47 @@ pub fn configure_api(service: &mut ServiceConfig) {
     service.service(
         web::resource("/users")
             .route(web::get().to(get_users))
-            .route(web::put().to(put_user))
-            .wrap(AuthenticationMiddleware::reject())
+            .route(web::put().to_async(put_user))
+            .wrap(AuthenticationMiddleware::reject().require(Role::Admin))
     );
 
+    // Users may view and update their own accounts, so privileges are checked
+    // by the handlers
     service.service(
         web::resource("/users/{id}")
             .route(web::get().to(get_user))
-            .route(web::patch().to(patch_user))
+            .route(web::patch().to_async(patch_user))
             .route(web::delete().to(delete_user))
-            .wrap(AuthenticationMiddleware::reject())
+            .wrap(AuthenticationMiddleware::reject().allow_setup())
+    );
+
+    service.service(
+        web::resource("/users/{id}/cameras")
+            .route(web::get().to(get_user_cameras))
+            .route(web::put().to(put_user_cameras))
+            .wrap(AuthenticationMiddleware::reject().require(Role::Admin))
     );
 
-    // The /sessions resource is used for logging in, so it is left unauthenticated
+    // The /sessions resource is used for logging in, so unauthenticated
+    // requests are allowed through
     service.service(
         web::resource("/sessions")
-            .route(web::put().to(put_session))
+            .route(web::put().to_async(put_session))
+            .route(web::get().to(get_sessions))
+            .route(web::delete().to(delete_sessions))
+            .wrap(AuthenticationMiddleware::optional())
+    );
+
+    service.service(
+        web::resource("/sessions/current")
+            .route(web::delete().to(delete_current_session))
+            .wrap(AuthenticationMiddleware::reject().allow_setup())
+    );
+
+    service.service(
+        web::resource("/sessions/{id}")
+            .route(web::delete().to(delete_session))
+            .wrap(AuthenticationMiddleware::reject())
     );
 }
 
@@ -518,3 +1795,34 @@ pub fn all(conn: &PooledConnection) -> Result<impl Serialize> {
 
     Ok(users)
 }
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn unknown_addresses_do_not_share_a_bucket() {
+
+        for i in 0..MAX_ADDRESS_FAILURES {
+            let keys = throttle_keys(&format!("unknown-addr-{}", i), None);
+            assert!(keys.iter().all(|(key, _)| !key.starts_with("addr:")));
+            record_login_failure(&keys);
+        }
+
+        let keys = throttle_keys("unknown-addr-other", None);
+        assert!(!is_login_locked(&keys));
+    }
+
+    #[test]
+    fn client_addresses_are_throttled() {
+
+        for i in 0..MAX_ADDRESS_FAILURES {
+            record_login_failure(&throttle_keys(&format!("client-addr-{}", i), Some("192.0.2.44")));
+        }
+
+        assert!(is_login_locked(&throttle_keys("client-addr-other", Some("192.0.2.44"))));
+        assert!(!is_login_locked(&throttle_keys("client-addr-other", Some("192.0.2.45"))));
+    }
+}